@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
+use std::fs;
 use std::num::NonZeroUsize;
 use std::ops::Range;
 use std::sync::atomic::Ordering;
@@ -8,23 +9,34 @@ use std::sync::Arc;
 use anyhow::{anyhow, Result};
 use atomic_float::AtomicF64;
 use bit_set::BitSet;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use lru::LruCache;
 use ratatui::style::Color;
 use ringbuf::{Consumer, Producer, RingBuffer};
 use triple_buffer::{Input, Output, TripleBuffer};
 
-use crate::delay::Delay;
+use crate::backend::{AudioBackend, AudioConfig};
+use crate::effects::{self, Category, Effect, EFFECTS};
 use crate::engine::{
-    Engine, EngineCommand, Event, Note, Pattern as EnginePattern, Plugin, Track as EngineTrack,
-    TrackParams, MAX_INSTRUMENTS, MAX_NODES, MAX_TRACKS, SCRATCH_BUFFER, TICKS_PER_LINE,
+    self, Engine, EngineCommand, Event, Note, Pattern as EnginePattern, Plugin, Pool,
+    Track as EngineTrack, TrackParams, INITIAL_POOL_SIZE, MAIN_OUTPUT, MASTER_TRACK,
+    MAX_INSTRUMENTS, SCRATCH_BUFFER, TICKS_PER_LINE,
 };
 use crate::files::FileBrowser;
-use crate::params::Params;
-use crate::pattern::{Pattern, Step, StepSize, NOTE_OFF};
-use crate::sampler::{self, Sampler, Sound};
+use crate::history::{Edit, History, InstrumentSnapshot, SongSnapshot};
+use crate::midi;
+use crate::params::{Param, ParamIterExt, Params};
+use crate::pattern::{self, Pattern, Step, StepSize, NOTE_OFF};
+use crate::project::{self, DeviceData, Project, Routing, TrackData};
+use crate::resample::Quality;
+use crate::sampler::{self, Sampler, SamplerParams, Sound};
+use crate::tracker;
+use crate::DEFAULT_SAMPLE_RATE;
 
 const MAX_PATTERNS: usize = 999;
+// Loading a project creates and deletes many nodes at once, before the engine gets a chance to
+// process any of the commands.
+const COMMAND_QUEUE_SIZE: usize = 2048;
 
 pub struct App {
     pub state: AppState,
@@ -41,22 +53,50 @@ pub struct App {
 
     pub tracks: Vec<Track>,
     pub instruments: Vec<Option<Device>>,
+    pub project_path: Option<Utf8PathBuf>,
+    /// Information for the user about the last action, e.g. what was skipped during an import
+    pub message: Option<String>,
 
     node_indices: BitSet,
+    /// Effects that were removed from a track and are still fading out in the engine. They stay
+    /// in the effect chain until the engine returns them, so removing them doesn't click.
+    fading_effects: Vec<FadingEffect>,
+    /// Buffers that receive the output of tracks that sidechains listen to, by the track's node
+    /// index
+    sidechain_buffers: HashMap<usize, usize>,
+    /// Number of nodes in the engine's pool, including ones that are still being sent to it
+    pool_size: usize,
+    history: History,
+    audio: Option<Box<dyn AudioBackend>>,
+    /// Sample rate the engine runs at, which new plugins and sounds are set up for
+    sample_rate: f64,
 }
 
 impl App {
     pub fn send(&mut self, msg: Msg) -> Result<()> {
+        self.message = None;
         while let Some(cmd) = self.consumer.pop() {
             match cmd {
                 AppCommand::DropPlugin(node_index, plugin) => {
                     drop(plugin);
-                    self.node_indices.insert(node_index);
+                    self.node_indices.remove(node_index);
+                    if let Some(i) = self
+                        .fading_effects
+                        .iter()
+                        .position(|f| f.node_index == node_index)
+                    {
+                        self.fading_effects.remove(i);
+                        self.update_node_order();
+                    }
                 }
+                AppCommand::DropPool(pool) => drop(pool),
             }
         }
 
         self.dispatch(msg)?;
+        for edit in self.history.take_dropped() {
+            self.release(edit)?;
+        }
         self.recompile_patterns();
         let input_buf = self.state_buf.input_buffer();
         input_buf.clone_from(&self.state);
@@ -65,14 +105,18 @@ impl App {
         Ok(())
     }
 
-    fn get_node_index(&mut self, range: Range<usize>) -> Result<usize> {
-        for n in range {
-            if !self.node_indices.contains(n) {
-                self.node_indices.insert(n);
-                return Ok(n);
-            }
+    /// Returns a free node index, and grows the engine's node pool if it doesn't have one
+    fn get_node_index(&mut self) -> Result<usize> {
+        let n = (0..)
+            .find(|n| !self.node_indices.contains(*n))
+            .expect("node indices are unlimited");
+        if n >= self.pool_size {
+            self.pool_size *= 2;
+            let pool = Box::new(Pool::new(self.pool_size, self.sample_rate));
+            self.send_to_engine(EngineCommand::Grow(pool))?;
         }
-        Err(anyhow!("reached max. number of nodes"))
+        self.node_indices.insert(n);
+        Ok(n)
     }
 
     fn dispatch(&mut self, msg: Msg) -> Result<()> {
@@ -88,38 +132,68 @@ impl App {
             LoadSound(idx, path) => {
                 // TODO: keep settings from previous sampler?
                 let snd = sampler::load_file(&path)?;
-                let sampler: Box<dyn Plugin + Send> = Box::new(Sampler::new(snd));
-                let sampler_index = self.get_node_index(MAX_TRACKS..MAX_NODES)?;
-                self.params.insert(sampler_index, sampler.params());
-                let cmd = EngineCommand::CreateNode(sampler_index, sampler);
-                self.send_to_engine(cmd)?;
-
-                if let Some(instr) = &self.instruments[idx] {
-                    self.params.remove(&instr.node_index);
-                    self.send_to_engine(EngineCommand::DeleteNode(instr.node_index))?;
-                }
-
-                self.instruments[idx] = Some(Device {
-                    node_index: sampler_index,
-                    name: path.file_name().unwrap().to_string(),
-                });
+                let name = path.file_name().unwrap().to_string();
+                let before = self.instrument_snapshot(idx);
+                self.load_sound(idx, snd, name, Some(path), &[])?;
+                self.history.record(Edit::Instrument(idx, before));
                 self.update_node_order();
             }
-            LoadEffect(idx, effect) => {
-                match effect.as_str() {
-                    "delay" => {
-                        let delay_index = self.get_node_index(MAX_TRACKS..MAX_NODES)?;
-                        let delay: Box<dyn Plugin + Send> = Box::new(Delay::new(44100 / 8));
-                        let cmd = EngineCommand::CreateNode(delay_index, delay);
-                        self.send_to_engine(cmd)?;
-                        self.tracks[idx].effects.push(Device {
-                            node_index: delay_index,
-                            name: String::from("Delay"),
-                        });
+            LoadEffect(idx, effect) => match self.load_effect(idx, &effect, None) {
+                Ok(_) => {
+                    let pos = self.tracks[idx].effects.len() - 1;
+                    self.history.record(Edit::RemoveEffect(idx, pos));
+                    self.update_node_order();
+                }
+                Err(err) => self.report(Err(err)),
+            },
+            LoadImpulseResponse(track_idx, idx, path) => {
+                let result = self.load_impulse_response(track_idx, idx, path);
+                self.report(result);
+            }
+            DeleteEffect(track_idx, idx) => {
+                self.check_effect(track_idx, idx)?;
+                let edit = self.remove_effect(track_idx, idx)?;
+                self.history.record(edit);
+            }
+            MoveEffect(track_idx, from, to) => {
+                self.check_effect(track_idx, from)?;
+                self.check_effect(track_idx, to)?;
+                let edit = self.move_effect(track_idx, from, to);
+                self.history.record(edit);
+            }
+            ToggleEffectBypass(track_idx, idx) => {
+                self.check_effect(track_idx, idx)?;
+                let bypass = !self.tracks[track_idx].effects[idx].bypass;
+                let edit = self.set_effect_bypass(track_idx, idx, bypass)?;
+                self.history.record(edit);
+            }
+            SetSidechain(track_idx, idx, source) => {
+                self.check_effect(track_idx, idx)?;
+                let source = match source {
+                    Some(source) if !self.can_sidechain(track_idx, source) => {
+                        return Err(anyhow!("track {track_idx} can't listen to track {source}"));
                     }
-                    _ => return Err(anyhow!("unknown effect {effect}")),
+                    Some(source) => Some(self.tracks[source].node_index),
+                    None => None,
                 };
-                self.update_node_order();
+                let edit = self.set_sidechain(track_idx, idx, source)?;
+                self.history.record(edit);
+            }
+            SaveProject(path) => {
+                let result = self.save_project(path);
+                self.report(result);
+            }
+            LoadProject(path) => {
+                let result = self.open_project(path);
+                self.report(result);
+            }
+            ImportMidi(path, track_idx) => {
+                let result = self.import_midi(&path, track_idx);
+                self.report(result);
+            }
+            ExportMidi(path) => {
+                let result = midi::export(&path, &self.state, &self.tracks);
+                self.report(result);
             }
             LoopToggle(idx) => {
                 self.state.loop_range = match self.state.loop_range {
@@ -172,6 +246,7 @@ impl App {
             DeletePattern(idx) => {
                 // Ensure we have at least one to avoid dealing with having no patterns
                 if self.state.song.len() > 1 {
+                    self.history.record(Edit::Song(self.song_snapshot()));
                     let pattern_id = self.state.song.remove(idx);
                     if !self.state.song.contains(&pattern_id) {
                         self.patterns.remove(&pattern_id);
@@ -188,16 +263,17 @@ impl App {
                 }
             }
             UpdatePattern(id, pattern) => {
-                self.patterns.insert(id, pattern);
+                if let Some(old) = self.patterns.insert(id, pattern) {
+                    if old != self.patterns[&id] {
+                        self.history.record(Edit::Pattern(id, old));
+                    }
+                }
             }
             CreatePattern(idx) => {
                 if self.state.patterns.len() < MAX_PATTERNS {
+                    self.history.record(Edit::Song(self.song_snapshot()));
                     let id = self.next_pattern_id();
-                    let num_instruments = self
-                        .tracks
-                        .iter()
-                        .filter(|track| matches!(track.track_type, TrackType::Instrument))
-                        .count();
+                    let num_instruments = self.num_instrument_tracks();
 
                     let pattern = Pattern::new(num_instruments);
                     self.patterns.insert(id, pattern);
@@ -209,10 +285,12 @@ impl App {
                 }
             }
             RepeatPattern(idx) => {
+                self.history.record(Edit::Song(self.song_snapshot()));
                 let pattern_id = self.state.song[idx];
                 self.state.song.insert(idx + 1, pattern_id);
             }
             ClonePattern(idx) => {
+                self.history.record(Edit::Song(self.song_snapshot()));
                 let id = self.state.song[idx];
                 let p1: &Pattern = self.patterns.get(&id).unwrap();
                 let mut p2 = p1.clone();
@@ -223,75 +301,1087 @@ impl App {
             }
             ChangeDir(dir) => self.file_browser.move_to(dir)?,
             CreateTrack(idx, output_index, track_type, name) => {
-                let node_index = self.get_node_index(0..MAX_TRACKS)?;
-                let engine_track = EngineTrack::new();
-                let track = Track::new(
-                    node_index,
-                    output_index,
-                    track_type,
-                    name,
-                    engine_track.rms_out.clone(),
-                );
-                self.params.insert(node_index, engine_track.params());
-
-                if idx > self.tracks.len() {
-                    self.tracks.push(track);
-                } else {
-                    self.tracks.insert(idx, track)
-                }
-
-                if matches!(track_type, TrackType::Instrument) {
-                    for pattern in &mut self.patterns.values_mut() {
-                        pattern.add_track(idx);
-                    }
-                }
-
-                let engine_track: Box<dyn Plugin + Send> = Box::new(engine_track);
-                let cmd = EngineCommand::CreateNode(node_index, engine_track);
-                self.send_to_engine(cmd)?;
-                self.update_node_order();
+                let edit = self.add_track(idx, output_index, track_type, name)?;
+                self.history.record(edit);
             }
             DeleteTrack(idx) => {
-                self.tracks.remove(idx);
-                for pattern in &mut self.patterns.values_mut() {
-                    pattern.delete_track(idx);
-                }
-                self.update_node_order();
+                let edit = self.remove_track(idx);
+                self.history.record(edit);
             }
             RenameTrack(idx, name) => {
-                self.tracks[idx].name = name;
+                let old = std::mem::replace(&mut self.tracks[idx].name, name);
+                self.history.record(Edit::RenameTrack(idx, old));
             }
             ParamInc(node_index, param_idx, step_size) => {
-                self.params(node_index).get_param(param_idx).incr(step_size);
+                self.change_param(node_index, param_idx, |p| p.incr(step_size))?;
             }
             ParamDec(node_index, param_idx, step_size) => {
-                self.params(node_index).get_param(param_idx).decr(step_size);
+                self.change_param(node_index, param_idx, |p| p.decr(step_size))?;
             }
             DeleteInstrument(idx) => {
-                if let Some(instr) = &self.instruments[idx] {
-                    self.params.remove(&instr.node_index);
-                    self.send_to_engine(EngineCommand::DeleteNode(instr.node_index))?;
+                if let Some(before) = self.instrument_snapshot(idx) {
+                    self.delete_instrument(idx)?;
+                    self.history.record(Edit::Instrument(idx, Some(before)));
                 }
-                self.instruments[idx] = None;
             }
             ToggleMute(track_idx) => {
                 let idx = self.tracks[track_idx].node_index;
-                self.params(idx).get_param(TrackParams::MUTE).toggle();
+                self.change_param(idx, TrackParams::MUTE, |p| p.toggle())?;
             }
             TrackVolumeIncr(track_idx) => {
                 let idx = self.tracks[track_idx].node_index;
-                self.params(idx)
-                    .get_param(TrackParams::VOLUME)
-                    .incr(StepSize::Large);
+                self.change_param(idx, TrackParams::VOLUME, |p| p.incr(StepSize::Large))?;
             }
             TrackVolumeDecr(track_idx) => {
                 let idx = self.tracks[track_idx].node_index;
-                self.params(idx)
-                    .get_param(TrackParams::VOLUME)
-                    .decr(StepSize::Large);
+                self.change_param(idx, TrackParams::VOLUME, |p| p.decr(StepSize::Large))?;
+            }
+            Undo => match self.history.pop_undo() {
+                // An edit that fails stays in the history, so it can be undone again once the
+                // problem is fixed
+                Some(edit) => match self.apply(edit) {
+                    Ok(redo) => self.history.push_redo(redo),
+                    Err(FailedEdit(edit, err)) => {
+                        self.history.push_undo(*edit);
+                        self.report(Err(err));
+                    }
+                },
+                None => self.message = Some(String::from("nothing to undo")),
+            },
+            ListEffects => {
+                let mut categories: Vec<(Category, Vec<String>)> = Vec::new();
+                for effect in EFFECTS {
+                    let name = format!("{} ({})", effect.name, effect.id);
+                    match categories.iter_mut().find(|(c, _)| *c == effect.category) {
+                        Some((_, names)) => names.push(name),
+                        None => categories.push((effect.category, vec![name])),
+                    }
+                }
+                let categories: Vec<String> = categories
+                    .into_iter()
+                    .map(|(category, names)| format!("{category}: {}", names.join(", ")))
+                    .collect();
+                self.message = Some(categories.join(" | "));
+            }
+            ListDevices => {
+                let result = self.list_devices();
+                self.report(result);
+            }
+            SelectDevice(name) => {
+                let result = self.audio().and_then(|audio| audio.select_device(&name));
+                self.report(result);
+            }
+            SetBufferSize(buffer_size) => {
+                self.reconfigure_audio(|config| config.buffer_size = buffer_size);
+            }
+            SetOutputChannels(channels) => {
+                self.reconfigure_audio(|config| config.channels = channels);
+            }
+            SetSampleRate(sample_rate) => self.set_sample_rate(sample_rate)?,
+            Redo => match self.history.pop_redo() {
+                Some(edit) => match self.apply(edit) {
+                    Ok(undo) => self.history.push_undo(undo),
+                    Err(FailedEdit(edit, err)) => {
+                        self.history.push_redo(*edit);
+                        self.report(Err(err));
+                    }
+                },
+                None => self.message = Some(String::from("nothing to redo")),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Applies an edit from the history and returns the edit that reverts it. An edit that can't
+    /// be applied is handed back with the error, and leaves the project as it was.
+    fn apply(&mut self, edit: Edit) -> Result<Edit, FailedEdit> {
+        let inverse = match edit {
+            Edit::Pattern(id, pattern) => {
+                let Some(current) = self.patterns.get_mut(&id) else {
+                    let err = anyhow!("pattern {id} doesn't exist");
+                    return Err(FailedEdit::new(Edit::Pattern(id, pattern), err));
+                };
+                Edit::Pattern(id, std::mem::replace(current, pattern))
+            }
+            Edit::Song(snapshot) => {
+                let current = self.song_snapshot();
+                self.restore_song(snapshot);
+                Edit::Song(current)
+            }
+            Edit::InsertTrack(idx, track, columns) => {
+                // The buffers its sidechains listened to can have been freed in the meantime
+                let sources = track.effects.iter().filter_map(|e| e.sidechain);
+                let result = sources
+                    .into_iter()
+                    .try_for_each(|source| self.add_sidechain_buffer(source));
+                if let Err(err) = result {
+                    return Err(FailedEdit::new(Edit::InsertTrack(idx, track, columns), err));
+                }
+                self.insert_track(idx, track, columns)
+            }
+            Edit::RemoveTrack(idx) => self.remove_track(idx),
+            Edit::RenameTrack(idx, name) => {
+                let old = std::mem::replace(&mut self.tracks[idx].name, name);
+                Edit::RenameTrack(idx, old)
+            }
+            Edit::Instrument(slot, snapshot) => {
+                let current = self.instrument_snapshot(slot);
+                let result = match snapshot.clone() {
+                    Some(snapshot) => self.restore_instrument(slot, snapshot),
+                    None => self.delete_instrument(slot),
+                };
+                if let Err(err) = result {
+                    return Err(FailedEdit::new(Edit::Instrument(slot, snapshot), err));
+                }
+                self.update_node_order();
+                Edit::Instrument(slot, current)
+            }
+            Edit::InstrumentParam(slot, param_idx, value) => {
+                let failed =
+                    |err| FailedEdit::new(Edit::InstrumentParam(slot, param_idx, value), err);
+                let Some(node_index) = self.instruments[slot].as_ref().map(|i| i.node_index) else {
+                    return Err(failed(anyhow!("instrument {slot} doesn't exist")));
+                };
+                let old = self
+                    .set_param(node_index, param_idx, value)
+                    .map_err(failed)?;
+                Edit::InstrumentParam(slot, param_idx, old)
+            }
+            Edit::Param(node_index, param_idx, value) => {
+                let old = self
+                    .set_param(node_index, param_idx, value)
+                    .map_err(|err| {
+                        FailedEdit::new(Edit::Param(node_index, param_idx, value), err)
+                    })?;
+                Edit::Param(node_index, param_idx, old)
+            }
+            Edit::EffectParam(track_idx, idx, param_idx, value) => {
+                let failed =
+                    |err| FailedEdit::new(Edit::EffectParam(track_idx, idx, param_idx, value), err);
+                self.check_effect(track_idx, idx).map_err(failed)?;
+                let node_index = self.tracks[track_idx].effects[idx].node_index;
+                let old = self
+                    .set_param(node_index, param_idx, value)
+                    .map_err(failed)?;
+                Edit::EffectParam(track_idx, idx, param_idx, old)
+            }
+            Edit::InsertEffect(track_idx, idx, data) => self
+                .insert_effect(track_idx, idx, data.clone())
+                .map_err(|err| FailedEdit::new(Edit::InsertEffect(track_idx, idx, data), err))?,
+            Edit::RemoveEffect(track_idx, idx) => self
+                .remove_effect(track_idx, idx)
+                .map_err(|err| FailedEdit::new(Edit::RemoveEffect(track_idx, idx), err))?,
+            Edit::MoveEffect(track_idx, from, to) => self.move_effect(track_idx, from, to),
+            Edit::BypassEffect(track_idx, idx, bypass) => self
+                .set_effect_bypass(track_idx, idx, bypass)
+                .map_err(|err| FailedEdit::new(Edit::BypassEffect(track_idx, idx, bypass), err))?,
+            Edit::Sidechain(track_idx, idx, source) => {
+                let failed = |err| FailedEdit::new(Edit::Sidechain(track_idx, idx, source), err);
+                self.check_effect(track_idx, idx).map_err(failed)?;
+                self.set_sidechain(track_idx, idx, source).map_err(failed)?
+            }
+            Edit::Batch(edits) => self.apply_batch(edits)?,
+        };
+        Ok(inverse)
+    }
+
+    /// Applies edits in order. When one fails, the ones before it are reverted, so the batch is
+    /// handed back whole.
+    fn apply_batch(&mut self, edits: Vec<Edit>) -> Result<Edit, FailedEdit> {
+        let mut inverse = Vec::with_capacity(edits.len());
+        let mut edits = edits.into_iter();
+        while let Some(edit) = edits.next() {
+            match self.apply(edit) {
+                Ok(edit) => inverse.push(edit),
+                Err(FailedEdit(failed, err)) => {
+                    let mut batch = Vec::with_capacity(inverse.len() + 1 + edits.len());
+                    for edit in inverse.into_iter().rev() {
+                        // Reverting an edit that was just applied shouldn't fail, but if it does,
+                        // there's nothing left to hand back
+                        if let Ok(edit) = self.apply(edit) {
+                            batch.push(edit);
+                        }
+                    }
+                    batch.reverse();
+                    batch.push(*failed);
+                    batch.extend(edits);
+                    return Err(FailedEdit::new(Edit::Batch(batch), err));
+                }
+            }
+        }
+        inverse.reverse();
+        Ok(Edit::Batch(inverse))
+    }
+
+    /// Creates a track at `idx` and returns the edit that removes it again
+    fn add_track(
+        &mut self,
+        idx: usize,
+        output_index: usize,
+        track_type: TrackType,
+        name: Option<String>,
+    ) -> Result<Edit> {
+        let node_index = self.get_node_index()?;
+        let track = self.create_track(node_index, output_index, track_type, name)?;
+
+        let idx = usize::min(idx, self.tracks.len());
+        self.tracks.insert(idx, track);
+
+        if matches!(track_type, TrackType::Instrument) {
+            for pattern in &mut self.patterns.values_mut() {
+                pattern.add_track(idx);
+            }
+        }
+        self.update_node_order();
+        Ok(Edit::RemoveTrack(idx))
+    }
+
+    /// Deletes the nodes of removed tracks in an edit that was dropped from the history, since
+    /// the tracks can't be put back anymore
+    fn release(&mut self, edit: Edit) -> Result<()> {
+        match edit {
+            Edit::InsertTrack(_, track, _) => {
+                let effects = track.effects.iter().map(|e| e.node_index);
+                for node_index in effects.chain([track.node_index]) {
+                    self.params.remove(&node_index);
+                    self.send_to_engine(EngineCommand::DeleteNode(node_index))?;
+                }
+                if let Some(buffer) = self.sidechain_buffers.remove(&track.node_index) {
+                    self.node_indices.remove(buffer);
+                }
+                // The node index is reused, so sidechains mustn't listen to the next track that
+                // gets it
+                for effect in self.tracks.iter_mut().flat_map(|t| t.effects.iter_mut()) {
+                    if effect.sidechain == Some(track.node_index) {
+                        effect.sidechain = None;
+                    }
+                }
+                self.update_node_order();
+            }
+            Edit::Batch(edits) => {
+                for edit in edits {
+                    self.release(edit)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Removes a track and its column from all patterns. The track's node is kept, so the track
+    /// can be put back by applying the returned edit.
+    fn remove_track(&mut self, idx: usize) -> Edit {
+        let track = self.tracks.remove(idx);
+        let mut columns = HashMap::new();
+        if matches!(track.track_type, TrackType::Instrument) {
+            for (id, pattern) in &mut self.patterns {
+                columns.insert(*id, pattern.delete_track(idx));
+            }
+        }
+        self.update_node_order();
+        Edit::InsertTrack(idx, track, columns)
+    }
+
+    fn insert_track(
+        &mut self,
+        idx: usize,
+        track: Track,
+        mut columns: HashMap<PatternId, pattern::Track>,
+    ) -> Edit {
+        if matches!(track.track_type, TrackType::Instrument) {
+            for (id, pattern) in &mut self.patterns {
+                match columns.remove(id) {
+                    Some(column) => pattern.insert_track(idx, column),
+                    None => pattern.add_track(idx),
+                }
+            }
+        }
+        self.tracks.insert(idx, track);
+        self.update_node_order();
+        Edit::RemoveTrack(idx)
+    }
+
+    fn song_snapshot(&self) -> SongSnapshot {
+        SongSnapshot {
+            song: self.state.song.clone(),
+            patterns: self.patterns.clone(),
+            selected_pattern: self.state.selected_pattern,
+            loop_range: self.state.loop_range,
+        }
+    }
+
+    fn restore_song(&mut self, snapshot: SongSnapshot) {
+        self.patterns = snapshot.patterns;
+        self.state
+            .patterns
+            .retain(|id, _| self.patterns.contains_key(id));
+        self.state.song = snapshot.song;
+        self.state.selected_pattern =
+            usize::min(snapshot.selected_pattern, self.state.song.len() - 1);
+        self.state.loop_range = snapshot.loop_range;
+    }
+
+    fn instrument_snapshot(&self, slot: usize) -> Option<InstrumentSnapshot> {
+        self.instruments[slot]
+            .as_ref()
+            .map(|instr| InstrumentSnapshot {
+                name: instr.name.clone(),
+                path: instr.path.clone(),
+                sound: instr.sound.clone(),
+                params: self.param_values(instr.node_index),
+            })
+    }
+
+    fn restore_instrument(&mut self, slot: usize, snapshot: InstrumentSnapshot) -> Result<()> {
+        let sound = match (&snapshot.path, snapshot.sound) {
+            (_, Some(sound)) => sound,
+            (Some(path), None) => sampler::load_file(path)?,
+            (None, None) => return Err(anyhow!("instrument {slot} has no sound")),
+        };
+        self.load_sound(slot, sound, snapshot.name, snapshot.path, &snapshot.params)?;
+        Ok(())
+    }
+
+    fn delete_instrument(&mut self, slot: usize) -> Result<()> {
+        if let Some(instr) = self.instruments[slot].take() {
+            self.params.remove(&instr.node_index);
+            self.send_to_engine(EngineCommand::DeleteNode(instr.node_index))?;
+        }
+        Ok(())
+    }
+
+    /// Changes a parameter with `f` and records its previous value. Consecutive changes to the
+    /// same parameter are undone at once.
+    fn change_param<F>(&mut self, node_index: usize, param_idx: usize, f: F) -> Result<()>
+    where
+        F: FnOnce(&Param),
+    {
+        let param = self.params(node_index).get_param(param_idx);
+        let old = param.target();
+        f(param);
+        let changed = param.target() != old;
+        if changed {
+            let edit = if let Some(slot) = self.instrument_slot(node_index) {
+                Edit::InstrumentParam(slot, param_idx, old)
+            } else if let Some((track_idx, idx)) = self.effect_position(node_index) {
+                Edit::EffectParam(track_idx, idx, param_idx, old)
+            } else {
+                Edit::Param(node_index, param_idx, old)
+            };
+            if !self.history.continues_param_edit(&edit) {
+                self.history.record(edit);
+            }
+        }
+        self.param_changed(node_index, param_idx)
+    }
+
+    /// Sets a parameter and returns its previous value
+    fn set_param(&mut self, node_index: usize, param_idx: usize, value: f64) -> Result<f64> {
+        let param = self.params(node_index).get_param(param_idx);
+        let old = param.target();
+        param.set(value);
+        self.param_changed(node_index, param_idx)?;
+        Ok(old)
+    }
+
+    /// Sets the backend that plays the engine's output, so it can be configured from the app
+    pub fn set_audio_backend(&mut self, backend: Box<dyn AudioBackend>) {
+        self.audio = Some(backend);
+    }
+
+    /// Shows the error of a command, e.g. for a file that can't be loaded, instead of passing it
+    /// on to end the app
+    fn report(&mut self, result: Result<()>) {
+        if let Err(err) = result {
+            self.message = Some(format!("{err:#}"));
+        }
+    }
+
+    fn save_project(&mut self, path: Option<Utf8PathBuf>) -> Result<()> {
+        let Some(path) = path.or_else(|| self.project_path.clone()) else {
+            return Err(anyhow!("no file name"));
+        };
+        self.save_embedded_sounds(&path)?;
+        project::save(&path, &self.project())?;
+        self.project_path = Some(path);
+        Ok(())
+    }
+
+    /// Loads a project or a module from another tracker
+    fn open_project(&mut self, path: Utf8PathBuf) -> Result<()> {
+        if tracker::can_load_file(&path) {
+            let module = tracker::load(&path)?;
+            self.load_module(module)?;
+            // Saving shouldn't overwrite the module
+            self.project_path = None;
+        } else {
+            let project = project::load(&path)?;
+            self.load_project(project)?;
+            self.project_path = Some(path);
+        }
+        Ok(())
+    }
+
+    fn import_midi(&mut self, path: &Utf8Path, track_idx: usize) -> Result<()> {
+        // The notes play the instrument of the track they end up in
+        let track_idx = usize::min(track_idx, self.num_instrument_tracks());
+        let import = midi::import(path, self.state.lines_per_beat, track_idx)?;
+        let names = import.channels.iter().map(|ch| format!("ch{}", ch + 1));
+        self.import_patterns(track_idx, import.patterns, names)
+    }
+
+    /// Restarts the audio backend with a changed configuration and returns whether it was
+    /// changed. A missing backend, backends that can't be reconfigured, or a configuration that
+    /// the device doesn't support, are reported in the message.
+    fn reconfigure_audio<F>(&mut self, change: F) -> bool
+    where
+        F: FnOnce(&mut AudioConfig),
+    {
+        let audio = match self.audio() {
+            Ok(audio) => audio,
+            Err(err) => {
+                self.report(Err(err));
+                return false;
+            }
+        };
+        if !audio.can_reconfigure() {
+            self.message = Some(String::from("not supported by this backend"));
+            return false;
+        }
+        let mut config = audio.config().clone();
+        change(&mut config);
+        let result = audio.set_config(config);
+        let changed = result.is_ok();
+        self.report(result);
+        changed
+    }
+
+    /// Shows the output devices of all hosts, with the selected one marked
+    fn list_devices(&mut self) -> Result<()> {
+        let mut hosts: Vec<(String, Vec<String>)> = Vec::new();
+        for device in self.audio()?.devices()? {
+            let name = if device.selected {
+                format!("{}*", device.name)
+            } else {
+                device.name
+            };
+            match hosts.iter_mut().find(|(host, _)| *host == device.host) {
+                Some((_, names)) => names.push(name),
+                None => hosts.push((device.host, vec![name])),
+            }
+        }
+        let hosts: Vec<String> = hosts
+            .into_iter()
+            .map(|(host, names)| format!("{host}: {}", names.join(", ")))
+            .collect();
+        self.message = Some(hosts.join(" | "));
+        Ok(())
+    }
+
+    fn audio(&mut self) -> Result<&mut Box<dyn AudioBackend>> {
+        self.audio
+            .as_mut()
+            .ok_or_else(|| anyhow!("no audio backend"))
+    }
+
+    /// Forgets all edits, e.g. after setting up the initial project
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Changes the sample rate of the engine. The audio backend is restarted at the new rate if
+    /// there is one, otherwise the owner of the engine has to update it. Sounds are converted to
+    /// the new rate again.
+    fn set_sample_rate(&mut self, sample_rate: u32) -> Result<()> {
+        if !engine::supports_sample_rate(sample_rate) {
+            return Err(anyhow!("unsupported sample rate {sample_rate}"));
+        }
+        if self.audio.is_some()
+            && !self.reconfigure_audio(|config| config.sample_rate = sample_rate)
+        {
+            return Ok(());
+        }
+        self.sample_rate = sample_rate as f64;
+        for slot in 0..self.instruments.len() {
+            let Some(instr) = &self.instruments[slot] else {
+                continue;
+            };
+            let params = self.param_values(instr.node_index);
+            if sampler::resampling_quality(&params) != Quality::Off {
+                self.reload_sound(slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads a sound into a new sampler in instrument slot `idx`, with the given parameter values.
+    /// The sound is converted to the engine's sample rate with the sampler's resampling quality.
+    fn load_sound(
+        &mut self,
+        idx: usize,
+        sound: Sound,
+        name: String,
+        path: Option<Utf8PathBuf>,
+        params: &[f64],
+    ) -> Result<usize> {
+        let embedded = path.is_none().then(|| sound.clone());
+        let sound = sound.resample(sampler::resampling_quality(params), self.sample_rate);
+        let sampler: Box<dyn Plugin + Send> = Box::new(Sampler::new(sound, self.sample_rate));
+        let sampler_index = self.get_node_index()?;
+        self.params.insert(sampler_index, sampler.params());
+        self.set_param_values(sampler_index, params);
+        let cmd = EngineCommand::CreateNode(sampler_index, sampler);
+        self.send_to_engine(cmd)?;
+
+        if let Some(instr) = &self.instruments[idx] {
+            self.params.remove(&instr.node_index);
+            self.send_to_engine(EngineCommand::DeleteNode(instr.node_index))?;
+        }
+
+        self.instruments[idx] = Some(Device {
+            node_index: sampler_index,
+            name,
+            id: String::from("sampler"),
+            path,
+            sound: embedded,
+            bypass: false,
+            sidechain: None,
+            gain_reduction: None,
+        });
+        Ok(sampler_index)
+    }
+
+    /// Adds an effect from the registry to the end of a track's effect chain. Effects that load a
+    /// file, like an impulse response, are created from `path`.
+    fn load_effect(
+        &mut self,
+        track_idx: usize,
+        name: &str,
+        path: Option<Utf8PathBuf>,
+    ) -> Result<usize> {
+        let (effect, plugin) = self.create_effect(name, path.as_ref())?;
+        if track_idx >= self.tracks.len() {
+            return Err(anyhow!("invalid track {track_idx}"));
+        }
+        self.add_effect(track_idx, effect, plugin, path)
+    }
+
+    /// Creates an effect from the registry without adding it to a track
+    fn create_effect(&self, name: &str, path: Option<&Utf8PathBuf>) -> Result<EffectPlugin> {
+        let Some(effect) = effects::find(name) else {
+            return Err(anyhow!("unknown effect {name}"));
+        };
+        let plugin = match path {
+            Some(path) => effect.load(path, self.sample_rate)?,
+            None => effect.create(self.sample_rate),
+        };
+        Ok((effect, plugin))
+    }
+
+    /// Adds a created effect to the end of a track's effect chain
+    fn add_effect(
+        &mut self,
+        track_idx: usize,
+        effect: &'static Effect,
+        plugin: Box<dyn Plugin + Send>,
+        path: Option<Utf8PathBuf>,
+    ) -> Result<usize> {
+        let node_index = self.get_node_index()?;
+        self.params.insert(node_index, plugin.params());
+        let gain_reduction = plugin.gain_reduction();
+        self.send_to_engine(EngineCommand::CreateNode(node_index, plugin))?;
+        self.tracks[track_idx].effects.push(Device {
+            node_index,
+            name: String::from(effect.name),
+            id: String::from(effect.id),
+            path,
+            sound: None,
+            bypass: false,
+            sidechain: None,
+            gain_reduction,
+        });
+        Ok(node_index)
+    }
+
+    fn check_effect(&self, track_idx: usize, idx: usize) -> Result<()> {
+        match self.tracks.get(track_idx) {
+            Some(track) if idx < track.effects.len() => Ok(()),
+            _ => Err(anyhow!("track {track_idx} has no effect {idx}")),
+        }
+    }
+
+    /// Replaces an effect with one that loads a file, keeping its params and routing
+    fn load_impulse_response(
+        &mut self,
+        track_idx: usize,
+        idx: usize,
+        path: Utf8PathBuf,
+    ) -> Result<()> {
+        self.check_effect(track_idx, idx)?;
+        let effect = &self.tracks[track_idx].effects[idx];
+        let data = DeviceData {
+            id: effect.id.clone(),
+            path: Some(path),
+            params: self.param_values(effect.node_index),
+            bypass: effect.bypass,
+            sidechain: effect.sidechain.and_then(|node| self.track_position(node)),
+        };
+        // The new effect is loaded before the old one is removed, so a file that can't be
+        // loaded leaves the track as it was
+        let remove_new = self.insert_effect(track_idx, idx, data)?;
+        let insert_old = self.remove_effect(track_idx, idx + 1)?;
+        self.history
+            .record(Edit::Batch(vec![insert_old, remove_new]));
+        Ok(())
+    }
+
+    /// Creates an effect at `idx` in a track's chain and returns the edit that removes it again
+    fn insert_effect(&mut self, track_idx: usize, idx: usize, data: DeviceData) -> Result<Edit> {
+        let node_index = self.load_effect(track_idx, &data.id, data.path)?;
+        self.set_param_values(node_index, &data.params);
+        let effects = &mut self.tracks[track_idx].effects;
+        let effect = effects.pop().unwrap();
+        let idx = usize::min(idx, effects.len());
+        effects.insert(idx, effect);
+        if data.bypass {
+            self.set_effect_bypass(track_idx, idx, true)?;
+        }
+        if let Some(source) = data.sidechain.and_then(|i| self.tracks.get(i)) {
+            self.set_sidechain(track_idx, idx, Some(source.node_index))?;
+        }
+        self.update_node_order();
+        Ok(Edit::RemoveEffect(track_idx, idx))
+    }
+
+    /// Removes an effect from a track's chain. It fades out before the engine drops it.
+    fn remove_effect(&mut self, track_idx: usize, idx: usize) -> Result<Edit> {
+        let track = &mut self.tracks[track_idx];
+        let effect = track.effects.remove(idx);
+        self.fading_effects.push(FadingEffect {
+            track_node_index: track.node_index,
+            position: idx,
+            node_index: effect.node_index,
+        });
+        let data = DeviceData {
+            id: effect.id,
+            path: effect.path,
+            params: self.param_values(effect.node_index),
+            bypass: effect.bypass,
+            sidechain: effect.sidechain.and_then(|node| self.track_position(node)),
+        };
+        self.params.remove(&effect.node_index);
+        self.send_to_engine(EngineCommand::DeleteNode(effect.node_index))?;
+        if let Some(source) = effect.sidechain {
+            self.free_sidechain_buffer(source);
+        }
+        self.update_node_order();
+        Ok(Edit::InsertEffect(track_idx, idx, data))
+    }
+
+    fn move_effect(&mut self, track_idx: usize, from: usize, to: usize) -> Edit {
+        let effects = &mut self.tracks[track_idx].effects;
+        let effect = effects.remove(from);
+        effects.insert(to, effect);
+        self.update_node_order();
+        Edit::MoveEffect(track_idx, to, from)
+    }
+
+    fn set_effect_bypass(&mut self, track_idx: usize, idx: usize, bypass: bool) -> Result<Edit> {
+        let effect = &mut self.tracks[track_idx].effects[idx];
+        let old = std::mem::replace(&mut effect.bypass, bypass);
+        let node_index = effect.node_index;
+        self.send_to_engine(EngineCommand::SetBypass(node_index, bypass))?;
+        Ok(Edit::BypassEffect(track_idx, idx, old))
+    }
+
+    /// Makes an effect's sidechain listen to the track with node index `source`, and returns the
+    /// edit that restores the previous source
+    fn set_sidechain(
+        &mut self,
+        track_idx: usize,
+        idx: usize,
+        source: Option<usize>,
+    ) -> Result<Edit> {
+        let effect = &self.tracks[track_idx].effects[idx];
+        if source.is_some() && !effects::find(&effect.id).is_some_and(|e| e.sidechain) {
+            return Err(anyhow!("{} has no sidechain", effect.name));
+        }
+        if let Some(source) = source {
+            self.add_sidechain_buffer(source)?;
+        }
+        let effect = &mut self.tracks[track_idx].effects[idx];
+        let old = std::mem::replace(&mut effect.sidechain, source);
+        if let Some(old) = old {
+            self.free_sidechain_buffer(old);
+        }
+        self.update_node_order();
+        Ok(Edit::Sidechain(track_idx, idx, old))
+    }
+
+    /// Makes sure the track with node index `source` also writes its output to a buffer that
+    /// sidechains can listen to
+    fn add_sidechain_buffer(&mut self, source: usize) -> Result<()> {
+        if !self.sidechain_buffers.contains_key(&source) {
+            let buffer = self.get_node_index()?;
+            self.sidechain_buffers.insert(source, buffer);
+        }
+        Ok(())
+    }
+
+    /// Frees the sidechain buffer of the track with node index `source` once no sidechain listens
+    /// to it anymore
+    fn free_sidechain_buffer(&mut self, source: usize) {
+        let mut effects = self.tracks.iter().flat_map(|t| &t.effects);
+        if effects.any(|e| e.sidechain == Some(source)) {
+            return;
+        }
+        // The buffer has no node, so its index can be reused right away
+        if let Some(buffer) = self.sidechain_buffers.remove(&source) {
+            self.node_indices.remove(buffer);
+        }
+    }
+
+    /// Returns whether an effect on track `track_idx` can listen to track `source`
+    pub fn can_sidechain(&self, track_idx: usize, source: usize) -> bool {
+        self.routing().can_sidechain(track_idx, source)
+    }
+
+    /// Returns how the tracks depend on each other
+    fn routing(&self) -> Routing {
+        let mut routing = Routing::new(self.tracks.len());
+        for (idx, track) in self.tracks.iter().enumerate() {
+            if let Some(output) = self.track_position(track.output_node_index) {
+                routing.add_input(output, idx);
+            }
+            for source in track.effects.iter().filter_map(|e| e.sidechain) {
+                if let Some(source) = self.track_position(source) {
+                    routing.add_input(idx, source);
+                }
+            }
+        }
+        routing
+    }
+
+    /// Returns the track with a node index
+    fn track_position(&self, node_index: usize) -> Option<usize> {
+        self.tracks.iter().position(|t| t.node_index == node_index)
+    }
+
+    fn create_track(
+        &mut self,
+        node_index: usize,
+        output_index: usize,
+        track_type: TrackType,
+        name: Option<String>,
+    ) -> Result<Track> {
+        let engine_track = EngineTrack::new(self.sample_rate);
+        let track = Track::new(
+            node_index,
+            output_index,
+            track_type,
+            name,
+            engine_track.rms_out.clone(),
+        );
+        self.params.insert(node_index, engine_track.params());
+
+        let engine_track: Box<dyn Plugin + Send> = Box::new(engine_track);
+        let cmd = EngineCommand::CreateNode(node_index, engine_track);
+        self.send_to_engine(cmd)?;
+        Ok(track)
+    }
+
+    /// Returns a snapshot of everything needed to restore the current project
+    pub fn project(&self) -> Project {
+        let device = |dev: &Device| DeviceData {
+            id: dev.id.clone(),
+            path: dev.path.clone(),
+            params: self.param_values(dev.node_index),
+            bypass: dev.bypass,
+            sidechain: dev.sidechain.and_then(|node| self.track_position(node)),
+        };
+
+        let instruments = self
+            .instruments
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instr)| instr.as_ref().map(|instr| (i, device(instr))))
+            .collect();
+
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| TrackData {
+                track_type: track.track_type,
+                name: track.name.clone(),
+                output: self
+                    .tracks
+                    .iter()
+                    .position(|t| t.node_index == track.output_node_index),
+                params: self.param_values(track.node_index),
+                effects: track.effects.iter().map(device).collect(),
+            })
+            .collect();
+
+        let mut ids: Vec<PatternId> = self.patterns.keys().copied().collect();
+        ids.sort();
+        let song = self
+            .state
+            .song
+            .iter()
+            .map(|id| ids.iter().position(|i| i == id).unwrap())
+            .collect();
+        let patterns = ids.iter().map(|id| self.patterns[id].clone()).collect();
+
+        Project {
+            bpm: self.state.bpm,
+            lines_per_beat: self.state.lines_per_beat,
+            octave: self.state.octave,
+            loop_range: self.state.loop_range,
+            instruments,
+            tracks,
+            patterns,
+            song,
+        }
+    }
+
+    fn param_values(&self, node_index: usize) -> Vec<f64> {
+        self.params
+            .get(&node_index)
+            .map(|params| params.iter().map(|p| p.target()).collect())
+            .unwrap_or_default()
+    }
+
+    fn set_param_values(&self, node_index: usize, values: &[f64]) {
+        let Some(params) = self.params.get(&node_index) else {
+            return;
+        };
+        for (i, value) in values.iter().enumerate().take(params.len()) {
+            params.get_param(i).set(*value);
+        }
+    }
+
+    /// Replaces the current project. Sounds and effects are loaded before anything is changed, so
+    /// a project that fails to load leaves the current one intact.
+    pub fn load_project(&mut self, mut project: Project) -> Result<()> {
+        let mut sounds = Vec::new();
+        for (slot, instr) in std::mem::take(&mut project.instruments) {
+            if instr.id != "sampler" {
+                return Err(anyhow!("unknown instrument {}", instr.id));
+            }
+            let Some(path) = instr.path else {
+                return Err(anyhow!("instrument {slot} has no sound"));
+            };
+            sounds.push(InstrumentSound {
+                slot,
+                name: path.file_name().unwrap_or_default().to_string(),
+                sound: sampler::load_file(&path)?,
+                path: Some(path),
+                params: instr.params,
+            });
+        }
+        self.replace_project(project, sounds)
+    }
+
+    /// Replaces the current project with a module from another tracker
+    pub fn load_module(&mut self, module: tracker::Module) -> Result<()> {
+        let sounds = module
+            .samples
+            .into_iter()
+            .map(|sample| InstrumentSound {
+                slot: sample.slot,
+                name: sample.name,
+                sound: sample.sound,
+                path: None,
+                params: sample.params,
+            })
+            .collect();
+        self.replace_project(module.project, sounds)?;
+        if !module.warnings.is_empty() {
+            self.message = Some(format!("skipped: {}", module.warnings.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn replace_project(&mut self, project: Project, sounds: Vec<InstrumentSound>) -> Result<()> {
+        if let Some(sound) = sounds.iter().find(|s| s.slot >= MAX_INSTRUMENTS) {
+            return Err(anyhow!("invalid instrument slot {}", sound.slot));
+        }
+        project.check_routing()?;
+        // Effects can fail to load their files, so they are created before the current project
+        // is torn down
+        let mut plugins = Vec::new();
+        for track in &project.tracks {
+            let effects: Vec<EffectPlugin> = track
+                .effects
+                .iter()
+                .map(|data| self.create_effect(&data.id, data.path.as_ref()))
+                .collect::<Result<_>>()?;
+            plugins.push(effects);
+        }
+
+        let instruments = std::mem::replace(&mut self.instruments, vec![None; MAX_INSTRUMENTS]);
+        for instr in instruments.into_iter().flatten() {
+            self.params.remove(&instr.node_index);
+            self.send_to_engine(EngineCommand::DeleteNode(instr.node_index))?;
+        }
+        self.fading_effects.clear();
+        for track in std::mem::take(&mut self.tracks) {
+            for effect in track.effects {
+                self.params.remove(&effect.node_index);
+                self.send_to_engine(EngineCommand::DeleteNode(effect.node_index))?;
+            }
+            self.params.remove(&track.node_index);
+            self.send_to_engine(EngineCommand::DeleteNode(track.node_index))?;
+        }
+        // Sidechain buffers have no node, so nothing has to wait for the engine
+        for (_, buffer) in self.sidechain_buffers.drain() {
+            self.node_indices.remove(buffer);
+        }
+        self.patterns.clear();
+        self.state.patterns.clear();
+
+        // Allocate all track nodes up front so tracks can be routed to tracks that come after them
+        let mut node_indices = Vec::new();
+        for _ in &project.tracks {
+            node_indices.push(self.get_node_index()?);
+        }
+        let mut sidechains = Vec::new();
+        for (i, (data, plugins)) in project.tracks.into_iter().zip(plugins).enumerate() {
+            let output = data.output.map_or(MAIN_OUTPUT, |idx| node_indices[idx]);
+            let track = self.create_track(node_indices[i], output, data.track_type, data.name)?;
+            self.set_param_values(track.node_index, &data.params);
+            self.tracks.push(track);
+
+            for (effect, (info, plugin)) in data.effects.into_iter().zip(plugins) {
+                let node_index = self.add_effect(i, info, plugin, effect.path)?;
+                self.set_param_values(node_index, &effect.params);
+                let idx = self.tracks[i].effects.len() - 1;
+                if effect.bypass {
+                    self.set_effect_bypass(i, idx, true)?;
+                }
+                if let Some(source) = effect.sidechain {
+                    sidechains.push((i, idx, node_indices[source]));
+                }
             }
         }
+        // Sources can come after the tracks that listen to them
+        for (track_idx, idx, source) in sidechains {
+            self.set_sidechain(track_idx, idx, Some(source))?;
+        }
+
+        for instr in sounds {
+            self.load_sound(
+                instr.slot,
+                instr.sound,
+                instr.name,
+                instr.path,
+                &instr.params,
+            )?;
+        }
+
+        let ids: Vec<PatternId> = (0..project.patterns.len() as u64).map(PatternId).collect();
+        for (id, pattern) in ids.iter().zip(project.patterns) {
+            self.patterns.insert(*id, pattern);
+        }
+        self.state.song = project.song.iter().map(|idx| ids[*idx]).collect();
+        self.state.selected_pattern = 0;
+        self.state.is_playing = false;
+        self.state.bpm = project.bpm;
+        self.state.lines_per_beat = project.lines_per_beat;
+        self.state.octave = project.octave;
+        self.state.loop_range = project.loop_range;
+        self.history.clear();
+        self.update_node_order();
+        self.recompile_patterns();
+        Ok(())
+    }
+
+    fn param_changed(&mut self, node_index: usize, param_idx: usize) -> Result<()> {
+        if let Some(slot) = self.instrument_slot(node_index) {
+            if param_idx == SamplerParams::RESAMPLING {
+                self.reload_sound(slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn instrument_slot(&self, node_index: usize) -> Option<usize> {
+        self.instruments
+            .iter()
+            .position(|instr| instr.as_ref().is_some_and(|i| i.node_index == node_index))
+    }
+
+    /// Returns the track and chain index of an effect
+    fn effect_position(&self, node_index: usize) -> Option<(usize, usize)> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .find_map(|(track_idx, track)| {
+                let idx = track
+                    .effects
+                    .iter()
+                    .position(|e| e.node_index == node_index)?;
+                Some((track_idx, idx))
+            })
+    }
+
+    /// Recreates the sampler of an instrument, to convert its sound at a new resampling quality or
+    /// sample rate
+    fn reload_sound(&mut self, slot: usize) -> Result<()> {
+        let Some(instr) = self.instruments[slot].clone() else {
+            return Ok(());
+        };
+        let params = self.param_values(instr.node_index);
+        let sound = match (&instr.path, instr.sound) {
+            (Some(path), _) => sampler::load_file(path)?,
+            (None, Some(sound)) => sound,
+            (None, None) => return Ok(()),
+        };
+        self.load_sound(slot, sound, instr.name, instr.path, &params)?;
+        self.update_node_order();
+        Ok(())
+    }
+
+    /// Writes sounds of instruments that aren't backed by a file to a directory next to the
+    /// project, so the project can refer to them.
+    fn save_embedded_sounds(&mut self, project_path: &Utf8Path) -> Result<()> {
+        let stem = project_path.file_stem().unwrap_or("project");
+        let dir = project_path
+            .parent()
+            .unwrap_or(Utf8Path::new(""))
+            .join(format!("{stem}-samples"));
+        for (slot, instr) in self.instruments.iter_mut().enumerate() {
+            let Some(instr) = instr else { continue };
+            let Some(sound) = &instr.sound else { continue };
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{slot:02}.wav"));
+            sampler::save_file(sound, &path)?;
+            instr.path = Some(path);
+            instr.sound = None;
+        }
+        Ok(())
+    }
 
+    /// Returns the sounds of instruments that aren't backed by a file, by instrument slot
+    pub fn embedded_sounds(&self) -> Vec<(usize, Sound)> {
+        self.instruments
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, instr)| Some((slot, instr.as_ref()?.sound.clone()?)))
+            .collect()
+    }
+
+    /// Keeps the sounds of instruments loaded from files in `dir` in memory instead, for files
+    /// that are about to be removed.
+    pub fn embed_sounds(&mut self, dir: &Utf8Path) -> Result<()> {
+        for instr in self.instruments.iter_mut().flatten() {
+            let Some(path) = instr.path.as_ref().filter(|path| path.starts_with(dir)) else {
+                continue;
+            };
+            instr.sound = Some(sampler::load_file(path)?);
+            instr.path = None;
+        }
         Ok(())
     }
 
@@ -310,6 +1400,57 @@ impl App {
         Msg::UpdatePattern(pattern_id, pattern)
     }
 
+    /// Appends patterns to the song, with their tracks starting at instrument track `track_idx`,
+    /// which is at most the number of instrument tracks. Instrument tracks are created as needed,
+    /// named by `names`.
+    fn import_patterns<I>(
+        &mut self,
+        track_idx: usize,
+        patterns: Vec<Pattern>,
+        names: I,
+    ) -> Result<()>
+    where
+        I: Iterator<Item = String>,
+    {
+        if self.state.patterns.len() + patterns.len() > MAX_PATTERNS {
+            return Err(anyhow!("reached max. number of patterns"));
+        }
+        let num_tracks = patterns.first().map_or(0, |p| p.tracks.len());
+        let num_instruments = self.num_instrument_tracks();
+        // Undoing the import removes the patterns first and then the tracks
+        let mut edits = Vec::new();
+        for (i, name) in names.enumerate().take(num_tracks) {
+            let idx = track_idx + i;
+            if idx >= num_instruments {
+                let edit = self.add_track(idx, MASTER_TRACK, TrackType::Instrument, Some(name))?;
+                edits.push(edit);
+            }
+        }
+        edits.push(Edit::Song(self.song_snapshot()));
+        edits.reverse();
+        self.history.record(Edit::Batch(edits));
+
+        let num_instruments = self.num_instrument_tracks();
+        for imported in patterns {
+            let mut pattern = Pattern::new(num_instruments);
+            pattern.set_len(imported.len());
+            for (i, track) in imported.tracks.into_iter().enumerate() {
+                pattern.tracks[track_idx + i] = track;
+            }
+            let id = self.next_pattern_id();
+            self.patterns.insert(id, pattern);
+            self.state.song.push(id);
+        }
+        Ok(())
+    }
+
+    fn num_instrument_tracks(&self) -> usize {
+        self.tracks
+            .iter()
+            .filter(|track| matches!(track.track_type, TrackType::Instrument))
+            .count()
+    }
+
     fn next_pattern_id(&self) -> PatternId {
         if self.state.patterns.is_empty() {
             return PatternId(0);
@@ -365,17 +1506,36 @@ impl App {
             entries.push(NodeEntry::new(instr.node_index, None));
         }
 
-        for track in &self.tracks {
+        for track_idx in self.routing().order() {
+            let track = &self.tracks[track_idx];
             let mut input = track.node_index;
             let mut output = SCRATCH_BUFFER;
 
-            for effect in &track.effects {
-                let entry = NodeEntry::new(effect.node_index, Some((input, output)));
+            let sidechain_buffer = |effect: &Device| {
+                let source = effect.sidechain?;
+                self.sidechain_buffers.get(&source).copied()
+            };
+            let mut chain: Vec<(usize, Option<usize>)> = track
+                .effects
+                .iter()
+                .map(|e| (e.node_index, sidechain_buffer(e)))
+                .collect();
+            for effect in &self.fading_effects {
+                if effect.track_node_index == track.node_index {
+                    let position = usize::min(effect.position, chain.len());
+                    chain.insert(position, (effect.node_index, None));
+                }
+            }
+            for (node_index, sidechain) in chain {
+                let mut entry = NodeEntry::new(node_index, Some((input, output)));
+                entry.sidechain = sidechain;
                 entries.push(entry);
                 (input, output) = (output, input);
             }
 
-            let entry = NodeEntry::new(track.node_index, Some((input, track.output_node_index)));
+            let mut entry =
+                NodeEntry::new(track.node_index, Some((input, track.output_node_index)));
+            entry.send = self.sidechain_buffers.get(&track.node_index).copied();
             entries.push(entry);
         }
 
@@ -395,8 +1555,8 @@ fn compile_pattern(
             let offset = u8::min(TICKS_PER_LINE as u8 - 1, step.offset().unwrap_or(0));
             let note_offset = pattern_offset + offset as usize;
             pattern_offset += TICKS_PER_LINE;
-            let instr_idx = step.instrument().unwrap_or(i as u8);
-            let Some(instr) = &instruments[instr_idx as usize] else {
+            let instr_idx = step.instrument().map_or(i, usize::from);
+            let Some(Some(instr)) = instruments.get(instr_idx) else {
                 continue;
             };
             let track_idx = tracks[i].node_index;
@@ -433,6 +1593,7 @@ impl EngineState {
 
 pub enum AppCommand {
     DropPlugin(usize, Box<dyn Plugin + Send>),
+    DropPool(Box<Pool>),
 }
 
 #[derive(Clone)]
@@ -514,10 +1675,57 @@ impl Track {
     }
 }
 
+/// An effect from the registry, created but not yet added to a track
+type EffectPlugin = (&'static Effect, Box<dyn Plugin + Send>);
+
+struct InstrumentSound {
+    slot: usize,
+    name: String,
+    sound: Sound,
+    path: Option<Utf8PathBuf>,
+    params: Vec<f64>,
+}
+
 #[derive(Clone)]
 pub struct Device {
     pub node_index: usize,
     pub name: String,
+    /// Identifies the kind of device so it can be recreated when a project is loaded
+    pub id: String,
+    pub path: Option<Utf8PathBuf>,
+    /// Sound of an instrument that isn't backed by a file, like a sample from an imported module.
+    /// It's written next to the project when the project is saved.
+    sound: Option<Sound>,
+    /// Whether an effect is bypassed
+    pub bypass: bool,
+    /// Node index of the track that an effect's sidechain listens to
+    pub sidechain: Option<usize>,
+    gain_reduction: Option<Arc<AtomicF64>>,
+}
+
+impl Device {
+    /// Current gain reduction in dB, for effects that report it
+    pub fn gain_reduction(&self) -> Option<f64> {
+        self.gain_reduction
+            .as_ref()
+            .map(|gr| gr.load(Ordering::Relaxed))
+    }
+}
+
+/// Edit that couldn't be applied, with the error
+struct FailedEdit(Box<Edit>, anyhow::Error);
+
+impl FailedEdit {
+    fn new(edit: Edit, err: anyhow::Error) -> Self {
+        Self(Box::new(edit), err)
+    }
+}
+
+struct FadingEffect {
+    track_node_index: usize,
+    /// Position in the effect chain when the effect was removed
+    position: usize,
+    node_index: usize,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -550,9 +1758,11 @@ pub fn new() -> Result<(App, Output<AppState>, Engine, Output<EngineState>)> {
     let (engine_state_input, engine_state_output) = TripleBuffer::new(&engine_state).split();
 
     let params = HashMap::new();
-    let node_indices = BitSet::with_capacity(MAX_NODES);
+    let mut node_indices = BitSet::with_capacity(INITIAL_POOL_SIZE);
+    node_indices.insert(MAIN_OUTPUT);
+    node_indices.insert(SCRATCH_BUFFER);
 
-    let (eng_producer, eng_consumer) = RingBuffer::<EngineCommand>::new(64).split();
+    let (eng_producer, eng_consumer) = RingBuffer::<EngineCommand>::new(COMMAND_QUEUE_SIZE).split();
     let (app_producer, app_consumer) = RingBuffer::<AppCommand>::new(64).split();
 
     let engine = Engine::new(engine_state, engine_state_input, eng_consumer, app_producer);
@@ -564,14 +1774,23 @@ pub fn new() -> Result<(App, Output<AppState>, Engine, Output<EngineState>)> {
         state_buf: app_state_input,
         producer: eng_producer,
         consumer: app_consumer,
-        file_browser: FileBrowser::with_path("./sounds")?,
+        file_browser: FileBrowser::with_path("./sounds")
+            .or_else(|_| FileBrowser::with_path("."))?,
         params,
         preview_cache,
         engine_state: EngineState::default(),
         patterns: HashMap::new(),
         node_indices,
+        fading_effects: Vec::new(),
+        sidechain_buffers: HashMap::new(),
+        pool_size: INITIAL_POOL_SIZE,
         tracks: Vec::new(),
         instruments: vec![None; MAX_INSTRUMENTS],
+        project_path: None,
+        message: None,
+        history: History::default(),
+        audio: None,
+        sample_rate: DEFAULT_SAMPLE_RATE,
     };
 
     Ok((app, app_state_output, engine, engine_state_output))
@@ -582,7 +1801,23 @@ pub enum Msg {
     Exit,
     TogglePlay,
     LoadSound(usize, Utf8PathBuf),
+    /// Adds an effect to a track, by id or name
     LoadEffect(usize, String),
+    ListEffects,
+    /// Loads an impulse response into an effect, by track and effect index
+    LoadImpulseResponse(usize, usize, Utf8PathBuf),
+    /// Removes an effect from a track, by track and effect index
+    DeleteEffect(usize, usize),
+    /// Moves an effect of a track from one index to another
+    MoveEffect(usize, usize, usize),
+    ToggleEffectBypass(usize, usize),
+    /// Makes the sidechain of an effect listen to a track, by track and effect index and the
+    /// index of the source track
+    SetSidechain(usize, usize, Option<usize>),
+    SaveProject(Option<Utf8PathBuf>),
+    LoadProject(Utf8PathBuf),
+    ImportMidi(Utf8PathBuf, usize),
+    ExportMidi(Utf8PathBuf),
     DeleteInstrument(usize),
     PreviewSound(Utf8PathBuf),
     LoopAdd(usize),
@@ -606,6 +1841,13 @@ pub enum Msg {
     ToggleMute(usize),
     TrackVolumeIncr(usize),
     TrackVolumeDecr(usize),
+    Undo,
+    Redo,
+    ListDevices,
+    SelectDevice(String),
+    SetBufferSize(Option<usize>),
+    SetOutputChannels([usize; 2]),
+    SetSampleRate(u32),
 }
 
 impl Msg {
@@ -614,7 +1856,7 @@ impl Msg {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct PatternId(u64);
 
 impl Display for PatternId {
@@ -634,6 +1876,10 @@ pub fn random_color() -> Color {
 pub struct NodeEntry {
     pub node_index: usize,
     pub buffers: Option<(usize, usize)>,
+    /// Buffer with the signal that the node's sidechain listens to
+    pub sidechain: Option<usize>,
+    /// Buffer that also gets the node's output, for nodes that other nodes listen to
+    pub send: Option<usize>,
 }
 
 impl NodeEntry {
@@ -641,6 +1887,8 @@ impl NodeEntry {
         Self {
             node_index,
             buffers,
+            sidechain: None,
+            send: None,
         }
     }
 }
@@ -0,0 +1,266 @@
+//! Periodic saving of the project to a recovery directory, so a session can be restored after
+//! a crash.
+//!
+//! While the app runs, a marker file exists in the recovery directory. It's removed when the app
+//! exits normally, so finding it on start means the last session didn't shut down cleanly. The
+//! session keeps the marker locked, which the system undoes when the process ends, so a session
+//! that's still running in the same directory isn't taken for a crashed one.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::app::App;
+use crate::project::{self, Project};
+use crate::sampler::{self, Sound};
+
+pub const INTERVAL: Duration = Duration::from_secs(30);
+pub const RECOVERY_DIR: &str = ".unsound";
+
+const MARKER_FILE: &str = "session";
+const PROJECT_FILE: &str = "autosave.txt";
+/// Contains the path the project was saved to by the user, if any
+const PATH_FILE: &str = "autosave-path";
+const SAMPLES_DIR: &str = "autosave-samples";
+
+/// A project left behind by a session that didn't shut down cleanly
+pub struct Recovery {
+    pub project: Utf8PathBuf,
+    pub project_path: Option<Utf8PathBuf>,
+}
+
+/// Returns the autosaved project in `dir`, if the last session didn't shut down cleanly
+pub fn recover(dir: &Utf8Path) -> Option<Recovery> {
+    let project = dir.join(PROJECT_FILE);
+    let marker = dir.join(MARKER_FILE);
+    if !marker.exists() || !project.exists() || is_locked(&marker) {
+        return None;
+    }
+    let project_path = fs::read_to_string(dir.join(PATH_FILE))
+        .ok()
+        .map(|path| Utf8PathBuf::from(path.trim_end()))
+        .filter(|path| !path.as_str().is_empty());
+    Some(Recovery {
+        project,
+        project_path,
+    })
+}
+
+struct Snapshot {
+    project: Project,
+    project_path: Option<Utf8PathBuf>,
+    /// Sounds of instruments that aren't backed by a file
+    sounds: Vec<(usize, Sound)>,
+}
+
+pub struct Autosave {
+    dir: Utf8PathBuf,
+    interval: Duration,
+    last_save: Instant,
+    /// The last project that was saved, to skip saving when nothing changed
+    last_project: Option<String>,
+    sender: Option<Sender<Snapshot>>,
+    writer: Option<JoinHandle<()>>,
+    /// The locked marker file, which is unlocked when it's closed
+    marker: Option<File>,
+}
+
+impl Autosave {
+    /// Starts a new session, discarding what was autosaved by the previous one. Files are written
+    /// on a background thread. Fails if another session is running in the same directory.
+    pub fn start(dir: &Utf8Path, interval: Duration) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("unable to create {dir}"))?;
+        let path = dir.join(MARKER_FILE);
+        let mut marker = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("unable to open {path}"))?;
+        match marker.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(anyhow!("another session is running in {dir}"));
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("unable to lock {path}"));
+            }
+        }
+        // The marker stays, because another session could lock a new file at the same path
+        clear_files(dir)?;
+        marker.set_len(0)?;
+        write!(marker, "{}", std::process::id())?;
+
+        let (sender, receiver) = mpsc::channel::<Snapshot>();
+        let writer_dir = dir.to_path_buf();
+        let writer = thread::spawn(move || {
+            for snapshot in receiver {
+                if let Err(err) = write(&writer_dir, snapshot) {
+                    eprintln!("error: autosave failed: {err:?}");
+                }
+            }
+        });
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            interval,
+            last_save: Instant::now(),
+            last_project: None,
+            sender: Some(sender),
+            writer: Some(writer),
+            marker: Some(marker),
+        })
+    }
+
+    /// Saves the project if the interval has passed since the last save
+    pub fn tick(&mut self, app: &App) {
+        if self.last_save.elapsed() >= self.interval {
+            self.save(app);
+        }
+    }
+
+    /// Saves the project now, unless it didn't change since the last save
+    pub fn save(&mut self, app: &App) {
+        self.last_save = Instant::now();
+        let project = app.project();
+        let serialized = project.serialize();
+        if self.last_project.as_ref() == Some(&serialized) {
+            return;
+        }
+        self.last_project = Some(serialized);
+
+        let snapshot = Snapshot {
+            project,
+            project_path: app.project_path.clone(),
+            sounds: app.embedded_sounds(),
+        };
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(snapshot);
+        }
+    }
+
+    /// Ends the session after a clean shutdown by removing the recovery files
+    pub fn finish(mut self) -> Result<()> {
+        self.wait();
+        clear_files(&self.dir)?;
+        // Some systems can't remove a file that's still open
+        self.marker = None;
+        let marker = self.dir.join(MARKER_FILE);
+        fs::remove_file(&marker).with_context(|| format!("unable to remove {marker}"))
+    }
+
+    fn wait(&mut self) {
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for Autosave {
+    /// Waits for pending saves, so they're not lost when the app exits with an error
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+fn write(dir: &Utf8Path, snapshot: Snapshot) -> Result<()> {
+    let Snapshot {
+        mut project,
+        project_path,
+        sounds,
+    } = snapshot;
+
+    let samples_dir = dir.join(SAMPLES_DIR);
+    if !sounds.is_empty() {
+        fs::create_dir_all(&samples_dir)?;
+    }
+    for (slot, sound) in sounds {
+        let path = samples_dir.join(format!("{slot:02}.wav"));
+        sampler::save_file(&sound, &path)?;
+        if let Some((_, device)) = project.instruments.iter_mut().find(|(s, _)| *s == slot) {
+            device.path = Some(path);
+        }
+    }
+
+    let path = project_path.map(|p| p.to_string()).unwrap_or_default();
+    fs::write(dir.join(PATH_FILE), path)?;
+    // Write to a temporary file first, so a crash while saving doesn't leave a partial project
+    let tmp = dir.join(format!("{PROJECT_FILE}.tmp"));
+    project::save(&tmp, &project)?;
+    fs::rename(&tmp, dir.join(PROJECT_FILE))?;
+    Ok(())
+}
+
+/// Returns whether a running session holds the lock on a marker file
+fn is_locked(marker: &Utf8Path) -> bool {
+    File::open(marker).is_ok_and(|file| matches!(file.try_lock(), Err(TryLockError::WouldBlock)))
+}
+
+/// Removes the autosaved files, but not the marker
+fn clear_files(dir: &Utf8Path) -> Result<()> {
+    for file in [PROJECT_FILE, PATH_FILE] {
+        let path = dir.join(file);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("unable to remove {path}"))?;
+        }
+    }
+    let samples_dir = dir.join(SAMPLES_DIR);
+    if samples_dir.exists() {
+        fs::remove_dir_all(&samples_dir)
+            .with_context(|| format!("unable to remove {samples_dir}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{self, Msg, TrackType};
+    use crate::engine::{MAIN_OUTPUT, MASTER_TRACK};
+
+    #[test]
+    fn recover_after_unclean_shutdown() -> Result<()> {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-autosave");
+        let (mut app, _, _, _) = app::new()?;
+        app.send(Msg::CreateTrack(
+            MASTER_TRACK,
+            MAIN_OUTPUT,
+            TrackType::Bus,
+            None,
+        ))?;
+        app.send(Msg::CreateTrack(
+            0,
+            MASTER_TRACK,
+            TrackType::Instrument,
+            None,
+        ))?;
+        app.send(Msg::CreatePattern(None))?;
+        app.project_path = Some(Utf8PathBuf::from("song.txt"));
+
+        let mut autosave = Autosave::start(&dir, Duration::ZERO)?;
+        assert!(recover(&dir).is_none());
+        autosave.tick(&app);
+        autosave.wait();
+        // A second instance in the same directory leaves the running session alone
+        assert!(recover(&dir).is_none());
+        assert!(Autosave::start(&dir, INTERVAL).is_err());
+        // Dropping the autosave without finishing it is like a crash
+        drop(autosave);
+
+        let recovery = recover(&dir).unwrap();
+        assert_eq!(Some(Utf8PathBuf::from("song.txt")), recovery.project_path);
+        let project = project::load(&recovery.project)?;
+        assert_eq!(2, project.tracks.len());
+        assert_eq!(1, project.song.len());
+
+        Autosave::start(&dir, INTERVAL)?.finish()?;
+        assert!(recover(&dir).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Audio backends run the engine and send its output somewhere, like a sound card or a file.
+
+mod cpal;
+mod file;
+mod null;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use assert_no_alloc::assert_no_alloc;
+use camino::Utf8PathBuf;
+use triple_buffer::Output;
+
+use crate::app::AppState;
+use crate::audio::Stereo;
+use crate::engine::Engine;
+use crate::{DEFAULT_SAMPLE_RATE, FRAMES_PER_BUFFER, INTERNAL_BUFFER_SIZE};
+
+pub use self::cpal::CpalBackend;
+pub use self::file::FileBackend;
+pub use self::null::NullBackend;
+
+pub const BACKENDS: &str = "cpal|null|file:<output.wav>";
+
+pub trait AudioBackend {
+    /// Starts processing audio. Processing stops when the backend is dropped.
+    fn start(&mut self, processor: Processor, config: AudioConfig) -> Result<()>;
+
+    fn config(&self) -> &AudioConfig;
+
+    /// Whether the configuration can be changed with [`AudioBackend::set_config`]
+    fn can_reconfigure(&self) -> bool {
+        false
+    }
+
+    /// Restarts processing with a new configuration
+    fn set_config(&mut self, _config: AudioConfig) -> Result<()> {
+        Err(anyhow!(
+            "the audio backend can't be reconfigured while it's running"
+        ))
+    }
+
+    /// Lists the output devices of all hosts
+    fn devices(&self) -> Result<Vec<OutputDevice>> {
+        Ok(Vec::new())
+    }
+
+    /// Switches to the output device called `name`, keeping the engine's state
+    fn select_device(&mut self, _name: &str) -> Result<()> {
+        Err(anyhow!("the audio backend has no output devices"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioConfig {
+    /// Frames per buffer, or `None` for the default of the device
+    pub buffer_size: Option<usize>,
+    /// Output channels that the left and right channel are written to, starting at 0
+    pub channels: [usize; 2],
+    pub sample_rate: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: Some(FRAMES_PER_BUFFER),
+            channels: [0, 1],
+            sample_rate: DEFAULT_SAMPLE_RATE as u32,
+        }
+    }
+}
+
+/// Parses a channel mapping like "1,2", where channels are numbered from 1
+pub fn parse_channels(s: &str) -> Result<[usize; 2]> {
+    let parse = |ch: &str| match ch.trim().parse::<usize>() {
+        Ok(ch) if ch > 0 => Ok(ch - 1),
+        _ => Err(anyhow!("invalid channel {ch}")),
+    };
+    let Some((left, right)) = s.split_once(',') else {
+        return Err(anyhow!("expected <left>,<right> channels, got {s}"));
+    };
+    Ok([parse(left)?, parse(right)?])
+}
+
+pub struct OutputDevice {
+    pub host: String,
+    pub name: String,
+    pub selected: bool,
+}
+
+/// Creates a backend by name, as listed in [`BACKENDS`]
+pub fn new(name: &str) -> Result<Box<dyn AudioBackend>> {
+    let backend: Box<dyn AudioBackend> = match name.split_once(':') {
+        Some(("file", path)) if !path.is_empty() => {
+            Box::new(FileBackend::new(Utf8PathBuf::from(path)))
+        }
+        _ => match name {
+            "cpal" => Box::new(CpalBackend::new()),
+            "null" => Box::new(NullBackend::new()),
+            _ => return Err(anyhow!("unknown audio backend {name}, expected {BACKENDS}")),
+        },
+    };
+    Ok(backend)
+}
+
+/// Runs the engine for a backend
+pub struct Processor {
+    engine: Engine,
+    app_state: Output<AppState>,
+    buf: Vec<Stereo>,
+}
+
+impl Processor {
+    pub fn new(engine: Engine, app_state: Output<AppState>) -> Self {
+        Self {
+            engine,
+            app_state,
+            buf: vec![Stereo::ZERO; INTERNAL_BUFFER_SIZE],
+        }
+    }
+
+    /// Must not be called while the processor is running, see [`Engine::set_sample_rate`]
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.engine.set_sample_rate(sample_rate);
+    }
+
+    /// Fills `output`, which is interleaved with `channels` channels, with the next frames. The
+    /// left and right channel are written to the output channels in `mapping`, other channels are
+    /// silent. When both map to the same channel, it gets their average.
+    pub fn process_interleaved(
+        &mut self,
+        output: &mut [f32],
+        channels: usize,
+        mapping: [usize; 2],
+    ) {
+        let gain = if mapping[0] == mapping[1] { 0.5 } else { 1.0 };
+        assert_no_alloc(|| {
+            for chunk in output.chunks_mut(INTERNAL_BUFFER_SIZE * channels) {
+                let frames = chunk.len() / channels;
+                let buf = &mut self.buf[..frames];
+                self.engine.process(self.app_state.read(), buf);
+                for (frame, out) in buf.iter_mut().zip(chunk.chunks_mut(channels)) {
+                    out.fill(0.0);
+                    for (ch, output_ch) in mapping.iter().enumerate() {
+                        if let Some(sample) = out.get_mut(*output_ch) {
+                            *sample += gain * frame.channel(ch);
+                        }
+                    }
+                    *frame = Stereo::ZERO;
+                }
+            }
+        });
+    }
+}
+
+/// Drives a processor from a thread at the rate a sound card would, for backends without one
+struct Timer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Timer {
+    /// Starts processing stereo buffers and passes them to `write`. Processing stops when
+    /// writing fails.
+    fn start<F>(mut processor: Processor, config: &AudioConfig, mut write: F) -> Self
+    where
+        F: FnMut(&[f32]) -> Result<()> + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let frames = config.buffer_size.unwrap_or(FRAMES_PER_BUFFER);
+        let sample_rate = config.sample_rate as f64;
+        processor.set_sample_rate(sample_rate);
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let period = Duration::from_secs_f64(frames as f64 / sample_rate);
+                let mut output = vec![0.0; 2 * frames];
+                let mut next = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    processor.process_interleaved(&mut output, 2, [0, 1]);
+                    if let Err(err) = write(&output) {
+                        eprintln!("error while processing audio {:?}", err);
+                        return;
+                    }
+                    next += period;
+                    if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                        thread::sleep(wait);
+                    }
+                }
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hound::WavReader;
+
+    use super::*;
+    use crate::app;
+
+    #[test]
+    fn file_backend_writes_in_real_time() -> Result<()> {
+        let path = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-backend.wav");
+        let (_app, app_state, engine, _) = app::new()?;
+
+        let mut backend = new(&format!("file:{path}"))?;
+        let config = AudioConfig {
+            sample_rate: 48000,
+            ..AudioConfig::default()
+        };
+        backend.start(Processor::new(engine, app_state), config)?;
+        thread::sleep(Duration::from_millis(200));
+        drop(backend);
+
+        let wav = WavReader::open(&path)?;
+        assert_eq!(2, wav.spec().channels);
+        assert_eq!(48000, wav.spec().sample_rate);
+        // Roughly 200ms of audio, allowing for a slow test machine
+        let frames = wav.duration() as f64;
+        assert!(frames > 0.1 * 48000.0 && frames < 0.3 * 48000.0);
+        Ok(())
+    }
+
+    #[test]
+    fn channel_mapping() -> Result<()> {
+        assert_eq!([2, 3], parse_channels("3,4")?);
+        assert!(parse_channels("0,1").is_err());
+        assert!(parse_channels("1").is_err());
+
+        let (_app, app_state, engine, _) = app::new()?;
+        let mut processor = Processor::new(engine, app_state);
+        let mut output = [1.0; 4 * 8];
+        processor.process_interleaved(&mut output, 4, [2, 3]);
+        assert!(output.iter().all(|s| *s == 0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_backend() {
+        assert!(new("jack").is_err());
+        assert!(new("file:").is_err());
+    }
+}
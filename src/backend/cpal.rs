@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+
+use ::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ::cpal::{BufferSize, Device, Host, HostId, SampleRate, Stream};
+use anyhow::{anyhow, Result};
+
+use super::{AudioBackend, AudioConfig, OutputDevice, Processor};
+
+/// Plays the engine's output on a sound card. Starts with the default output device of the
+/// default host.
+pub struct CpalBackend {
+    host_id: HostId,
+    /// Name of the selected device, or `None` for the default device of the host
+    device_name: Option<String>,
+    config: AudioConfig,
+    /// Shared with the stream's callback. It's only locked by another thread while the stream is
+    /// rebuilt, so the callback doesn't have to wait for it.
+    processor: Option<Arc<Mutex<Processor>>>,
+    stream: Option<Stream>,
+}
+
+impl Default for CpalBackend {
+    fn default() -> Self {
+        Self {
+            host_id: ::cpal::default_host().id(),
+            device_name: None,
+            config: AudioConfig::default(),
+            processor: None,
+            stream: None,
+        }
+    }
+}
+
+impl CpalBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn device(&self) -> Result<Device> {
+        let host = ::cpal::host_from_id(self.host_id)?;
+        match &self.device_name {
+            Some(name) => {
+                find_device(&host, name)?.ok_or_else(|| anyhow!("can't find output device {name}"))
+            }
+            None => host
+                .default_output_device()
+                .ok_or_else(|| anyhow!("can't find output device")),
+        }
+    }
+
+    /// Replaces the stream with one for the selected device and config
+    fn restart(&mut self) -> Result<()> {
+        let Some(processor) = self.processor.clone() else {
+            return Ok(());
+        };
+        // Dropping the stream stops its callback before the new one starts
+        self.stream = None;
+        let device = self.device()?;
+        processor
+            .lock()
+            .map_err(|_| anyhow!("audio processing panicked"))?
+            .set_sample_rate(self.config.sample_rate as f64);
+
+        let mut config = device.default_output_config()?.config();
+        config.sample_rate = SampleRate(self.config.sample_rate);
+        config.buffer_size = match self.config.buffer_size {
+            Some(size) => BufferSize::Fixed(size as u32),
+            None => BufferSize::Default,
+        };
+
+        let channels = config.channels as usize;
+        let mapping = self.config.channels;
+        if let Some(ch) = mapping.iter().find(|ch| **ch >= channels) {
+            return Err(anyhow!("output device has no channel {}", ch + 1));
+        }
+        let stream = device.build_output_stream(
+            &config,
+            move |output: &mut [f32], _: &::cpal::OutputCallbackInfo| match processor.try_lock() {
+                Ok(mut processor) => processor.process_interleaved(output, channels, mapping),
+                Err(_) => output.fill(0.0),
+            },
+            move |err| eprintln!("error while processing audio {}", err),
+            None,
+        )?;
+        stream.play()?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Restarts with the settings after `f` was applied. The previous settings are restored when
+    /// the stream can't be started.
+    fn restart_with<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self),
+    {
+        let previous = (self.host_id, self.device_name.clone(), self.config.clone());
+        f(self);
+        if let Err(err) = self.restart() {
+            (self.host_id, self.device_name, self.config) = previous;
+            self.restart()?;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn start(&mut self, processor: Processor, config: AudioConfig) -> Result<()> {
+        self.processor = Some(Arc::new(Mutex::new(processor)));
+        self.config = config;
+        self.restart()
+    }
+
+    fn config(&self) -> &AudioConfig {
+        &self.config
+    }
+
+    fn can_reconfigure(&self) -> bool {
+        true
+    }
+
+    fn set_config(&mut self, config: AudioConfig) -> Result<()> {
+        self.restart_with(|backend| backend.config = config)
+    }
+
+    fn devices(&self) -> Result<Vec<OutputDevice>> {
+        let selected = self.device()?.name()?;
+        let mut devices = Vec::new();
+        for host_id in ::cpal::available_hosts() {
+            let Ok(host) = ::cpal::host_from_id(host_id) else {
+                continue;
+            };
+            // Hosts can fail to list devices, e.g. when their server isn't running
+            let Ok(output_devices) = host.output_devices() else {
+                continue;
+            };
+            for device in output_devices {
+                let Ok(name) = device.name() else { continue };
+                devices.push(OutputDevice {
+                    host: host_id.name().to_string(),
+                    selected: host_id == self.host_id && name == selected,
+                    name,
+                });
+            }
+        }
+        Ok(devices)
+    }
+
+    fn select_device(&mut self, name: &str) -> Result<()> {
+        // Devices of the current host take precedence over devices with the same name on
+        // other hosts
+        let mut host_ids = ::cpal::available_hosts();
+        host_ids.sort_by_key(|id| *id != self.host_id);
+        for host_id in host_ids {
+            let Ok(host) = ::cpal::host_from_id(host_id) else {
+                continue;
+            };
+            if let Ok(Some(_)) = find_device(&host, name) {
+                return self.restart_with(|backend| {
+                    backend.host_id = host_id;
+                    backend.device_name = Some(name.to_string());
+                });
+            }
+        }
+        Err(anyhow!("can't find output device {name}"))
+    }
+}
+
+fn find_device(host: &Host, name: &str) -> Result<Option<Device>> {
+    let device = host
+        .output_devices()?
+        .find(|device| device.name().is_ok_and(|n| n == name));
+    Ok(device)
+}
@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use super::{AudioBackend, AudioConfig, Processor, Timer};
+
+/// Runs the engine in real time and writes its output to a 32-bit float WAV file. The channel
+/// mapping of the config doesn't apply, the file always has two channels.
+pub struct FileBackend {
+    path: Utf8PathBuf,
+    config: AudioConfig,
+    timer: Option<Timer>,
+}
+
+impl FileBackend {
+    pub fn new(path: Utf8PathBuf) -> Self {
+        Self {
+            path,
+            config: AudioConfig::default(),
+            timer: None,
+        }
+    }
+}
+
+impl AudioBackend for FileBackend {
+    fn start(&mut self, processor: Processor, config: AudioConfig) -> Result<()> {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: config.sample_rate,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let path = &self.path;
+        let mut wav =
+            WavWriter::create(path, spec).with_context(|| format!("unable to create {path}"))?;
+        // The writer finalizes the file when it's dropped with the timer's thread
+        self.timer = Some(Timer::start(processor, &config, move |output| {
+            for sample in output {
+                wav.write_sample(*sample)?;
+            }
+            Ok(())
+        }));
+        self.config = config;
+        Ok(())
+    }
+
+    fn config(&self) -> &AudioConfig {
+        &self.config
+    }
+}
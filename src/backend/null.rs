@@ -0,0 +1,28 @@
+use anyhow::Result;
+
+use super::{AudioBackend, AudioConfig, Processor, Timer};
+
+/// Runs the engine in real time and discards its output, for machines without a sound card
+#[derive(Default)]
+pub struct NullBackend {
+    config: AudioConfig,
+    timer: Option<Timer>,
+}
+
+impl NullBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn start(&mut self, processor: Processor, config: AudioConfig) -> Result<()> {
+        self.timer = Some(Timer::start(processor, &config, |_| Ok(())));
+        self.config = config;
+        Ok(())
+    }
+
+    fn config(&self) -> &AudioConfig {
+        &self.config
+    }
+}
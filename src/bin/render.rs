@@ -0,0 +1,62 @@
+use std::env;
+use std::io::{self, Write};
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+
+use unsound::project;
+use unsound::render::{self, RenderOptions, RenderRange};
+
+const USAGE: &str = "usage: render <project> <output.wav> [--loop | --patterns <start>-<end>] \
+                     [--bits 16|24|32f] [--sample-rate <hz>] [--tail <seconds>] \
+                     [--stems <dir>]";
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("error: {err:#}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let (Some(input), Some(output)) = (args.next(), args.next()) else {
+        return Err(anyhow!(USAGE));
+    };
+
+    let mut options = RenderOptions::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{arg} requires a value"));
+        match arg.as_str() {
+            "--loop" => options.range = RenderRange::Loop,
+            "--patterns" => {
+                let value = value()?;
+                let Some((start, end)) = value.split_once('-') else {
+                    return Err(anyhow!("invalid pattern range {value}"));
+                };
+                options.range = RenderRange::Patterns(start.parse()?, end.parse()?);
+            }
+            "--bits" => options.bit_depth = value()?.parse()?,
+            "--sample-rate" => options.sample_rate = value()?.parse()?,
+            "--tail" => options.tail = value()?.parse()?,
+            "--stems" => options.stems = Some(Utf8PathBuf::from(value()?)),
+            _ => return Err(anyhow!(USAGE)),
+        }
+    }
+
+    let project = project::load(&Utf8PathBuf::from(input))?;
+    let output = Utf8PathBuf::from(output);
+
+    let mut last_percentage = None;
+    render::render(project, &output, &options, |progress| {
+        let percentage = (progress * 100.0) as usize;
+        if last_percentage != Some(percentage) {
+            last_percentage = Some(percentage);
+            eprint!("\rrendering {output} {percentage:3}%");
+            let _ = io::stderr().flush();
+        }
+    })?;
+    eprintln!();
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use atomic_float::AtomicF64;
+
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, format_millis, Param, ParamInfo, Params, Smoothing};
+use param_derive::Params;
+
+/// Level that the detector treats as silence, so the level in dB stays finite
+const MIN_LEVEL_DB: f64 = -120.0;
+
+#[derive(Params)]
+pub struct CompressorParams {
+    threshold: Param,
+    ratio: Param,
+    attack: Param,
+    release: Param,
+    knee: Param,
+    makeup: Param,
+}
+
+impl CompressorParams {
+    fn new(sample_rate: f64) -> Self {
+        let format_db = |v| format!("{:.1}dB", v);
+        Self {
+            threshold: Param::new(
+                -18.0,
+                ParamInfo::new("Threshold", -60, 0)
+                    .with_steps([0.5, 3.0])
+                    .with_formatter(format_db),
+            ),
+            ratio: Param::new(
+                4.0,
+                ParamInfo::new("Ratio", 1, 20)
+                    .with_steps([0.1, 1.0])
+                    .with_formatter(|v| format!("{:.1}:1", v)),
+            ),
+            attack: Param::new(
+                10.0,
+                ParamInfo::new("Attack", 0.1, 100.0)
+                    .with_steps([0.1, 1.0])
+                    .with_formatter(|v| format!("{:.1}ms", v)),
+            ),
+            release: Param::new(
+                100.0,
+                ParamInfo::new("Release", 5, 1000)
+                    .with_steps([1, 10])
+                    .with_formatter(format_millis),
+            ),
+            knee: Param::new(
+                6.0,
+                ParamInfo::new("Knee", 0, 24)
+                    .with_steps([0.5, 3.0])
+                    .with_formatter(format_db),
+            ),
+            makeup: Param::new(
+                0.0,
+                ParamInfo::new("Makeup", 0, 24)
+                    .with_steps([0.5, 3.0])
+                    .with_formatter(format_db)
+                    .with_map(params::db_to_amp)
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+        }
+    }
+
+    /// Returns how many dB a signal at `level` dB is turned down, with a soft knee around the
+    /// threshold
+    fn gain_reduction(&self, level: f64) -> f64 {
+        let threshold = self.threshold.target();
+        let ratio = self.ratio.target();
+        let knee = self.knee.target();
+        let over = level - threshold;
+        let output = if 2.0 * over <= -knee {
+            level
+        } else if 2.0 * over.abs() <= knee {
+            level + (1.0 / ratio - 1.0) * (over + knee / 2.0).powi(2) / (2.0 * knee)
+        } else {
+            threshold + over / ratio
+        };
+        level - output
+    }
+}
+
+/// Feed-forward compressor. The detector follows the peak level of the track's own input, or of
+/// another track when the sidechain listens to one, so e.g. a kick drum can duck the bass.
+pub struct Compressor {
+    params: Arc<CompressorParams>,
+    /// Current gain reduction in dB, which follows the target with the attack and release times
+    reduction: f64,
+    gain_reduction: Arc<AtomicF64>,
+    sample_rate: f64,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            params: Arc::new(CompressorParams::new(sample_rate)),
+            reduction: 0.0,
+            gain_reduction: Arc::new(AtomicF64::new(0.0)),
+            sample_rate,
+        }
+    }
+
+    /// Returns the coefficient of a one-pole filter that settles in `ms` milliseconds
+    fn coefficient(&self, ms: f64) -> f64 {
+        (-1.0 / (ms / 1000.0 * self.sample_rate)).exp()
+    }
+}
+
+impl Plugin for Compressor {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    fn gain_reduction(&self) -> Option<Arc<AtomicF64>> {
+        Some(self.gain_reduction.clone())
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        let attack = self.coefficient(self.params.attack.target());
+        let release = self.coefficient(self.params.release.target());
+        for mut frame in ctx.buffers() {
+            let input = *frame.input;
+            let detected = frame.sidechain.copied().unwrap_or(input);
+            let peak = f32::max(detected.channel(0).abs(), detected.channel(1).abs()) as f64;
+            let level = (20.0 * peak.log10()).max(MIN_LEVEL_DB);
+
+            let target = self.params.gain_reduction(level);
+            let coefficient = if target > self.reduction {
+                attack
+            } else {
+                release
+            };
+            self.reduction = target + coefficient * (self.reduction - target);
+
+            let gain = params::db_to_amp(-self.reduction) * self.params.makeup.value();
+            frame.write(input * gain as f32);
+        }
+        self.gain_reduction.store(self.reduction, Ordering::Relaxed);
+
+        ProcessStatus::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{Buffer, Stereo};
+
+    const SAMPLE_RATE: f64 = 8000.0;
+
+    /// Processes a constant input with an optional constant sidechain, and returns the last frame
+    /// of the output
+    fn process(compressor: &mut Compressor, input: f32, sidechain: Option<f32>) -> f32 {
+        let len = 8000;
+        let mut buffers: Vec<Buffer> = vec![
+            vec![Stereo::new([input, input]); len],
+            vec![Stereo::ZERO; len],
+            vec![Stereo::new([sidechain.unwrap_or(0.0); 2]); len],
+        ];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        ctx.sidechain_index = sidechain.map(|_| 2);
+        compressor.process(&mut ctx);
+        buffers[1][len - 1].channel(0)
+    }
+
+    fn db(amp: f32) -> f64 {
+        20.0 * (amp as f64).log10()
+    }
+
+    #[test]
+    fn compress_above_threshold() {
+        let mut compressor = Compressor::new(SAMPLE_RATE);
+        let params = compressor.params.clone();
+        params.threshold.set(-20.0);
+        params.ratio.set(4.0);
+        params.knee.set(0.0);
+
+        // Below the threshold nothing changes
+        let output = process(&mut compressor, 0.05, None);
+        assert!((output - 0.05).abs() < 1e-4);
+        assert_eq!(0.0, compressor.gain_reduction.load(Ordering::Relaxed));
+
+        // 20dB over the threshold comes out 5dB over it
+        let output = process(&mut compressor, 1.0, None);
+        assert!((db(output) - -15.0).abs() < 0.01);
+        let reduction = compressor.gain_reduction.load(Ordering::Relaxed);
+        assert!((reduction - 15.0).abs() < 0.01);
+
+        params.makeup.set(6.0);
+        let output = process(&mut compressor, 1.0, None);
+        assert!((db(output) - -9.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn soft_knee() {
+        let params = CompressorParams::new(SAMPLE_RATE);
+        params.threshold.set(-20.0);
+        params.ratio.set(4.0);
+        params.knee.set(10.0);
+        assert_eq!(0.0, params.gain_reduction(-26.0));
+        // At the threshold it already compresses a little, and fully at the end of the knee
+        let reduction = params.gain_reduction(-20.0);
+        assert!(reduction > 0.5 && reduction < 1.0);
+        assert!((params.gain_reduction(-15.0) - 3.75).abs() < 1e-9);
+        assert!((params.gain_reduction(0.0) - 15.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sidechain() {
+        let mut compressor = Compressor::new(SAMPLE_RATE);
+        compressor.params.threshold.set(-20.0);
+        compressor.params.ratio.set(10.0);
+        compressor.params.knee.set(0.0);
+
+        // A quiet input is turned down while the sidechain is loud
+        let output = process(&mut compressor, 0.05, Some(1.0));
+        assert!(output < 0.01);
+        assert!(compressor.gain_reduction.load(Ordering::Relaxed) > 10.0);
+
+        // And comes back when it's quiet
+        let output = process(&mut compressor, 0.05, Some(0.0));
+        assert!((output - 0.05).abs() < 1e-4);
+    }
+}
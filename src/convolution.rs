@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+
+use crate::audio::Stereo;
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, Param, ParamInfo, Params, Smoothing};
+use crate::resample::Quality;
+use crate::sampler::{self, Sound};
+use param_derive::Params;
+
+/// Frames per partition of the impulse response. The wet signal is delayed by this much.
+const BLOCK_SIZE: usize = 256;
+/// Longest impulse response in seconds
+const MAX_IR_LEN: f64 = 10.0;
+
+#[derive(Params)]
+pub struct ConvolutionParams {
+    gain: Param,
+    mix: Param,
+}
+
+impl ConvolutionParams {
+    fn new(sample_rate: f64) -> Self {
+        Self {
+            gain: Param::new(
+                0.0,
+                ParamInfo::new("Gain", -24, 24)
+                    .with_steps([0.5, 3.0])
+                    .with_formatter(|v| format!("{:.1}dB", v))
+                    .with_map(params::db_to_amp)
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            mix: Param::new(
+                0.25,
+                ParamInfo::new("Mix", 0, 1).with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+        }
+    }
+}
+
+/// Convolves one channel with an impulse response, which is split into partitions of
+/// [`BLOCK_SIZE`] frames that are each convolved with the input in the frequency domain.
+struct Convolver {
+    fft: Arc<dyn RealToComplex<f32>>,
+    ifft: Arc<dyn ComplexToReal<f32>>,
+    /// Spectra of the partitions of the impulse response
+    partitions: Vec<Vec<Complex<f32>>>,
+    /// Spectra of the most recent input blocks, as many as there are partitions. The newest is
+    /// at `newest`.
+    history: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    /// The previous and the current input block
+    window: Vec<f32>,
+    time: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    output: Vec<f32>,
+}
+
+impl Convolver {
+    fn new(ir: &[f32], planner: &mut RealFftPlanner<f32>) -> Self {
+        let fft = planner.plan_fft_forward(2 * BLOCK_SIZE);
+        let ifft = planner.plan_fft_inverse(2 * BLOCK_SIZE);
+        let mut scratch = fft.make_scratch_vec();
+        if ifft.get_scratch_len() > scratch.len() {
+            scratch = ifft.make_scratch_vec();
+        }
+
+        let mut time = fft.make_input_vec();
+        let partitions: Vec<_> = ir
+            .chunks(BLOCK_SIZE)
+            .map(|chunk| {
+                time.fill(0.0);
+                time[..chunk.len()].copy_from_slice(chunk);
+                let mut spectrum = fft.make_output_vec();
+                fft.process_with_scratch(&mut time, &mut spectrum, &mut scratch)
+                    .expect("buffers should have the length of the fft");
+                spectrum
+            })
+            .collect();
+
+        Self {
+            history: vec![fft.make_output_vec(); partitions.len()],
+            newest: 0,
+            partitions,
+            window: vec![0.0; 2 * BLOCK_SIZE],
+            time,
+            spectrum: fft.make_output_vec(),
+            scratch,
+            output: vec![0.0; BLOCK_SIZE],
+            fft,
+            ifft,
+        }
+    }
+
+    /// Convolves the next block of input, the result is in `output`
+    fn process(&mut self, input: &[f32]) {
+        self.window.copy_within(BLOCK_SIZE.., 0);
+        self.window[BLOCK_SIZE..].copy_from_slice(input);
+
+        let len = self.history.len();
+        self.newest = (self.newest + 1) % len;
+        self.time.copy_from_slice(&self.window);
+        self.fft
+            .process_with_scratch(
+                &mut self.time,
+                &mut self.history[self.newest],
+                &mut self.scratch,
+            )
+            .expect("buffers should have the length of the fft");
+
+        // Each partition of the impulse response is applied to the input block that's as many
+        // blocks old as the partition is from the start
+        self.spectrum.fill(Complex::ZERO);
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let block = &self.history[(self.newest + len - i) % len];
+            for (out, (x, h)) in self.spectrum.iter_mut().zip(block.iter().zip(partition)) {
+                *out += x * h;
+            }
+        }
+        // The spectrum is of a real signal, so these have no imaginary part apart from rounding
+        let last = self.spectrum.len() - 1;
+        self.spectrum[0].im = 0.0;
+        self.spectrum[last].im = 0.0;
+        self.ifft
+            .process_with_scratch(&mut self.spectrum, &mut self.time, &mut self.scratch)
+            .expect("buffers should have the length of the fft");
+
+        // The first half wrapped around in the circular convolution
+        let scale = 1.0 / (2 * BLOCK_SIZE) as f32;
+        for (out, sample) in self.output.iter_mut().zip(&self.time[BLOCK_SIZE..]) {
+            *out = sample * scale;
+        }
+    }
+}
+
+/// Convolution reverb that applies an impulse response loaded from a file. The impulse response
+/// is prepared when the effect is created on the app thread, so loading a new one creates a new
+/// effect. Without an impulse response only the dry signal comes through.
+pub struct Convolution {
+    params: Arc<ConvolutionParams>,
+    /// The impulse response as it was loaded, to prepare it again for a new sample rate
+    sound: Option<Sound>,
+    /// A convolver for the left and right channel, or none without an impulse response
+    convolvers: Vec<Convolver>,
+    /// Input that's collected until there's a full block to convolve
+    input: [Vec<f32>; 2],
+    pos: usize,
+    /// Length of the impulse response in frames
+    ir_len: usize,
+    /// Frames in a row without input
+    quiet_frames: usize,
+}
+
+impl Convolution {
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_params(
+            Arc::new(ConvolutionParams::new(sample_rate)),
+            None,
+            sample_rate,
+        )
+    }
+
+    /// Loads an impulse response with one or two channels from an audio file
+    pub fn load(path: &Utf8PathBuf, sample_rate: f64) -> Result<Self> {
+        let sound = sampler::load_file(path)?;
+        let seconds = sound.frames().len() as f64 / sound.sample_rate() as f64;
+        if seconds > MAX_IR_LEN {
+            return Err(anyhow!(
+                "impulse response {path} is longer than {MAX_IR_LEN} seconds"
+            ));
+        }
+        let params = Arc::new(ConvolutionParams::new(sample_rate));
+        Ok(Self::with_params(params, Some(sound), sample_rate))
+    }
+
+    fn with_params(params: Arc<ConvolutionParams>, sound: Option<Sound>, sample_rate: f64) -> Self {
+        let mut convolvers = Vec::new();
+        let mut ir_len = 0;
+        if let Some(sound) = sound.as_ref().filter(|s| !s.frames().is_empty()) {
+            let sound = sound.resample(Quality::High, sample_rate);
+            let frames = sound.frames();
+            ir_len = frames.len();
+            // Normalized to unit energy, so impulse responses of any length are about as loud
+            let energy: f32 = frames
+                .iter()
+                .map(|f| f.channel(0).powi(2) + f.channel(1).powi(2))
+                .sum();
+            let gain = if energy > 0.0 {
+                (2.0 / energy).sqrt()
+            } else {
+                0.0
+            };
+            let mut planner = RealFftPlanner::new();
+            for ch in 0..2 {
+                let ir: Vec<f32> = frames.iter().map(|f| gain * f.channel(ch)).collect();
+                convolvers.push(Convolver::new(&ir, &mut planner));
+            }
+        }
+        Self {
+            params,
+            sound,
+            convolvers,
+            input: [vec![0.0; BLOCK_SIZE], vec![0.0; BLOCK_SIZE]],
+            pos: 0,
+            ir_len,
+            quiet_frames: 0,
+        }
+    }
+}
+
+impl Plugin for Convolution {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    /// Prepares the impulse response again at the new sample rate, which clears the tail
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        *self = Self::with_params(self.params.clone(), self.sound.take(), sample_rate);
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        for mut frame in ctx.buffers() {
+            let gain = self.params.gain.value() as f32;
+            let mix = self.params.mix.value() as f32;
+            let input = *frame.input;
+
+            let mut wet = Stereo::ZERO;
+            if !self.convolvers.is_empty() {
+                self.input[0][self.pos] = input.channel(0);
+                self.input[1][self.pos] = input.channel(1);
+                wet = Stereo::new([
+                    self.convolvers[0].output[self.pos],
+                    self.convolvers[1].output[self.pos],
+                ]);
+                self.pos += 1;
+                if self.pos == BLOCK_SIZE {
+                    for (convolver, input) in self.convolvers.iter_mut().zip(&self.input) {
+                        convolver.process(input);
+                    }
+                    self.pos = 0;
+                }
+            }
+
+            if input == Stereo::ZERO {
+                self.quiet_frames += 1;
+            } else {
+                self.quiet_frames = 0;
+            }
+            frame.write(input * (1.0 - mix) + wet * gain * mix);
+        }
+
+        // Input takes up to a block to go in and another one to come out again
+        if self.quiet_frames >= self.ir_len + 2 * BLOCK_SIZE {
+            ProcessStatus::Idle
+        } else {
+            ProcessStatus::Continue
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    fn process(convolution: &mut Convolution, input: Buffer) -> (Buffer, ProcessStatus) {
+        let len = input.len();
+        let mut buffers = vec![input, vec![Stereo::ZERO; len]];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        let status = convolution.process(&mut ctx);
+        (buffers.pop().unwrap(), status)
+    }
+
+    #[test]
+    fn convolve_impulse_response() {
+        let sample_rate = 8000.0;
+        let mut ir = vec![Stereo::ZERO; 1000];
+        ir[0] = Stereo::new([1.0, 0.0]);
+        ir[700] = Stereo::new([0.5, 0.5]);
+        let sound = Sound::new(ir, 0, sample_rate as usize);
+        let params = Arc::new(ConvolutionParams::new(sample_rate));
+        params.mix.set(1.0);
+        let mut convolution = Convolution::with_params(params, Some(sound), sample_rate);
+
+        let mut input = vec![Stereo::ZERO; 1200];
+        input[10] = Stereo::new([1.0, 1.0]);
+        // Process in odd sizes, which don't line up with the blocks
+        let mut output = Vec::new();
+        for chunk in input.chunks(300) {
+            let (out, status) = process(&mut convolution, chunk.to_vec());
+            assert!(matches!(status, ProcessStatus::Continue));
+            output.extend(out);
+        }
+
+        let peaks: Vec<(usize, Stereo)> = output
+            .iter()
+            .enumerate()
+            .skip(20)
+            .filter(|(_, frame)| frame.channel(0).abs() > 0.01 || frame.channel(1).abs() > 0.01)
+            .map(|(i, frame)| (i, *frame))
+            .collect();
+        let first = 10 + BLOCK_SIZE;
+        assert_eq!(
+            vec![first, first + 700],
+            peaks.iter().map(|p| p.0).collect::<Vec<_>>()
+        );
+        let (left, echo) = (peaks[0].1, peaks[1].1);
+        assert!(left.channel(1).abs() < 1e-4);
+        assert!((echo.channel(0) / left.channel(0) - 0.5).abs() < 1e-3);
+        assert!((echo.channel(1) / left.channel(0) - 0.5).abs() < 1e-3);
+
+        // The tail ends after the impulse response
+        let (_, status) = process(&mut convolution, vec![Stereo::ZERO; 1000]);
+        assert!(matches!(status, ProcessStatus::Idle));
+    }
+}
@@ -2,57 +2,257 @@ use std::sync::Arc;
 
 use crate::audio::Stereo;
 use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
-use crate::params::{self, Params};
+use crate::params::{self, format_millis, Param, ParamInfo, Params, Smoothing};
 use param_derive::Params;
 
+/// Longest delay in seconds, including the stereo offset. Synced delays that would be longer are
+/// shortened to this.
+const MAX_DELAY_TIME: f64 = 4.0;
+/// Time in milliseconds over which the delay glides to a new delay time
+const TIME_SMOOTHING: f64 = 100.0;
+
+/// Note divisions that the delay time can be synced to, with their length in beats
+const DIVISIONS: &[(&str, f64)] = &[
+    ("Off", 0.0),
+    ("1/1", 4.0),
+    ("1/2", 2.0),
+    ("1/2.", 3.0),
+    ("1/4", 1.0),
+    ("1/4.", 1.5),
+    ("1/4T", 2.0 / 3.0),
+    ("1/8", 0.5),
+    ("1/8.", 0.75),
+    ("1/8T", 1.0 / 3.0),
+    ("1/16", 0.25),
+    ("1/16.", 0.375),
+    ("1/16T", 1.0 / 6.0),
+    ("1/32", 0.125),
+];
+
+#[derive(Params)]
+pub struct DelayParams {
+    time: Param,
+    sync: Param,
+    feedback: Param,
+    mix: Param,
+    ping_pong: Param,
+    offset: Param,
+    damping: Param,
+}
+
+impl DelayParams {
+    fn new(sample_rate: f64) -> Self {
+        Self {
+            time: Param::new(
+                250.0,
+                ParamInfo::new("Time", 1, 2000)
+                    .with_steps([1, 10])
+                    .with_formatter(format_millis),
+            ),
+            sync: Param::new(
+                0.0,
+                ParamInfo::new("Sync", 0, DIVISIONS.len() as u32 - 1)
+                    .with_steps([1, 1])
+                    .with_formatter(|v| String::from(DIVISIONS[v as usize].0)),
+            ),
+            feedback: Param::new(
+                0.4,
+                ParamInfo::new("Feedback", 0.0, 0.99)
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            mix: Param::new(
+                0.3,
+                ParamInfo::new("Mix", 0, 1).with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            ping_pong: Param::new(0.0, ParamInfo::bool("Ping-Pong", 1.0)),
+            offset: Param::new(
+                0.0,
+                ParamInfo::new("Stereo Offset", -100, 100)
+                    .with_steps([1, 10])
+                    .with_formatter(format_millis),
+            ),
+            damping: Param::new(
+                0.2,
+                ParamInfo::new("Damping", 0.0, 0.95)
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+        }
+    }
+
+    /// Returns the delay time of the left and right channel in seconds
+    fn times(&self, bpm: u16) -> (f64, f64) {
+        let beats = DIVISIONS[self.sync.value() as usize].1;
+        let time = if beats > 0.0 {
+            beats * 60.0 / bpm as f64
+        } else {
+            self.time.value() / 1000.0
+        };
+        let offset = self.offset.value() / 1000.0;
+        let clamp = |t: f64| t.clamp(0.001, MAX_DELAY_TIME);
+        (clamp(time), clamp(time + offset))
+    }
+}
+
+/// Stereo delay with feedback through a low-pass filter. The delay line is allocated for the
+/// longest delay time up front, so changing the time only moves the read position, which glides
+/// to the new time instead of jumping.
 pub struct Delay {
+    params: Arc<DelayParams>,
     buffer: Vec<Stereo>,
     write_pos: usize,
-    delay_samples: usize,
+    /// Current delay of the left and right channel in samples
+    delay: [f64; 2],
+    /// Coefficient of the delay time smoothing, per sample
+    smoothing: f64,
+    /// State of the low-pass filters in the feedback path
+    damped: Stereo,
+    sample_rate: f64,
 }
 
-#[derive(Params)]
-struct DelayParams {}
-
 impl Delay {
-    pub fn new(delay_samples: usize) -> Self {
-        Delay {
-            buffer: vec![Stereo::ZERO; delay_samples],
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_params(Arc::new(DelayParams::new(sample_rate)), sample_rate)
+    }
+
+    fn with_params(params: Arc<DelayParams>, sample_rate: f64) -> Self {
+        let len = (MAX_DELAY_TIME * sample_rate) as usize + 2;
+        let (left, right) = params.times(120);
+        let smoothing_samples = TIME_SMOOTHING / 1000.0 * sample_rate;
+        Self {
+            params,
+            buffer: vec![Stereo::ZERO; len],
             write_pos: 0,
-            delay_samples,
+            delay: [left * sample_rate, right * sample_rate],
+            smoothing: 0.001f64.powf(1.0 / smoothing_samples),
+            damped: Stereo::ZERO,
+            sample_rate,
         }
     }
+
+    /// Reads a channel `delay` samples behind the write position, interpolating between samples
+    fn read(&self, ch: usize, delay: f64) -> f32 {
+        let len = self.buffer.len();
+        let pos = self.write_pos as f64 + len as f64 - delay;
+        let idx = pos as usize;
+        let frac = (pos - idx as f64) as f32;
+        let a = self.buffer[idx % len].channel(ch);
+        let b = self.buffer[(idx + 1) % len].channel(ch);
+        a + (b - a) * frac
+    }
 }
 
 impl Plugin for Delay {
     fn send_event(&mut self, _event: PluginEvent) {}
 
     fn params(&self) -> Arc<dyn Params> {
-        Arc::new(DelayParams {})
+        self.params.clone()
     }
 
-    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
-        const FEEDBACK: f32 = 0.5;
-        const DRY_MIX: f32 = 0.8;
-        const WET_MIX: f32 = 0.8;
+    /// Clears the delay line, which is reallocated for the new sample rate
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        *self = Self::with_params(self.params.clone(), sample_rate);
+    }
 
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        let bpm = ctx.bpm;
         for mut frame in ctx.buffers() {
-            let read_pos = {
-                let mut pos = self.write_pos as isize - self.delay_samples as isize;
-                if pos < 0 {
-                    pos += self.delay_samples as isize;
-                }
-                pos as usize
-            };
+            let params = &self.params;
+            let (left, right) = params.times(bpm);
+            let targets = [left * self.sample_rate, right * self.sample_rate];
+            for (delay, target) in self.delay.iter_mut().zip(targets) {
+                *delay = self.smoothing * *delay + (1.0 - self.smoothing) * target;
+            }
+            let feedback = params.feedback.value() as f32;
+            let mix = params.mix.value() as f32;
+            let damping = params.damping.value() as f32;
 
-            let delayed_sample = self.buffer[read_pos];
-            let output = *frame.input * DRY_MIX + delayed_sample * WET_MIX;
-            frame.write(output);
+            let delayed = Stereo::new([self.read(0, self.delay[0]), self.read(1, self.delay[1])]);
+            self.damped = delayed * (1.0 - damping) + self.damped * damping;
 
-            self.buffer[self.write_pos] = *frame.input + delayed_sample * FEEDBACK;
-            self.write_pos = (self.write_pos + 1) % self.delay_samples;
+            let input = *frame.input;
+            let write = if params.ping_pong.as_bool() {
+                // The input goes into the left channel and each repeat switches sides
+                let mono = 0.5 * (input.channel(0) + input.channel(1));
+                Stereo::new([
+                    mono + feedback * self.damped.channel(1),
+                    feedback * self.damped.channel(0),
+                ])
+            } else {
+                input + self.damped * feedback
+            };
+            self.buffer[self.write_pos] = write;
+            self.write_pos = (self.write_pos + 1) % self.buffer.len();
+
+            frame.write(input * (1.0 - mix) + delayed * mix);
         }
 
         ProcessStatus::Continue
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    fn process(delay: &mut Delay, input: &[Stereo], bpm: u16) -> Vec<Stereo> {
+        let mut buffers: Vec<Buffer> = vec![input.to_vec(), vec![Stereo::ZERO; input.len()]];
+        let mut ctx = ProcessContext::new(&mut buffers, input.len());
+        ctx.buffer_indices = Some((0, 1));
+        ctx.bpm = bpm;
+        delay.process(&mut ctx);
+        buffers.pop().unwrap()
+    }
+
+    fn impulse(len: usize) -> Vec<Stereo> {
+        let mut input = vec![Stereo::ZERO; len];
+        input[0] = Stereo::new([1.0, 1.0]);
+        input
+    }
+
+    fn first_echo(output: &[Stereo], ch: usize) -> Option<usize> {
+        output
+            .iter()
+            .skip(1)
+            .position(|frame| frame.channel(ch).abs() > 0.1)
+            .map(|i| i + 1)
+    }
+
+    #[test]
+    fn delay_time() {
+        let mut delay = Delay::new(1000.0);
+        let params = delay.params.clone();
+        params.time.set(100.0);
+        params.damping.set(0.0);
+        params.mix.set(1.0);
+        // Let the delay time glide to its target
+        process(&mut delay, &[Stereo::ZERO; 2000], 120);
+
+        let output = process(&mut delay, &impulse(500), 120);
+        assert_eq!(Some(100), first_echo(&output, 0));
+        assert_eq!(Some(100), first_echo(&output, 1));
+
+        // An eighth note at 120 bpm
+        params.sync.set(7.0);
+        process(&mut delay, &[Stereo::ZERO; 4000], 120);
+        let output = process(&mut delay, &impulse(500), 120);
+        assert_eq!(Some(250), first_echo(&output, 0));
+    }
+
+    #[test]
+    fn ping_pong() {
+        let mut delay = Delay::new(1000.0);
+        let params = delay.params.clone();
+        params.time.set(100.0);
+        params.damping.set(0.0);
+        params.feedback.set(0.5);
+        params.mix.set(1.0);
+        params.ping_pong.set(1.0);
+        process(&mut delay, &[Stereo::ZERO; 2000], 120);
+
+        let output = process(&mut delay, &impulse(500), 120);
+        assert_eq!(Some(100), first_echo(&output, 0));
+        assert_eq!(Some(200), first_echo(&output, 1));
+    }
+}
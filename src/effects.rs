@@ -0,0 +1,162 @@
+//! Registry of the built-in effects that can be added to tracks.
+
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+
+use crate::compressor::Compressor;
+use crate::convolution::Convolution;
+use crate::delay::Delay;
+use crate::engine::Plugin;
+use crate::equalizer::Equalizer;
+use crate::filter::Filter;
+use crate::limiter::Limiter;
+use crate::reverb::Reverb;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Delay,
+    Dynamics,
+    Eq,
+    Filter,
+    Reverb,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Delay => "Delay",
+            Self::Dynamics => "Dynamics",
+            Self::Eq => "EQ",
+            Self::Filter => "Filter",
+            Self::Reverb => "Reverb",
+        };
+        write!(f, "{name}")
+    }
+}
+
+pub struct Effect {
+    /// Identifies the effect in projects and commands, so it must not change
+    pub id: &'static str,
+    pub name: &'static str,
+    pub category: Category,
+    factory: fn(f64) -> Box<dyn Plugin + Send>,
+    /// Creates the effect from a file, for effects that load one
+    loader: Option<Loader>,
+    /// Whether the effect can listen to another track through its sidechain
+    pub sidechain: bool,
+}
+
+type Loader = fn(&Utf8PathBuf, f64) -> Result<Box<dyn Plugin + Send>>;
+
+impl Effect {
+    /// Creates the effect for the engine's sample rate
+    pub fn create(&self, sample_rate: f64) -> Box<dyn Plugin + Send> {
+        (self.factory)(sample_rate)
+    }
+
+    /// Creates the effect from a file, like an impulse response
+    pub fn load(&self, path: &Utf8PathBuf, sample_rate: f64) -> Result<Box<dyn Plugin + Send>> {
+        match self.loader {
+            Some(load) => load(path, sample_rate),
+            None => Err(anyhow!("{} doesn't load files", self.name)),
+        }
+    }
+
+    pub fn loads_files(&self) -> bool {
+        self.loader.is_some()
+    }
+}
+
+/// All built-in effects, sorted by category
+pub static EFFECTS: &[Effect] = &[
+    Effect {
+        id: "delay",
+        name: "Delay",
+        category: Category::Delay,
+        factory: |sample_rate| Box::new(Delay::new(sample_rate)),
+        loader: None,
+        sidechain: false,
+    },
+    Effect {
+        id: "compressor",
+        name: "Compressor",
+        category: Category::Dynamics,
+        factory: |sample_rate| Box::new(Compressor::new(sample_rate)),
+        loader: None,
+        sidechain: true,
+    },
+    Effect {
+        id: "limiter",
+        name: "Limiter",
+        category: Category::Dynamics,
+        factory: |sample_rate| Box::new(Limiter::new(sample_rate)),
+        loader: None,
+        sidechain: false,
+    },
+    Effect {
+        id: "equalizer",
+        name: "Parametric EQ",
+        category: Category::Eq,
+        factory: |sample_rate| Box::new(Equalizer::new(sample_rate)),
+        loader: None,
+        sidechain: false,
+    },
+    Effect {
+        id: "filter",
+        name: "Filter",
+        category: Category::Filter,
+        factory: |sample_rate| Box::new(Filter::new(sample_rate)),
+        loader: None,
+        sidechain: false,
+    },
+    Effect {
+        id: "reverb",
+        name: "Reverb",
+        category: Category::Reverb,
+        factory: |sample_rate| Box::new(Reverb::new(sample_rate)),
+        loader: None,
+        sidechain: false,
+    },
+    Effect {
+        id: "convolution",
+        name: "Convolution Reverb",
+        category: Category::Reverb,
+        factory: |sample_rate| Box::new(Convolution::new(sample_rate)),
+        loader: Some(|path, sample_rate| Ok(Box::new(Convolution::load(path, sample_rate)?))),
+        sidechain: false,
+    },
+];
+
+/// Finds an effect by its id, or by its name ignoring case
+pub fn find(name: &str) -> Option<&'static Effect> {
+    EFFECTS
+        .iter()
+        .find(|effect| effect.id == name || effect.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_effects() {
+        assert_eq!("delay", find("delay").unwrap().id);
+        assert_eq!("delay", find("DELAY").unwrap().id);
+        assert!(find("flanger").is_none());
+
+        for (i, effect) in EFFECTS.iter().enumerate() {
+            assert!(
+                EFFECTS[..i].iter().all(|e| e.id != effect.id),
+                "duplicate effect id {}",
+                effect.id
+            );
+        }
+    }
+
+    #[test]
+    fn effects_sorted_by_category() {
+        assert!(EFFECTS.is_sorted_by_key(|effect| effect.category));
+    }
+}
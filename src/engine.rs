@@ -1,4 +1,5 @@
 use std::iter;
+use std::mem;
 use std::ops::Range;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -8,27 +9,34 @@ use get_many_mut::GetManyMutExt;
 use ringbuf::{Consumer, Producer};
 use triple_buffer::Input;
 
-use crate::app::{AppCommand, AppState, EngineState};
+use crate::app::{AppCommand, AppState, EngineState, NodeEntry};
 use crate::audio::{self, Buffer, Rms, Stereo};
 use crate::params::{self, Param, ParamInfo, Params};
 use crate::sampler::{Sampler, Sound};
-use crate::SAMPLE_RATE;
+use crate::DEFAULT_SAMPLE_RATE;
 use param_derive::Params;
 
-pub const MAX_INSTRUMENTS: usize = 16;
+// Instruments are numbered with a single byte in patterns
+pub const MAX_INSTRUMENTS: usize = u8::MAX as usize;
 pub const TICKS_PER_LINE: usize = 12;
-pub const MAX_TRACKS: usize = MAX_INSTRUMENTS + 1; // add 1 for master
-pub const MAX_NODES: usize = MAX_TRACKS + MAX_INSTRUMENTS;
-pub const MAX_BUFFERS: usize = MAX_TRACKS + 2; // add 1 for main output and 1 for scratch space
-pub const MAIN_OUTPUT: usize = MAX_BUFFERS - 1;
-pub const SCRATCH_BUFFER: usize = MAX_BUFFERS - 2;
 pub const MASTER_TRACK: usize = 0;
-const RMS_WINDOW_SIZE: usize = SAMPLE_RATE as usize / 10 * 3;
+// Buffers are indexed like nodes. These indices are reserved for buffers without a node.
+pub const MAIN_OUTPUT: usize = 1;
+pub const SCRATCH_BUFFER: usize = 2;
+/// Number of nodes the engine starts with. The pool grows when the app needs more.
+pub const INITIAL_POOL_SIZE: usize = 64;
+/// RMS window in seconds
+const RMS_WINDOW: f64 = 0.3;
 const SUBFRAMES_PER_SEC: usize = 282240000; // LCM of common sample rates
 
 pub enum EngineCommand {
+    /// Replaces the node pool with a larger one
+    Grow(Box<Pool>),
     CreateNode(usize, Box<dyn Plugin + Send>),
+    /// Fades a node out, after which it's returned to the app thread
     DeleteNode(usize),
+    /// Crossfades between the output of a node and its input
+    SetBypass(usize, bool),
     PreviewSound(Arc<Sound>),
 }
 
@@ -45,12 +53,18 @@ pub struct Engine {
 
     consumer: Consumer<EngineCommand>,
     producer: Producer<AppCommand>,
+    /// Command that waits for room to return memory to the app thread
+    pending: Option<EngineCommand>,
 
     /// Number of subframes until the next tick
     subframe_countdown: usize,
     total_ticks: u64,
 
     preview: Sampler,
+    sample_rate: f64,
+
+    /// Nodes whose output is captured separately on every call to `process`
+    taps: Vec<(usize, Buffer)>,
 }
 
 impl Engine {
@@ -60,17 +74,12 @@ impl Engine {
         consumer: Consumer<EngineCommand>,
         producer: Producer<AppCommand>,
     ) -> Engine {
-        let mut nodes = Vec::with_capacity(MAX_NODES);
-        for _ in 0..MAX_NODES {
-            nodes.push(Node::new());
-        }
-        let mut buffers = Vec::with_capacity(MAX_BUFFERS);
-        for _ in 0..MAX_BUFFERS {
-            buffers.push(audio::buffer());
-        }
-
-        let preview = Sampler::new(Sound::silence());
-        let last_events = vec![None; MAX_TRACKS];
+        let Pool {
+            nodes,
+            buffers,
+            last_events,
+        } = Pool::new(INITIAL_POOL_SIZE, DEFAULT_SAMPLE_RATE);
+        let preview = Sampler::new(Sound::silence(), DEFAULT_SAMPLE_RATE);
 
         Self {
             nodes,
@@ -78,25 +87,40 @@ impl Engine {
             state_buf,
             consumer,
             producer,
+            pending: None,
             subframe_countdown: 0,
             total_ticks: 0,
             preview,
+            sample_rate: DEFAULT_SAMPLE_RATE,
             buffers,
             last_events,
+            taps: Vec::new(),
+        }
+    }
+
+    /// Runs the engine at a new sample rate. Nodes are created at the rate the app thread knows
+    /// about, so pending commands are applied first to update their nodes as well. This allocates
+    /// when plugins resize their buffers, so it must not be called while audio is being processed.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.run_commands();
+        self.sample_rate = sample_rate;
+        for node in self.nodes.iter_mut() {
+            node.mix.set_sample_rate(sample_rate);
+            if let Some(plugin) = &mut node.inner {
+                plugin.set_sample_rate(sample_rate);
+            }
         }
+        self.preview.set_sample_rate(sample_rate);
     }
 
     fn tick(&mut self, state: &AppState, frames: usize) {
-        let subframes_per_sample = SUBFRAMES_PER_SEC / SAMPLE_RATE as usize;
+        let subframes_per_sample = SUBFRAMES_PER_SEC / self.sample_rate as usize;
         let mut subframes = frames * subframes_per_sample;
         let mut offset = 0;
         while subframes > 0 {
             if self.subframe_countdown == 0 {
                 self.dispatch_events(state, offset / subframes_per_sample);
-                let subframes_per_tick = (SUBFRAMES_PER_SEC * 60)
-                    / (TICKS_PER_LINE as u16 * state.lines_per_beat * state.bpm) as usize;
-
-                self.subframe_countdown = subframes_per_tick;
+                self.subframe_countdown = subframes_per_tick(state.bpm, state.lines_per_beat);
                 self.total_ticks += 1;
             }
             offset = usize::min(subframes, self.subframe_countdown);
@@ -105,23 +129,117 @@ impl Engine {
         }
     }
 
+    /// Captures the output of a node so it can be read with `tapped` after every call to
+    /// `process`. The node still writes to its regular output as well. This allocates, so it's
+    /// meant for offline rendering.
+    pub fn tap(&mut self, node_index: usize) {
+        if !self.taps.iter().any(|(idx, _)| *idx == node_index) {
+            self.taps.push((node_index, audio::buffer()));
+        }
+    }
+
+    /// Output written by a tapped node during the last call to `process`
+    pub fn tapped(&self, node_index: usize) -> Option<&[Stereo]> {
+        self.taps
+            .iter()
+            .find(|(idx, _)| *idx == node_index)
+            .map(|(_, buf)| &buf[..])
+    }
+
+    /// Moves the playback position to the start of a pattern in the song
+    pub fn seek(&mut self, pattern_idx: usize) {
+        self.state.current_pattern = pattern_idx;
+        self.state.current_tick = 0;
+        self.subframe_countdown = 0;
+    }
+
     pub fn process(&mut self, state: &AppState, buffer: &mut [Stereo]) {
         let frames = buffer.len();
-        self.run_commands(state);
+        self.run_commands();
+        self.drop_deleted_nodes();
         self.tick(state, frames);
 
+        for (_, buf) in self.taps.iter_mut() {
+            buf.fill(Stereo::ZERO);
+        }
+
         for entry in &state.node_order {
-            let node = &mut self.nodes[entry.node_index];
-            if node.is_idle() {
+            // The order can refer to nodes whose pool hasn't arrived yet
+            if !self.has_node(entry) {
                 continue;
             }
-            let Some(plugin) = &mut node.inner else {
-                continue;
+            // Effects report idle when their tail has decayed, and wake up when they get input
+            let has_input = entry.buffers.is_some_and(|(input, _)| {
+                self.buffers[input][..frames]
+                    .iter()
+                    .any(|frame| *frame != Stereo::ZERO)
+            });
+            let node = &mut self.nodes[entry.node_index];
+            let skip = (node.is_idle() && !has_input) || node.is_bypassed();
+            // A node with a send writes into the send buffer, which is then mixed into its
+            // output, so other nodes can still read what it wrote as their sidechain
+            let send = entry
+                .buffers
+                .zip(entry.send)
+                .map(|((_, output), send)| (output, send));
+            if let Some((output, send)) = send {
+                self.buffers.swap(output, send);
+            }
+            let plugin = match &mut node.inner {
+                Some(plugin) if !skip => Some(plugin),
+                _ => None,
             };
-            let mut ctx = ProcessContext::new(&mut self.buffers, frames);
-            ctx.mix = Some(&node.mix);
-            ctx.buffer_indices = entry.buffers;
-            node.status = Some(plugin.process(&mut ctx));
+            if let Some(plugin) = plugin {
+                let tap = entry.buffers.and_then(|(_, output)| {
+                    let idx = self.taps.iter().position(|(i, _)| *i == entry.node_index)?;
+                    Some((idx, output))
+                });
+
+                // A tapped node writes into its own empty buffer, which is mixed into the real
+                // output afterwards. Nodes add to their output, so this gives the same result as
+                // writing to it directly.
+                if let Some((idx, output)) = tap {
+                    mem::swap(&mut self.buffers[output], &mut self.taps[idx].1);
+                }
+                let mut ctx = ProcessContext::new(&mut self.buffers, frames);
+                ctx.mix = Some(&node.mix);
+                ctx.buffer_indices = entry.buffers;
+                ctx.sidechain_index = entry.sidechain;
+                ctx.bpm = state.bpm;
+                node.status = Some(plugin.process(&mut ctx));
+
+                if let Some((idx, output)) = tap {
+                    let tap = &mut self.taps[idx].1;
+                    mem::swap(&mut self.buffers[output], tap);
+                    for (out, frame) in self.buffers[output][..frames].iter_mut().zip(tap.iter()) {
+                        *out += *frame;
+                    }
+                }
+            } else if let Some((input, output)) = entry.buffers {
+                // Skipped nodes pass their input through, so the rest of an effect chain still
+                // gets it
+                let [input, output] =
+                    GetManyMutExt::get_many_mut(&mut self.buffers[..], [input, output])
+                        .expect("buffers should exist");
+                for (out, frame) in output[..frames].iter_mut().zip(&input[..frames]) {
+                    *out += *frame;
+                }
+            }
+
+            if let Some((output, send)) = send {
+                self.buffers.swap(output, send);
+                let [output, send] =
+                    GetManyMutExt::get_many_mut(&mut self.buffers[..], [output, send])
+                        .expect("buffers should exist");
+                for (out, frame) in output[..frames].iter_mut().zip(&send[..frames]) {
+                    *out += *frame;
+                }
+            }
+            // Nodes add to their output, so an effect chain that goes back and forth between two
+            // buffers needs the input cleared once it's consumed
+            if let Some((input, _)) = entry.buffers {
+                self.buffers[input][..frames].fill(Stereo::ZERO);
+            }
         }
         let mut ctx = ProcessContext::new(&mut self.buffers, frames);
         self.preview.process(&mut ctx);
@@ -133,15 +251,23 @@ impl Engine {
         }
 
         for buf in self.buffers.iter_mut() {
-            for frame in buf {
-                *frame = Stereo::ZERO;
-            }
+            buf[..frames].fill(Stereo::ZERO);
         }
 
         self.state_buf.input_buffer().clone_from(&self.state);
         self.state_buf.publish();
     }
 
+    /// Whether the node and the buffers of an entry exist in the current pool
+    fn has_node(&self, entry: &NodeEntry) -> bool {
+        let buffers = entry
+            .buffers
+            .iter()
+            .flat_map(|(input, output)| [input, output]);
+        let mut buffers = buffers.chain(&entry.sidechain).chain(&entry.send);
+        entry.node_index < self.nodes.len() && buffers.all(|idx| *idx < self.buffers.len())
+    }
+
     fn dispatch_events(&mut self, state: &AppState, offset: usize) {
         if !state.is_playing {
             return;
@@ -161,6 +287,9 @@ impl Engine {
             if event.offset == self.state.current_tick {
                 let node_idx = event.node_index;
                 let track_idx = event.track_index;
+                if node_idx >= self.nodes.len() || track_idx >= self.last_events.len() {
+                    continue;
+                }
 
                 if let Some((tick, node_idx)) = self.last_events[track_idx] {
                     if tick != self.total_ticks {
@@ -187,27 +316,60 @@ impl Engine {
         self.state.current_pattern = pattern_idx;
     }
 
-    fn run_commands(&mut self, _state: &AppState) {
-        while let Some(cmd) = self.consumer.pop() {
+    /// Returns deleted nodes to the app thread once they have faded out, so they can be
+    /// deallocated there.
+    fn drop_deleted_nodes(&mut self) {
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            // Try again in the next cycle when the app thread is busy. The last slot is kept for
+            // returning a pool, so growing doesn't have to wait for the app thread.
+            if self.producer.remaining() <= 1 {
+                return;
+            }
+            if node.inner.is_some() && node.deleted && node.is_quiet() {
+                let plugin = node.reset();
+                if self
+                    .producer
+                    .push(AppCommand::DropPlugin(i, plugin))
+                    .is_err()
+                {
+                    eprintln!("failed to return node to app thread");
+                }
+            }
+        }
+    }
+
+    fn run_commands(&mut self) {
+        while let Some(cmd) = self.pending.take().or_else(|| self.consumer.pop()) {
             match cmd {
+                EngineCommand::Grow(pool) if self.producer.is_full() => {
+                    // The old pool can't be dropped here, so growing waits until there's room to
+                    // return it. Later commands can refer to the new nodes, so they wait as well.
+                    self.pending = Some(EngineCommand::Grow(pool));
+                    return;
+                }
+                EngineCommand::Grow(mut pool) => {
+                    // Nodes and buffers are swapped into the new pool, which is preallocated, so
+                    // the old pool only holds unused ones when it's returned.
+                    for (old, new) in self.nodes.iter_mut().zip(pool.nodes.iter_mut()) {
+                        mem::swap(old, new);
+                    }
+                    for (old, new) in self.buffers.iter_mut().zip(pool.buffers.iter_mut()) {
+                        mem::swap(old, new);
+                    }
+                    pool.last_events[..self.last_events.len()].copy_from_slice(&self.last_events);
+                    mem::swap(&mut self.nodes, &mut pool.nodes);
+                    mem::swap(&mut self.buffers, &mut pool.buffers);
+                    mem::swap(&mut self.last_events, &mut pool.last_events);
+                    if self.producer.push(AppCommand::DropPool(pool)).is_err() {
+                        eprintln!("failed to return node pool to app thread");
+                    }
+                }
                 EngineCommand::CreateNode(node_idx, plugin) => {
                     let node = &mut self.nodes[node_idx];
                     assert!(node.inner.is_none());
                     node.inner = Some(plugin);
                 }
                 EngineCommand::DeleteNode(node_idx) => {
-                    for (i, node) in self.nodes.iter_mut().enumerate() {
-                        if node.inner.is_some() && node.deleted && node.is_quiet() {
-                            let plugin = node.reset();
-                            if self
-                                .producer
-                                .push(AppCommand::DropPlugin(i, plugin))
-                                .is_err()
-                            {
-                                eprintln!("failed to return node to app thread");
-                            }
-                        }
-                    }
                     let node = &mut self.nodes[node_idx];
                     for (track_idx, event) in self.last_events.iter_mut().enumerate() {
                         if let Some((_, idx)) = event {
@@ -219,6 +381,9 @@ impl Engine {
                     }
                     node.delete();
                 }
+                EngineCommand::SetBypass(node_idx, bypass) => {
+                    self.nodes[node_idx].set_bypass(bypass);
+                }
                 EngineCommand::PreviewSound(sound) => {
                     let velocity = 80; // TODO: handle this with gain instead?
                     self.preview
@@ -235,9 +400,25 @@ impl Engine {
     }
 }
 
+fn subframes_per_tick(bpm: u16, lines_per_beat: u16) -> usize {
+    (SUBFRAMES_PER_SEC * 60) / (TICKS_PER_LINE as u16 * lines_per_beat * bpm) as usize
+}
+
+/// Returns the number of frames it takes the engine to play `ticks` ticks at the given tempo
+pub fn frames_for_ticks(ticks: usize, bpm: u16, lines_per_beat: u16, sample_rate: f64) -> usize {
+    let subframes_per_sample = SUBFRAMES_PER_SEC / sample_rate as usize;
+    ticks * subframes_per_tick(bpm, lines_per_beat) / subframes_per_sample
+}
+
+/// Returns whether the engine can run at a sample rate. Ticks are timed in subframes, so the
+/// rate has to divide their rate evenly.
+pub fn supports_sample_rate(sample_rate: u32) -> bool {
+    SUBFRAMES_PER_SEC.is_multiple_of(sample_rate as usize)
+}
+
 impl Default for Track {
     fn default() -> Self {
-        Track::new()
+        Track::new(DEFAULT_SAMPLE_RATE)
     }
 }
 
@@ -255,40 +436,46 @@ pub struct TrackParams {
 }
 
 impl TrackParams {
-    fn new() -> Self {
+    fn new(sample_rate: f64) -> Self {
         Self {
             volume: Param::new(
                 -6.0,
                 ParamInfo::new("Volume", -60, 3)
                     .with_steps([0.25, 1.0])
-                    .with_smoothing(params::Smoothing::exp_default())
+                    .with_smoothing(params::Smoothing::exp_default(sample_rate))
                     .with_map(params::db_to_amp),
             ),
             mute: Param::new(
                 1.0,
-                ParamInfo::bool("Mute", 0.0).with_smoothing(params::Smoothing::exp_default()),
+                ParamInfo::bool("Mute", 0.0)
+                    .with_smoothing(params::Smoothing::exp_default(sample_rate)),
             ),
             mix: Param::new(
                 1.0,
-                ParamInfo::bool("Mix", 1.0).with_smoothing(params::Smoothing::exp_default()),
+                ParamInfo::bool("Mix", 1.0)
+                    .with_smoothing(params::Smoothing::exp_default(sample_rate)),
             ),
         }
     }
 }
 
 impl Track {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f64) -> Self {
         Self {
-            rms: Rms::new(RMS_WINDOW_SIZE),
+            rms: Rms::new(rms_window_size(sample_rate)),
             rms_out: Arc::new([
                 AtomicF64::new(-f64::INFINITY),
                 AtomicF64::new(-f64::INFINITY),
             ]),
-            params: Arc::new(TrackParams::new()),
+            params: Arc::new(TrackParams::new(sample_rate)),
         }
     }
 }
 
+fn rms_window_size(sample_rate: f64) -> usize {
+    (RMS_WINDOW * sample_rate) as usize
+}
+
 impl Plugin for Track {
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -310,24 +497,55 @@ impl Plugin for Track {
 
         ProcessStatus::Continue
     }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.rms = Rms::new(rms_window_size(sample_rate));
+        self.params.set_sample_rate(sample_rate);
+    }
+}
+
+/// Nodes with their output buffers and the last event played on them, indexed by node index
+pub struct Pool {
+    nodes: Vec<Node>,
+    buffers: Vec<Buffer>,
+    last_events: Vec<Option<(u64, usize)>>,
+}
+
+impl Pool {
+    pub fn new(size: usize, sample_rate: f64) -> Self {
+        let mut nodes = Vec::with_capacity(size);
+        let mut buffers = Vec::with_capacity(size);
+        for _ in 0..size {
+            nodes.push(Node::new(sample_rate));
+            buffers.push(audio::buffer());
+        }
+        Self {
+            nodes,
+            buffers,
+            last_events: vec![None; size],
+        }
+    }
 }
 
 struct Node {
     inner: Option<Box<dyn Plugin + Send>>,
     status: Option<ProcessStatus>,
     deleted: bool,
+    bypassed: bool,
     mix: Param,
 }
 
 impl Node {
-    fn new() -> Self {
+    fn new(sample_rate: f64) -> Self {
         Self {
             status: None,
             deleted: false,
+            bypassed: false,
             inner: None,
             mix: Param::new(
                 1.0,
-                ParamInfo::new("Mix", 0, 1).with_smoothing(params::Smoothing::exp_default()),
+                ParamInfo::new("Mix", 0, 1)
+                    .with_smoothing(params::Smoothing::exp_default(sample_rate)),
             ),
         }
     }
@@ -346,16 +564,30 @@ impl Node {
         self.mix.set(0.0);
     }
 
+    fn set_bypass(&mut self, bypass: bool) {
+        if self.deleted {
+            return;
+        }
+        self.bypassed = bypass;
+        self.mix.set(if bypass { 0.0 } else { 1.0 });
+    }
+
     fn is_quiet(&self) -> bool {
         self.mix.value() == 0.0
     }
 
+    /// Returns whether the node is bypassed and has faded out
+    fn is_bypassed(&self) -> bool {
+        self.bypassed && self.is_quiet()
+    }
+
     fn is_idle(&self) -> bool {
         matches!(self.status, Some(ProcessStatus::Idle))
     }
 
     fn reset(&mut self) -> Box<dyn Plugin + Send> {
         self.deleted = false;
+        self.bypassed = false;
         self.mix.set(1.0);
         self.status = None;
         self.inner.take().unwrap()
@@ -370,10 +602,14 @@ pub enum ProcessStatus {
 /// Data passed to a device for processing a single audio buffer
 pub struct ProcessContext<'a> {
     pub num_frames: usize,
+    /// Tempo of the song, for plugins that sync to it
+    pub bpm: u16,
 
     mix: Option<&'a Param>,
 
-    buffer_indices: Option<(usize, usize)>,
+    pub(crate) buffer_indices: Option<(usize, usize)>,
+    /// Buffer with the signal of another track that the node listens to
+    pub(crate) sidechain_index: Option<usize>,
     buffers: &'a mut [Buffer],
 }
 
@@ -381,8 +617,10 @@ impl<'a> ProcessContext<'a> {
     pub fn new(buffers: &'a mut [Buffer], num_frames: usize) -> Self {
         Self {
             num_frames,
+            bpm: 120,
             buffers,
             buffer_indices: None,
+            sidechain_index: None,
             mix: None,
         }
     }
@@ -398,15 +636,30 @@ impl<'a> ProcessContext<'a> {
     pub fn buffers(&mut self) -> impl Iterator<Item = FrameRef> {
         let (input, output) = self.buffer_indices.unwrap();
 
-        let [input, output] = GetManyMutExt::get_many_mut(self.buffers, [input, output])
-            .expect("buffers should exist");
+        let (input, output, sidechain) = match self.sidechain_index {
+            Some(sidechain) => {
+                let [input, output, sidechain] =
+                    GetManyMutExt::get_many_mut(self.buffers, [input, output, sidechain])
+                        .expect("buffers should exist");
+                (input, output, Some(sidechain))
+            }
+            None => {
+                let [input, output] = GetManyMutExt::get_many_mut(self.buffers, [input, output])
+                    .expect("buffers should exist");
+                (input, output, None)
+            }
+        };
 
         let input = input[..self.num_frames].iter();
         let output = output[..self.num_frames].iter_mut();
-
-        iter::zip(input, output).map(|(i, o)| {
-            let mix = self.mix.map_or(1.0, |v| v.value() as f32);
-            FrameRef::new(i, o, mix)
+        let mut sidechain = sidechain.map(|buf| buf[..self.num_frames].iter());
+        let mix = self.mix;
+
+        iter::zip(input, output).map(move |(i, o)| {
+            let mix = mix.map_or(1.0, |v| v.value() as f32);
+            let mut frame = FrameRef::new(i, o, mix);
+            frame.sidechain = sidechain.as_mut().and_then(Iterator::next);
+            frame
         })
     }
 }
@@ -414,12 +667,20 @@ impl<'a> ProcessContext<'a> {
 pub struct FrameRef<'a> {
     mix: f32,
     pub input: &'a Stereo,
+    /// Input from another track, for nodes that listen to one like a compressor ducking to a
+    /// kick drum
+    pub sidechain: Option<&'a Stereo>,
     output: &'a mut Stereo,
 }
 
 impl<'a> FrameRef<'a> {
     fn new(input: &'a Stereo, output: &'a mut Stereo, mix: f32) -> Self {
-        Self { input, output, mix }
+        Self {
+            input,
+            sidechain: None,
+            output,
+            mix,
+        }
     }
 
     pub fn write(&mut self, frame: Stereo) {
@@ -433,6 +694,18 @@ pub trait Plugin {
     fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus;
     fn params(&self) -> Arc<dyn Params>;
     fn send_event(&mut self, event: PluginEvent);
+
+    /// Called when the engine's sample rate changes. Plugins with state that depends on it,
+    /// like buffers sized in samples, must override this and update their params as well.
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params().set_sample_rate(sample_rate);
+    }
+
+    /// Gain reduction in dB for plugins that report it, like compressors. It's read by the app
+    /// thread for display.
+    fn gain_reduction(&self) -> Option<Arc<AtomicF64>> {
+        None
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -485,3 +758,44 @@ pub enum Note {
     On(u8, u8),
     Off,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{self, Msg, TrackType};
+
+    #[test]
+    fn grow_waits_for_app_thread() -> anyhow::Result<()> {
+        let (mut app, mut app_state, mut engine, _) = app::new()?;
+        app.send(Msg::CreateTrack(
+            MASTER_TRACK,
+            MAIN_OUTPUT,
+            TrackType::Bus,
+            None,
+        ))?;
+        for i in 0..INITIAL_POOL_SIZE {
+            app.send(Msg::CreateTrack(
+                i,
+                MASTER_TRACK,
+                TrackType::Instrument,
+                None,
+            ))?;
+        }
+
+        // The app thread hasn't taken back anything, so the old pool can't be returned yet
+        while !engine.producer.is_full() {
+            let pool = Box::new(Pool::new(0, DEFAULT_SAMPLE_RATE));
+            assert!(engine.producer.push(AppCommand::DropPool(pool)).is_ok());
+        }
+        let mut buf = vec![Stereo::ZERO; 64];
+        engine.process(app_state.read(), &mut buf);
+        assert_eq!(INITIAL_POOL_SIZE, engine.nodes.len());
+
+        app.send(Msg::Noop)?;
+        engine.process(app_state.read(), &mut buf);
+        assert!(engine.nodes.len() > INITIAL_POOL_SIZE);
+        let track = &app.tracks[INITIAL_POOL_SIZE - 1];
+        assert!(engine.nodes[track.node_index].inner.is_some());
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{sampler::Adsr, SAMPLE_RATE};
+use crate::sampler::Adsr;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum State {
@@ -27,12 +27,15 @@ pub struct Envelope {
     decay: f64,
     sustain: f64,
     release: f64,
+
+    sample_rate: f64,
 }
 
 impl Envelope {
-    pub fn new(adsr: Adsr) -> Envelope {
+    pub fn new(adsr: Adsr, sample_rate: f64) -> Envelope {
         Envelope {
             state: State::Idle,
+            sample_rate,
             attack: adsr.attack,
             decay: adsr.decay,
             sustain: adsr.sustain,
@@ -52,17 +55,23 @@ impl Envelope {
         self.release = adsr.release;
     }
 
+    /// Keeps the current stage at the same duration in seconds when the sample rate changes
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.pole = self.pole.powf(self.sample_rate / sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
     pub fn value(&mut self, gate: f64) -> f64 {
         let sustain = self.sustain_value();
 
         if gate > self.prev_gate {
             self.state = State::Attack;
             self.target = 1.0 + EPS;
-            self.pole = ratio_to_pole(self.attack, EPS / self.target);
+            self.pole = ratio_to_pole(self.attack, EPS / self.target, self.sample_rate);
         } else if gate < self.prev_gate {
             self.state = State::Release;
             self.target = -EPS;
-            self.pole = ratio_to_pole(self.release, EPS / (sustain + EPS));
+            self.pole = ratio_to_pole(self.release, EPS / (sustain + EPS), self.sample_rate);
         }
 
         self.prev_gate = gate;
@@ -76,7 +85,8 @@ impl Envelope {
                     self.out = 1.0;
                     self.state = Decay;
                     self.target = sustain - EPS;
-                    self.pole = ratio_to_pole(self.decay, EPS / (1.0 - sustain + EPS));
+                    self.pole =
+                        ratio_to_pole(self.decay, EPS / (1.0 - sustain + EPS), self.sample_rate);
                 }
             }
             Decay => {
@@ -105,6 +115,6 @@ impl Envelope {
     }
 }
 
-fn ratio_to_pole(msec: f64, ratio: f64) -> f64 {
-    f64::powf(ratio, 1.0 / ((msec / 1000.0) * SAMPLE_RATE))
+fn ratio_to_pole(msec: f64, ratio: f64, sample_rate: f64) -> f64 {
+    f64::powf(ratio, 1.0 / ((msec / 1000.0) * sample_rate))
 }
@@ -0,0 +1,422 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::audio::Stereo;
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, format_hz, map_log, Param, ParamInfo, Params, Smoothing};
+use param_derive::Params;
+
+const MIN_FREQ: f64 = 20.0;
+const MAX_FREQ: f64 = 20_000.0;
+const MAX_GAIN: f64 = 18.0;
+/// Filter stages of a cut at its steepest slope, each adds 12dB per octave
+const MAX_CUT_STAGES: usize = 4;
+const NUM_PEAKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shape {
+    LowPass,
+    HighPass,
+    LowShelf,
+    HighShelf,
+    Peak,
+}
+
+#[derive(Params)]
+pub struct EqualizerParams {
+    low_cut: Param,
+    low_cut_slope: Param,
+    low_shelf: Param,
+    low_shelf_gain: Param,
+    low_shelf_q: Param,
+    peak1: Param,
+    peak1_gain: Param,
+    peak1_q: Param,
+    peak2: Param,
+    peak2_gain: Param,
+    peak2_q: Param,
+    peak3: Param,
+    peak3_gain: Param,
+    peak3_q: Param,
+    peak4: Param,
+    peak4_gain: Param,
+    peak4_q: Param,
+    high_shelf: Param,
+    high_shelf_gain: Param,
+    high_shelf_q: Param,
+    high_cut: Param,
+    high_cut_slope: Param,
+}
+
+impl EqualizerParams {
+    fn new(sample_rate: f64) -> Self {
+        let freq = |name: &str, hz: f64| {
+            let map = |v| map_log(v, MIN_FREQ, MAX_FREQ);
+            Param::new(
+                (hz / MIN_FREQ).ln() / (MAX_FREQ / MIN_FREQ).ln(),
+                ParamInfo::new(name, 0, 1)
+                    .with_map(map)
+                    .with_formatter(move |v| format_hz(map(v)))
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            )
+        };
+        let gain = |name: &str| {
+            Param::new(
+                0.0,
+                ParamInfo::new(name, -MAX_GAIN, MAX_GAIN)
+                    .with_steps([0.5, 3.0])
+                    .with_formatter(|v| format!("{:.1}dB", v))
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            )
+        };
+        let q = |name: &str, value: f64| {
+            Param::new(
+                value,
+                ParamInfo::new(name, 0.1, 10.0)
+                    .with_steps([0.05, 0.5])
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            )
+        };
+        // The cuts are off until a slope is selected
+        let slope = |name: &str| {
+            Param::new(
+                0.0,
+                ParamInfo::new(name, 0, MAX_CUT_STAGES as u32)
+                    .with_steps([1, 1])
+                    .with_formatter(|v| match v as usize {
+                        0 => String::from("Off"),
+                        stages => format!("{}dB/oct", 12 * stages),
+                    }),
+            )
+        };
+
+        Self {
+            low_cut: freq("Low Cut", MIN_FREQ),
+            low_cut_slope: slope("Low Cut Slope"),
+            low_shelf: freq("Low Shelf", 100.0),
+            low_shelf_gain: gain("Low Shelf Gain"),
+            low_shelf_q: q("Low Shelf Q", 0.7),
+            peak1: freq("Peak 1", 200.0),
+            peak1_gain: gain("Peak 1 Gain"),
+            peak1_q: q("Peak 1 Q", 1.0),
+            peak2: freq("Peak 2", 500.0),
+            peak2_gain: gain("Peak 2 Gain"),
+            peak2_q: q("Peak 2 Q", 1.0),
+            peak3: freq("Peak 3", 2000.0),
+            peak3_gain: gain("Peak 3 Gain"),
+            peak3_q: q("Peak 3 Q", 1.0),
+            peak4: freq("Peak 4", 5000.0),
+            peak4_gain: gain("Peak 4 Gain"),
+            peak4_q: q("Peak 4 Q", 1.0),
+            high_shelf: freq("High Shelf", 8000.0),
+            high_shelf_gain: gain("High Shelf Gain"),
+            high_shelf_q: q("High Shelf Q", 0.7),
+            high_cut: freq("High Cut", MAX_FREQ),
+            high_cut_slope: slope("High Cut Slope"),
+        }
+    }
+
+    /// Returns the frequency, gain and Q of each peaking band
+    fn peaks(&self) -> [[&Param; 3]; NUM_PEAKS] {
+        [
+            [&self.peak1, &self.peak1_gain, &self.peak1_q],
+            [&self.peak2, &self.peak2_gain, &self.peak2_q],
+            [&self.peak3, &self.peak3_gain, &self.peak3_q],
+            [&self.peak4, &self.peak4_gain, &self.peak4_q],
+        ]
+    }
+}
+
+/// State-variable filter like the one of [`crate::filter::Filter`], which mixes its outputs for
+/// the shape of each band. The coefficients are only computed again when the band changes.
+struct Band {
+    settings: Option<(Shape, f64, f64, f64)>,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    m0: f32,
+    m1: f32,
+    m2: f32,
+    ic1eq: Stereo,
+    ic2eq: Stereo,
+}
+
+impl Band {
+    fn new() -> Self {
+        Self {
+            settings: None,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            m0: 1.0,
+            m1: 0.0,
+            m2: 0.0,
+            ic1eq: Stereo::ZERO,
+            ic2eq: Stereo::ZERO,
+        }
+    }
+
+    /// Sets the shape of the band, with its gain in dB for shelves and peaks
+    fn update(&mut self, shape: Shape, freq: f64, q: f64, gain: f64, sample_rate: f64) {
+        let settings = (shape, freq, q, gain);
+        if self.settings == Some(settings) {
+            return;
+        }
+        self.settings = Some(settings);
+
+        let freq = f64::min(freq, 0.49 * sample_rate);
+        let mut g = (PI * freq / sample_rate).tan();
+        let mut k = 1.0 / q;
+        let a = f64::powf(10.0, gain / 40.0);
+        let (m0, m1, m2) = match shape {
+            Shape::LowPass => (0.0, 0.0, 1.0),
+            Shape::HighPass => (1.0, -k, -1.0),
+            Shape::LowShelf => {
+                g /= a.sqrt();
+                (1.0, k * (a - 1.0), a * a - 1.0)
+            }
+            Shape::HighShelf => {
+                g *= a.sqrt();
+                (a * a, k * (1.0 - a) * a, 1.0 - a * a)
+            }
+            Shape::Peak => {
+                k /= a;
+                (1.0, k * (a * a - 1.0), 0.0)
+            }
+        };
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        (self.a1, self.a2, self.a3) = (a1 as f32, a2 as f32, a3 as f32);
+        (self.m0, self.m1, self.m2) = (m0 as f32, m1 as f32, m2 as f32);
+    }
+
+    fn process(&mut self, v0: Stereo) -> Stereo {
+        let v3 = v0 - self.ic2eq;
+        let v1 = self.ic1eq * self.a1 + v3 * self.a2;
+        let v2 = self.ic2eq + self.ic1eq * self.a2 + v3 * self.a3;
+        self.ic1eq = v1 * 2.0 - self.ic1eq;
+        self.ic2eq = v2 * 2.0 - self.ic2eq;
+        v0 * self.m0 + v1 * self.m1 + v2 * self.m2
+    }
+
+    fn reset(&mut self) {
+        self.ic1eq = Stereo::ZERO;
+        self.ic2eq = Stereo::ZERO;
+    }
+}
+
+/// Low and high cut made of a cascade of second order stages, which together have a Butterworth
+/// response
+struct Cut {
+    shape: Shape,
+    stages: [Band; MAX_CUT_STAGES],
+    /// Number of stages in use
+    active: usize,
+}
+
+impl Cut {
+    fn new(shape: Shape) -> Self {
+        Self {
+            shape,
+            stages: std::array::from_fn(|_| Band::new()),
+            active: 0,
+        }
+    }
+
+    fn update(&mut self, freq: f64, slope: f64, sample_rate: f64) {
+        let active = slope as usize;
+        if active != self.active {
+            // Stages coming back into use start out clean
+            for stage in &mut self.stages[active.min(self.active)..] {
+                stage.reset();
+            }
+            self.active = active;
+        }
+        let order = 2 * active;
+        for (i, stage) in self.stages[..active].iter_mut().enumerate() {
+            let q = 1.0 / (2.0 * f64::cos((2 * i + 1) as f64 * PI / (2 * order) as f64));
+            stage.update(self.shape, freq, q, 0.0, sample_rate);
+        }
+    }
+
+    fn process(&mut self, input: Stereo) -> Stereo {
+        self.stages[..self.active]
+            .iter_mut()
+            .fold(input, |v, stage| stage.process(v))
+    }
+}
+
+/// Parametric equalizer with low and high cuts, low and high shelves and four peaking bands.
+/// Shelves and peaks at 0dB leave the signal as it is.
+pub struct Equalizer {
+    params: Arc<EqualizerParams>,
+    low_cut: Cut,
+    low_shelf: Band,
+    peaks: [Band; NUM_PEAKS],
+    high_shelf: Band,
+    high_cut: Cut,
+    sample_rate: f64,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            params: Arc::new(EqualizerParams::new(sample_rate)),
+            low_cut: Cut::new(Shape::HighPass),
+            low_shelf: Band::new(),
+            peaks: std::array::from_fn(|_| Band::new()),
+            high_shelf: Band::new(),
+            high_cut: Cut::new(Shape::LowPass),
+            sample_rate,
+        }
+    }
+}
+
+impl Plugin for Equalizer {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        self.sample_rate = sample_rate;
+        // Coefficients depend on the sample rate, so they are computed again
+        self.low_cut = Cut::new(Shape::HighPass);
+        self.low_shelf = Band::new();
+        self.peaks = std::array::from_fn(|_| Band::new());
+        self.high_shelf = Band::new();
+        self.high_cut = Cut::new(Shape::LowPass);
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        let params = &self.params;
+        let sample_rate = self.sample_rate;
+        for mut frame in ctx.buffers() {
+            self.low_cut.update(
+                params.low_cut.value(),
+                params.low_cut_slope.value(),
+                sample_rate,
+            );
+            self.low_shelf.update(
+                Shape::LowShelf,
+                params.low_shelf.value(),
+                params.low_shelf_q.value(),
+                params.low_shelf_gain.value(),
+                sample_rate,
+            );
+            for (band, [freq, gain, q]) in self.peaks.iter_mut().zip(params.peaks()) {
+                band.update(
+                    Shape::Peak,
+                    freq.value(),
+                    q.value(),
+                    gain.value(),
+                    sample_rate,
+                );
+            }
+            self.high_shelf.update(
+                Shape::HighShelf,
+                params.high_shelf.value(),
+                params.high_shelf_q.value(),
+                params.high_shelf_gain.value(),
+                sample_rate,
+            );
+            self.high_cut.update(
+                params.high_cut.value(),
+                params.high_cut_slope.value(),
+                sample_rate,
+            );
+
+            let mut output = self.low_cut.process(*frame.input);
+            output = self.low_shelf.process(output);
+            for band in &mut self.peaks {
+                output = band.process(output);
+            }
+            output = self.high_shelf.process(output);
+            output = self.high_cut.process(output);
+            frame.write(output);
+        }
+
+        ProcessStatus::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    /// Returns the gain of the equalizer in dB for a sine wave, after its params settled
+    fn response(equalizer: &mut Equalizer, freq: f64) -> f64 {
+        let len = 8192;
+        let input: Buffer = (0..len)
+            .map(|i| {
+                let s = (2.0 * PI * freq * i as f64 / SAMPLE_RATE).sin() as f32;
+                Stereo::new([s, s])
+            })
+            .collect();
+        let mut buffers = vec![input, vec![Stereo::ZERO; len]];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        equalizer.process(&mut ctx);
+        let peak = buffers[1][len / 2..]
+            .iter()
+            .map(|frame| frame.channel(0).abs())
+            .fold(0.0, f32::max);
+        20.0 * (peak as f64).log10()
+    }
+
+    fn set_freq(param: &Param, hz: f64) {
+        param.set((hz / MIN_FREQ).ln() / (MAX_FREQ / MIN_FREQ).ln());
+    }
+
+    #[test]
+    fn flat_by_default() {
+        let mut equalizer = Equalizer::new(SAMPLE_RATE);
+        for freq in [50.0, 1000.0, 10_000.0] {
+            assert!(response(&mut equalizer, freq).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn shelves_and_peaks() {
+        let mut equalizer = Equalizer::new(SAMPLE_RATE);
+        let params = equalizer.params.clone();
+        set_freq(&params.peak3, 1000.0);
+        params.peak3_gain.set(12.0);
+        assert!((response(&mut equalizer, 1000.0) - 12.0).abs() < 0.2);
+        assert!(response(&mut equalizer, 100.0).abs() < 0.5);
+        assert!(response(&mut equalizer, 10_000.0).abs() < 0.5);
+
+        params.peak3_gain.set(0.0);
+        params.low_shelf_gain.set(-6.0);
+        params.high_shelf_gain.set(6.0);
+        assert!((response(&mut equalizer, 30.0) - -6.0).abs() < 0.3);
+        assert!((response(&mut equalizer, 18_000.0) - 6.0).abs() < 0.3);
+        assert!(response(&mut equalizer, 1000.0).abs() < 0.3);
+    }
+
+    #[test]
+    fn cut_slopes() {
+        let mut equalizer = Equalizer::new(SAMPLE_RATE);
+        let params = equalizer.params.clone();
+        set_freq(&params.low_cut, 1000.0);
+        params.low_cut_slope.set(1.0);
+        let gentle = response(&mut equalizer, 250.0);
+        assert!((gentle - -24.0).abs() < 1.0, "{gentle}");
+
+        params.low_cut_slope.set(4.0);
+        let steep = response(&mut equalizer, 250.0);
+        assert!((steep - -96.0).abs() < 3.0, "{steep}");
+        // Butterworth cascades are 3dB down at the cutoff at any slope
+        assert!((response(&mut equalizer, 1000.0) - -3.0).abs() < 0.2);
+
+        set_freq(&params.high_cut, 1000.0);
+        params.high_cut_slope.set(2.0);
+        assert!(response(&mut equalizer, 4000.0) < -45.0);
+    }
+}
@@ -0,0 +1,208 @@
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use crate::audio::Stereo;
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, format_hz, map_log, Param, ParamInfo, Params, Smoothing};
+use param_derive::Params;
+
+const MIN_CUTOFF: f64 = 20.0;
+const MAX_CUTOFF: f64 = 20_000.0;
+/// Quality factor at zero and full resonance. Zero resonance gives a flat Butterworth response.
+const MIN_Q: f64 = FRAC_1_SQRT_2;
+const MAX_Q: f64 = 25.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+impl Mode {
+    const ALL: [Mode; 4] = [Self::LowPass, Self::HighPass, Self::BandPass, Self::Notch];
+
+    fn from_value(v: f64) -> Self {
+        Self::ALL[v as usize]
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::LowPass => "Low-Pass",
+            Self::HighPass => "High-Pass",
+            Self::BandPass => "Band-Pass",
+            Self::Notch => "Notch",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Params)]
+pub struct FilterParams {
+    mode: Param,
+    cutoff: Param,
+    resonance: Param,
+}
+
+impl FilterParams {
+    fn new(sample_rate: f64) -> Self {
+        let cutoff = |v| map_log(v, MIN_CUTOFF, MAX_CUTOFF);
+        Self {
+            mode: Param::new(
+                0.0,
+                ParamInfo::new("Mode", 0, Mode::ALL.len() as u32 - 1)
+                    .with_steps([1, 1])
+                    .with_formatter(|v| Mode::from_value(v).to_string()),
+            ),
+            cutoff: Param::new(
+                1.0,
+                ParamInfo::new("Cutoff", 0, 1)
+                    .with_map(cutoff)
+                    .with_formatter(move |v| format_hz(cutoff(v)))
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            resonance: Param::new(
+                0.0,
+                ParamInfo::new("Resonance", 0, 1)
+                    .with_map(|v| map_log(v, MIN_Q, MAX_Q))
+                    .with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+        }
+    }
+}
+
+/// Resonant state-variable filter, using the topology-preserving transform so the cutoff can be
+/// swept without the filter blowing up.
+pub struct Filter {
+    params: Arc<FilterParams>,
+    /// States of the two integrators for each channel
+    ic1eq: Stereo,
+    ic2eq: Stereo,
+    sample_rate: f64,
+}
+
+impl Filter {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            params: Arc::new(FilterParams::new(sample_rate)),
+            ic1eq: Stereo::ZERO,
+            ic2eq: Stereo::ZERO,
+            sample_rate,
+        }
+    }
+}
+
+impl Plugin for Filter {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        let mode = Mode::from_value(self.params.mode.value());
+        for mut frame in ctx.buffers() {
+            let cutoff = f64::min(self.params.cutoff.value(), 0.49 * self.sample_rate);
+            let g = (PI * cutoff / self.sample_rate).tan();
+            let k = 1.0 / self.params.resonance.value();
+            let a1 = 1.0 / (1.0 + g * (g + k));
+            let a2 = g * a1;
+            let a3 = g * a2;
+            let (a1, a2, a3, k) = (a1 as f32, a2 as f32, a3 as f32, k as f32);
+
+            let v0 = *frame.input;
+            let v3 = v0 - self.ic2eq;
+            let v1 = self.ic1eq * a1 + v3 * a2;
+            let v2 = self.ic2eq + self.ic1eq * a2 + v3 * a3;
+            self.ic1eq = v1 * 2.0 - self.ic1eq;
+            self.ic2eq = v2 * 2.0 - self.ic2eq;
+
+            let low = v2;
+            let high = v0 - v1 * k - v2;
+            let output = match mode {
+                Mode::LowPass => low,
+                Mode::HighPass => high,
+                // Scaled so the peak at the cutoff has unity gain at any resonance
+                Mode::BandPass => v1 * k,
+                Mode::Notch => low + high,
+            };
+            frame.write(output);
+        }
+
+        ProcessStatus::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    /// Returns the peak of the filter's output for a sine wave, after the filter and its params
+    /// settled
+    fn peak(filter: &mut Filter, freq: f64) -> f32 {
+        let len = 4096;
+        let input: Buffer = (0..len)
+            .map(|i| {
+                let s = (2.0 * PI * freq * i as f64 / SAMPLE_RATE).sin() as f32;
+                Stereo::new([s, s])
+            })
+            .collect();
+        let mut buffers = vec![input, vec![Stereo::ZERO; len]];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        filter.process(&mut ctx);
+        buffers[1][len / 2..]
+            .iter()
+            .map(|frame| frame.channel(0).abs())
+            .fold(0.0, f32::max)
+    }
+
+    fn filter(mode: Mode, cutoff: f64) -> Filter {
+        let filter = Filter::new(SAMPLE_RATE);
+        let value = (cutoff / MIN_CUTOFF).ln() / (MAX_CUTOFF / MIN_CUTOFF).ln();
+        filter.params.cutoff.set(value);
+        filter
+            .params
+            .mode
+            .set(Mode::ALL.iter().position(|m| *m == mode).unwrap() as f64);
+        filter
+    }
+
+    #[test]
+    fn filter_modes() {
+        let mut low_pass = filter(Mode::LowPass, 1000.0);
+        assert!(peak(&mut low_pass, 100.0) > 0.9);
+        assert!(peak(&mut low_pass, 10_000.0) < 0.02);
+
+        let mut high_pass = filter(Mode::HighPass, 1000.0);
+        assert!(peak(&mut high_pass, 100.0) < 0.02);
+        assert!(peak(&mut high_pass, 10_000.0) > 0.9);
+
+        let mut band_pass = filter(Mode::BandPass, 1000.0);
+        assert!(peak(&mut band_pass, 1000.0) > 0.9);
+        assert!(peak(&mut band_pass, 10_000.0) < 0.2);
+
+        let mut notch = filter(Mode::Notch, 1000.0);
+        assert!(peak(&mut notch, 1000.0) < 0.05);
+        assert!(peak(&mut notch, 10_000.0) > 0.9);
+    }
+
+    #[test]
+    fn resonance() {
+        let mut filter = filter(Mode::LowPass, 1000.0);
+        filter.params.resonance.set(1.0);
+        assert!(peak(&mut filter, 1000.0) > 10.0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Undo and redo of project edits.
+//!
+//! Every edit is stored as the edit that reverts it. Applying an edit returns its inverse, which
+//! then goes on the other stack, so undo and redo work the same way.
+
+use std::collections::{HashMap, VecDeque};
+
+use camino::Utf8PathBuf;
+
+use crate::app::{PatternId, Track};
+use crate::pattern::{self, Pattern};
+use crate::project::DeviceData;
+use crate::sampler::Sound;
+
+const MAX_EDITS: usize = 256;
+
+pub enum Edit {
+    /// Replaces the contents of a pattern
+    Pattern(PatternId, Pattern),
+    /// Replaces the song order and all patterns
+    Song(SongSnapshot),
+    InsertTrack(usize, Track, HashMap<PatternId, pattern::Track>),
+    RemoveTrack(usize),
+    RenameTrack(usize, Option<String>),
+    /// Replaces the instrument in a slot, or clears the slot
+    Instrument(usize, Option<InstrumentSnapshot>),
+    /// Sets a parameter of an instrument, which is referred to by its slot because its node
+    /// changes when it's reloaded
+    InstrumentParam(usize, usize, f64),
+    /// Sets a parameter of a track node
+    Param(usize, usize, f64),
+    /// Sets a parameter of an effect by track and effect index, because the effect's node changes
+    /// when it's removed and restored
+    EffectParam(usize, usize, usize, f64),
+    /// Creates an effect at a position in a track's effect chain
+    InsertEffect(usize, usize, DeviceData),
+    RemoveEffect(usize, usize),
+    /// Moves an effect of a track from one position in the chain to another
+    MoveEffect(usize, usize, usize),
+    BypassEffect(usize, usize, bool),
+    /// Sets the track that an effect's sidechain listens to, by the track's node index
+    Sidechain(usize, usize, Option<usize>),
+    /// Edits that are undone together, applied in order
+    Batch(Vec<Edit>),
+}
+
+#[derive(Clone)]
+pub struct SongSnapshot {
+    pub song: Vec<PatternId>,
+    pub patterns: HashMap<PatternId, Pattern>,
+    pub selected_pattern: usize,
+    pub loop_range: Option<(usize, usize)>,
+}
+
+/// Everything needed to load an instrument again
+#[derive(Clone)]
+pub struct InstrumentSnapshot {
+    pub name: String,
+    pub path: Option<Utf8PathBuf>,
+    /// Sound of an instrument that isn't backed by a file
+    pub sound: Option<Sound>,
+    pub params: Vec<f64>,
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    /// Edits that can't be applied anymore, which can still hold on to nodes of removed tracks
+    dropped: Vec<Edit>,
+}
+
+impl History {
+    /// Records the inverse of an edit that was just made. This clears the redo stack.
+    pub fn record(&mut self, edit: Edit) {
+        self.dropped.append(&mut self.redo);
+        self.push_undo(edit);
+    }
+
+    /// Returns whether a change to a parameter can be merged with the last edit, so a series of
+    /// changes to the same parameter is undone at once.
+    pub fn continues_param_edit(&self, edit: &Edit) -> bool {
+        if !self.redo.is_empty() {
+            return false;
+        }
+        match (self.undo.back(), edit) {
+            (Some(Edit::InstrumentParam(a, i, _)), Edit::InstrumentParam(b, j, _))
+            | (Some(Edit::Param(a, i, _)), Edit::Param(b, j, _)) => a == b && i == j,
+            (Some(Edit::EffectParam(a, b, i, _)), Edit::EffectParam(c, d, j, _)) => {
+                (a, b, i) == (c, d, j)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        self.undo.pop_back()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        self.redo.pop()
+    }
+
+    pub fn push_undo(&mut self, edit: Edit) {
+        if self.undo.len() == MAX_EDITS {
+            self.dropped.extend(self.undo.pop_front());
+        }
+        self.undo.push_back(edit);
+    }
+
+    pub fn push_redo(&mut self, edit: Edit) {
+        self.redo.push(edit);
+    }
+
+    pub fn clear(&mut self) {
+        self.dropped.extend(self.undo.drain(..));
+        self.dropped.append(&mut self.redo);
+    }
+
+    /// Returns the edits that were dropped since the last call, so their nodes can be deleted
+    pub fn take_dropped(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.dropped)
+    }
+}
@@ -6,7 +6,9 @@ use ratatui::{
 };
 
 use crate::app::{App, Msg, TrackType};
-use crate::engine::MASTER_TRACK;
+use crate::backend;
+use crate::effects::{self, EFFECTS};
+use crate::engine::{self, MASTER_TRACK};
 use crate::pattern::{Selection, StepSize, INPUTS_PER_STEP};
 use crate::sampler;
 use crate::view::{Focus, ProjectTreeState, View};
@@ -86,16 +88,23 @@ fn handle_key(app: &App, view: &mut View, key: KeyEvent) -> Result<Msg> {
                     let msg = if entry.file_type.is_dir() {
                         view.files = ListState::default().with_selected(Some(0));
                         ChangeDir(entry.path.to_path_buf())
-                    } else if sampler::can_load_file(&entry.path) {
+                    } else if !sampler::can_load_file(&entry.path) {
+                        Noop
+                    } else if let Some((track_idx, idx)) = view.ir_target.take() {
+                        view.focus = Focus::ProjectTree;
+                        LoadImpulseResponse(track_idx, idx, entry.path.to_path_buf())
+                    } else {
                         LoadSound(
                             view.instruments.selected().unwrap(),
                             entry.path.to_path_buf(),
                         )
-                    } else {
-                        Noop
                     };
                     return Ok(msg);
                 }
+                KeyCode::Esc if view.ir_target.is_some() => {
+                    view.ir_target = None;
+                    view.focus = Focus::ProjectTree;
+                }
                 _ => handle_list_input(&mut view.files, key),
             };
         }
@@ -157,6 +166,8 @@ fn handle_editor_input(app: &App, view: &mut View, key: KeyEvent) -> Result<Msg>
             return Ok(Noop);
         }
         KeyCode::Char(' ') => return Ok(TogglePlay),
+        KeyCode::Char('z') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(Undo),
+        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(Redo),
         KeyCode::Backspace => {
             let msg = app.update_pattern(|p| p.clear(view.editor.cursor));
             if view.editor.cursor.is_pitch_input() {
@@ -249,6 +260,41 @@ fn handle_command_line_input(app: &App, view: &mut View, key: KeyEvent) -> Resul
                 }
                 "bpm" => Ok(SetBpm(parts[1].parse()?)),
                 "quit" | "q" | "exit" => Ok(Exit),
+                "undo" => Ok(Undo),
+                "redo" => Ok(Redo),
+                "devices" => Ok(ListDevices),
+                // Device names can contain spaces
+                "device" if parts.len() > 1 => Ok(SelectDevice(parts[1..].join(" "))),
+                "buffer-size" if parts.len() == 2 => match parts[1] {
+                    "default" => Ok(SetBufferSize(None)),
+                    size => Ok(SetBufferSize(Some(size.parse()?))),
+                },
+                "channels" if parts.len() == 2 => {
+                    Ok(SetOutputChannels(backend::parse_channels(parts[1])?))
+                }
+                "sample-rate" if parts.len() == 2 => {
+                    let sample_rate = parts[1].parse()?;
+                    if !engine::supports_sample_rate(sample_rate) {
+                        return Err(anyhow!("unsupported sample rate {sample_rate}"));
+                    }
+                    Ok(SetSampleRate(sample_rate))
+                }
+                "fx" => match parts.get(1) {
+                    Some(&"list") => Ok(ListEffects),
+                    // Effect names can contain spaces
+                    Some(&"add") if parts.len() > 2 => {
+                        let idx = view.editor.cursor.track();
+                        Ok(LoadEffect(idx, parts[2..].join(" ")))
+                    }
+                    _ => Err(anyhow!("usage: fx list | fx add <name>")),
+                },
+                "w" | "write" => Ok(SaveProject(parts.get(1).map(Utf8PathBuf::from))),
+                "e" | "edit" if parts.len() == 2 => Ok(LoadProject(Utf8PathBuf::from(parts[1]))),
+                "import-midi" if parts.len() == 2 => {
+                    let idx = view.editor.cursor.track();
+                    Ok(ImportMidi(Utf8PathBuf::from(parts[1]), idx))
+                }
+                "export-midi" if parts.len() == 2 => Ok(ExportMidi(Utf8PathBuf::from(parts[1]))),
                 "setlength" if parts.len() == 2 => {
                     let new_length = parts[1].parse()?;
                     Ok(app.update_pattern(|p| p.set_len(new_length)))
@@ -328,48 +374,110 @@ fn handle_project_tree_input(app: &App, view: &mut View, key: KeyEvent) -> Resul
             };
         }
         ProjectTreeState::InstrumentParams(instr_idx) => {
-            let node_idx = app.instruments[instr_idx].as_ref().unwrap().node_index;
+            let instrument = app.instruments.get(instr_idx).and_then(Option::as_ref);
+            match instrument {
+                Some(instrument) if key.code != KeyCode::Char('u') => {
+                    return Ok(handle_param_input(app, view, instrument.node_index, key));
+                }
+                _ => view.project_tree_state = ProjectTreeState::Instruments,
+            }
+        }
+        ProjectTreeState::DeviceParams(track_idx, device_idx) => {
+            let effect = app
+                .tracks
+                .get(track_idx)
+                .and_then(|track| track.effects.get(device_idx));
+            match effect {
+                Some(effect) if key.code != KeyCode::Char('u') => {
+                    return Ok(handle_param_input(app, view, effect.node_index, key));
+                }
+                _ => view.project_tree_state = ProjectTreeState::Devices(track_idx),
+            }
+        }
+        ProjectTreeState::Devices(track_idx) => {
             match key.code {
                 KeyCode::Char('u') => {
-                    view.project_tree_state = ProjectTreeState::Instruments;
+                    view.project_tree_state = ProjectTreeState::Tracks;
                 }
-                KeyCode::Char('[') => {
-                    return Ok(ParamInc(
-                        node_idx,
-                        view.params.selected().unwrap(),
-                        StepSize::Default,
-                    ))
+                KeyCode::Char('a') => {
+                    view.project_tree_state = ProjectTreeState::EffectBrowser(track_idx);
                 }
-                KeyCode::Char(']') => {
-                    return Ok(ParamDec(
-                        node_idx,
-                        view.params.selected().unwrap(),
-                        StepSize::Default,
-                    ))
+                KeyCode::Char('l') => {
+                    let effect = view
+                        .devices
+                        .selected()
+                        .and_then(|idx| Some((idx, app.tracks[track_idx].effects.get(idx)?)));
+                    if let Some((idx, effect)) = effect {
+                        if effects::find(&effect.id).is_some_and(|e| e.loads_files()) {
+                            view.ir_target = Some((track_idx, idx));
+                            view.focus = Focus::FileLoader;
+                        }
+                    }
                 }
-                KeyCode::Char('{') => {
-                    return Ok(ParamInc(
-                        node_idx,
-                        view.params.selected().unwrap(),
-                        StepSize::Large,
-                    ))
+                KeyCode::Char('c') => {
+                    let count = app.tracks[track_idx].effects.len();
+                    let Some(idx) = view.devices.selected().filter(|i| *i < count) else {
+                        return Ok(Noop);
+                    };
+                    let effect = &app.tracks[track_idx].effects[idx];
+                    if !effects::find(&effect.id).is_some_and(|e| e.sidechain) {
+                        return Ok(Noop);
+                    }
+                    // Cycles through the tracks that the sidechain can listen to, then turns it
+                    // off again
+                    let current = effect
+                        .sidechain
+                        .and_then(|node| app.tracks.iter().position(|t| t.node_index == node));
+                    let start = current.map_or(0, |i| i + 1);
+                    let next = (start..app.tracks.len())
+                        .find(|source| app.can_sidechain(track_idx, *source));
+                    return Ok(SetSidechain(track_idx, idx, next));
                 }
-                KeyCode::Char('}') => {
-                    return Ok(ParamDec(
-                        node_idx,
-                        view.params.selected().unwrap(),
-                        StepSize::Large,
-                    ))
+                KeyCode::Enter => {
+                    let idx = view.devices.selected().unwrap();
+                    if idx < app.tracks[track_idx].effects.len() {
+                        view.project_tree_state = ProjectTreeState::DeviceParams(track_idx, idx);
+                        view.params.select(Some(0));
+                    }
+                }
+                KeyCode::Backspace
+                | KeyCode::Char('b')
+                | KeyCode::Char('J')
+                | KeyCode::Char('K') => {
+                    let count = app.tracks[track_idx].effects.len();
+                    let Some(idx) = view.devices.selected().filter(|i| *i < count) else {
+                        return Ok(Noop);
+                    };
+                    return Ok(match key.code {
+                        KeyCode::Backspace => DeleteEffect(track_idx, idx),
+                        KeyCode::Char('b') => ToggleEffectBypass(track_idx, idx),
+                        KeyCode::Char('J') if idx + 1 < count => {
+                            view.devices.select(Some(idx + 1));
+                            MoveEffect(track_idx, idx, idx + 1)
+                        }
+                        KeyCode::Char('K') if idx > 0 => {
+                            view.devices.select(Some(idx - 1));
+                            MoveEffect(track_idx, idx, idx - 1)
+                        }
+                        _ => Noop,
+                    });
                 }
-                _ => handle_list_input(&mut view.params, key),
+                _ => handle_list_input(&mut view.devices, key),
             };
         }
-        ProjectTreeState::Devices(_track_idx) => {
+        ProjectTreeState::EffectBrowser(track_idx) => {
             match key.code {
-                KeyCode::Char('u') => {
-                    view.project_tree_state = ProjectTreeState::Tracks;
+                KeyCode::Char('u') | KeyCode::Esc => {
+                    view.project_tree_state = ProjectTreeState::Devices(track_idx);
                 }
-                _ => handle_list_input(&mut view.devices, key),
+                KeyCode::Enter => {
+                    let Some(effect) = view.effects.selected().and_then(|i| EFFECTS.get(i)) else {
+                        return Ok(Noop);
+                    };
+                    view.project_tree_state = ProjectTreeState::Devices(track_idx);
+                    return Ok(LoadEffect(track_idx, String::from(effect.id)));
+                }
+                _ => handle_list_input(&mut view.effects, key),
             };
         }
         ProjectTreeState::Instruments => {
@@ -380,7 +488,10 @@ fn handle_project_tree_input(app: &App, view: &mut View, key: KeyEvent) -> Resul
                         view.project_tree_state = ProjectTreeState::InstrumentParams(idx);
                     }
                 }
-                KeyCode::Char('l') => view.focus = Focus::FileLoader,
+                KeyCode::Char('l') => {
+                    view.ir_target = None;
+                    view.focus = Focus::FileLoader;
+                }
                 KeyCode::Backspace => {
                     let idx = view.instruments.selected().unwrap();
                     return Ok(DeleteInstrument(idx));
@@ -403,6 +514,26 @@ enum CursorMove {
     LineEnd,
 }
 
+/// Edits the selected param of a node with `[`/`]`, or `{`/`}` for large steps
+fn handle_param_input(app: &App, view: &mut View, node_idx: usize, key: KeyEvent) -> Msg {
+    let step_size = match key.code {
+        KeyCode::Char('[') | KeyCode::Char(']') => StepSize::Default,
+        KeyCode::Char('{') | KeyCode::Char('}') => StepSize::Large,
+        _ => {
+            handle_list_input(&mut view.params, key);
+            return Msg::Noop;
+        }
+    };
+    let num_params = app.params(node_idx).len();
+    let Some(param_idx) = view.params.selected().filter(|i| *i < num_params) else {
+        return Msg::Noop;
+    };
+    match key.code {
+        KeyCode::Char('[') | KeyCode::Char('{') => Msg::ParamInc(node_idx, param_idx, step_size),
+        _ => Msg::ParamDec(node_idx, param_idx, step_size),
+    }
+}
+
 fn handle_list_input(list: &mut ListState, key: KeyEvent) {
     match key.code {
         KeyCode::Down => list.select_next(),
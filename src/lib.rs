@@ -1,18 +1,33 @@
 pub mod app;
 pub mod audio;
+pub mod autosave;
+pub mod backend;
+pub mod compressor;
+pub mod convolution;
 pub mod delay;
+pub mod effects;
 pub mod engine;
 pub mod env;
+pub mod equalizer;
 pub mod files;
+pub mod filter;
+pub mod history;
 pub mod input;
+pub mod limiter;
+pub mod midi;
 pub mod params;
 pub mod pattern;
+pub mod project;
+pub mod render;
+pub mod resample;
+pub mod reverb;
 pub mod sampler;
+pub mod tracker;
 pub mod view;
 
 // Keep https://github.com/RustAudio/cpal/issues/508 in mind
 // when changing the sample rate.
-pub const SAMPLE_RATE: f64 = 44100.0;
+pub const DEFAULT_SAMPLE_RATE: f64 = 44100.0;
 pub const FRAMES_PER_BUFFER: usize = 128;
 
 // Allocate a larger buffer size, because sometimes cpal requests more than the
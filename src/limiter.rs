@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use atomic_float::AtomicF64;
+
+use crate::audio::Stereo;
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, format_millis, Param, ParamInfo, Params};
+use param_derive::Params;
+
+/// Time in milliseconds that the limiter looks ahead, in which the gain goes down before a peak
+const LOOKAHEAD: f64 = 5.0;
+/// Points between two samples at which the true peak is estimated, including the first sample
+const OVERSAMPLING: usize = 4;
+/// Length of the interpolation filter in samples
+const TAPS: usize = 8;
+
+#[derive(Params)]
+pub struct LimiterParams {
+    ceiling: Param,
+    release: Param,
+}
+
+impl LimiterParams {
+    fn new() -> Self {
+        Self {
+            ceiling: Param::new(
+                -1.0,
+                ParamInfo::new("Ceiling", -12, 0)
+                    .with_steps([0.1, 1.0])
+                    .with_formatter(|v| format!("{:.1}dB", v))
+                    .with_map(params::db_to_amp),
+            ),
+            release: Param::new(
+                100.0,
+                ParamInfo::new("Release", 1, 1000)
+                    .with_steps([1, 10])
+                    .with_formatter(format_millis),
+            ),
+        }
+    }
+}
+
+/// Estimates the peaks between samples, which can be louder than the samples themselves once the
+/// signal is converted to analog, by interpolating it with a windowed sinc.
+struct TruePeak {
+    /// Filter for each point between two samples, apart from the first
+    phases: [[f64; TAPS]; OVERSAMPLING - 1],
+    /// The most recent samples of each channel, oldest first
+    history: [[f32; TAPS]; 2],
+}
+
+impl TruePeak {
+    /// Samples from the newest one to the start of the interval that the peak is estimated for
+    const DELAY: usize = TAPS / 2;
+
+    fn new() -> Self {
+        let mut phases = [[0.0; TAPS]; OVERSAMPLING - 1];
+        for (p, phase) in phases.iter_mut().enumerate() {
+            let frac = (p + 1) as f64 / OVERSAMPLING as f64;
+            for (k, tap) in phase.iter_mut().enumerate() {
+                let t = k as f64 - (Self::DELAY - 1) as f64 - frac;
+                let sinc = if t == 0.0 {
+                    1.0
+                } else {
+                    (PI * t).sin() / (PI * t)
+                };
+                let window = 0.5 * (1.0 + (PI * t / (TAPS / 2) as f64).cos());
+                *tap = sinc * window;
+            }
+        }
+        Self {
+            phases,
+            history: [[0.0; TAPS]; 2],
+        }
+    }
+
+    /// Adds a frame and returns the highest peak between the two samples `DELAY` frames back
+    fn process(&mut self, frame: Stereo) -> f32 {
+        let mut peak = 0.0f32;
+        for (ch, history) in self.history.iter_mut().enumerate() {
+            history.copy_within(1.., 0);
+            history[TAPS - 1] = frame.channel(ch);
+
+            let start = history[Self::DELAY - 1].abs();
+            let end = history[Self::DELAY].abs();
+            peak = peak.max(start).max(end);
+            for phase in &self.phases {
+                let value: f64 = phase
+                    .iter()
+                    .zip(history.iter())
+                    .map(|(tap, sample)| tap * *sample as f64)
+                    .sum();
+                peak = peak.max(value.abs() as f32);
+            }
+        }
+        peak
+    }
+}
+
+/// Brickwall limiter that keeps the true peak of its output below the ceiling. It delays the
+/// signal by the look-ahead time, so the gain can be turned down smoothly before a peak arrives
+/// instead of clipping it.
+pub struct Limiter {
+    params: Arc<LimiterParams>,
+    true_peak: TruePeak,
+    /// Gain needed for the incoming peaks, released exponentially
+    envelope: f64,
+    /// Recent envelope values with their time, kept increasing for a sliding window minimum
+    minimum: VecDeque<(u64, f64)>,
+    /// Recent gains, which are averaged so the gain changes smoothly
+    gains: Vec<f64>,
+    gains_sum: f64,
+    /// Delay line of the signal, for the look-ahead and the peak estimation
+    delay: Vec<Stereo>,
+    pos: usize,
+    time: u64,
+    gain_reduction: Arc<AtomicF64>,
+    sample_rate: f64,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_params(Arc::new(LimiterParams::new()), sample_rate)
+    }
+
+    fn with_params(params: Arc<LimiterParams>, sample_rate: f64) -> Self {
+        let window = ((LOOKAHEAD / 1000.0 * sample_rate) as usize).max(1);
+        Self {
+            params,
+            true_peak: TruePeak::new(),
+            envelope: 1.0,
+            minimum: VecDeque::with_capacity(window + 2),
+            gains: vec![1.0; window],
+            gains_sum: window as f64,
+            // A peak lies between two frames, and the gain of both has gone down for it when
+            // they come out
+            delay: vec![Stereo::ZERO; window + TruePeak::DELAY - 1],
+            pos: 0,
+            time: 0,
+            gain_reduction: Arc::new(AtomicF64::new(0.0)),
+            sample_rate,
+        }
+    }
+
+    /// Returns the gain for the frame that leaves the delay line next
+    fn next_gain(&mut self, peak: f32, ceiling: f64, release: f64) -> f64 {
+        let required = if peak as f64 > ceiling {
+            ceiling / peak as f64
+        } else {
+            1.0
+        };
+        self.envelope = if required < self.envelope {
+            required
+        } else {
+            required + release * (self.envelope - required)
+        };
+
+        // The minimum covers one frame more than the average, because the peak lies between two
+        // frames
+        let window = self.gains.len() as u64;
+        while self
+            .minimum
+            .back()
+            .is_some_and(|(_, g)| *g >= self.envelope)
+        {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.time, self.envelope));
+        while self
+            .minimum
+            .front()
+            .is_some_and(|(t, _)| *t + window < self.time)
+        {
+            self.minimum.pop_front();
+        }
+        let minimum = self.minimum.front().map_or(1.0, |(_, g)| *g);
+
+        let idx = self.time as usize % self.gains.len();
+        self.gains_sum += minimum - self.gains[idx];
+        self.gains[idx] = minimum;
+        self.time += 1;
+        self.gains_sum / window as f64
+    }
+}
+
+impl Plugin for Limiter {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    /// Resizes the look-ahead for the new sample rate, which clears the delay line
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        let gain_reduction = self.gain_reduction.clone();
+        *self = Self::with_params(self.params.clone(), sample_rate);
+        self.gain_reduction = gain_reduction;
+    }
+
+    fn gain_reduction(&self) -> Option<Arc<AtomicF64>> {
+        Some(self.gain_reduction.clone())
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        let ceiling = self.params.ceiling.value();
+        let release = (-1.0 / (self.params.release.target() / 1000.0 * self.sample_rate)).exp();
+        let mut lowest = 1.0f64;
+        for mut frame in ctx.buffers() {
+            let input = *frame.input;
+            let peak = self.true_peak.process(input);
+            let gain = self.next_gain(peak, ceiling, release);
+            lowest = lowest.min(gain);
+
+            let delayed = self.delay[self.pos];
+            self.delay[self.pos] = input;
+            self.pos = (self.pos + 1) % self.delay.len();
+            frame.write(delayed * gain as f32);
+        }
+        self.gain_reduction
+            .store(-20.0 * lowest.log10(), Ordering::Relaxed);
+
+        ProcessStatus::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn process(limiter: &mut Limiter, input: Buffer) -> Buffer {
+        let len = input.len();
+        let mut buffers = vec![input, vec![Stereo::ZERO; len]];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        limiter.process(&mut ctx);
+        buffers.pop().unwrap()
+    }
+
+    /// Sine at a quarter of the sample rate, whose samples all fall halfway between its peaks
+    fn sine(amplitude: f32, len: usize) -> Buffer {
+        (0..len)
+            .map(|i| {
+                let phase = PI / 2.0 * i as f64 + PI / 4.0;
+                let s = amplitude * phase.sin() as f32;
+                Stereo::new([s, s])
+            })
+            .collect()
+    }
+
+    fn peak(buf: &[Stereo]) -> f32 {
+        buf.iter()
+            .map(|frame| frame.channel(0).abs().max(frame.channel(1).abs()))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn true_peak() {
+        let mut true_peak = TruePeak::new();
+        let peak = sine(1.0, 64)
+            .into_iter()
+            .map(|frame| true_peak.process(frame))
+            .fold(0.0, f32::max);
+        assert!((peak - 1.0).abs() < 0.02, "{peak}");
+    }
+
+    #[test]
+    fn limit_true_peaks() {
+        let mut limiter = Limiter::new(SAMPLE_RATE);
+        let ceiling = params::db_to_amp(-1.0) as f32;
+
+        // The signal starts at full level, which the look-ahead catches
+        let mut input = vec![Stereo::ZERO; 100];
+        input.extend(sine(2.0, 4800));
+        let output = process(&mut limiter, input);
+        // The samples stay below the ceiling by as much as the peaks between them are louder
+        let expected = ceiling * std::f32::consts::FRAC_1_SQRT_2;
+        assert!(peak(&output) <= expected * 1.02, "{}", peak(&output));
+        assert!(peak(&output[2400..]) >= expected * 0.95);
+        let reduction = limiter.gain_reduction.load(Ordering::Relaxed);
+        assert!((reduction - 7.0).abs() < 0.2, "{reduction}");
+
+        // Quiet signals come back up after the release
+        let output = process(&mut limiter, sine(0.1, 48000));
+        assert!((peak(&output[24000..]) - 0.1 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        // The reduction is the most of each block
+        process(&mut limiter, sine(0.1, 480));
+        assert!(limiter.gain_reduction.load(Ordering::Relaxed) < 0.01);
+    }
+}
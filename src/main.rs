@@ -1,6 +1,8 @@
 extern crate anyhow;
 
 use std::{
+    env,
+    io::{self, Write},
     sync::mpsc::{self, Receiver},
     thread,
     time::Duration,
@@ -8,16 +10,17 @@ use std::{
 
 use anyhow::{anyhow, Result};
 use assert_no_alloc::*;
-use camino::Utf8PathBuf;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use camino::{Utf8Path, Utf8PathBuf};
 use ratatui::crossterm::event::{self, Event, KeyEventKind};
 use ratatui::DefaultTerminal;
 use triple_buffer::Output;
 
-use unsound::app::{self, App, AppState, EngineState, Msg, TrackType};
-use unsound::audio::Stereo;
-use unsound::engine::{Engine, MAIN_OUTPUT, MASTER_TRACK};
+use unsound::app::{self, App, EngineState, Msg, TrackType};
+use unsound::autosave::{self, Autosave, Recovery};
+use unsound::backend::{self, AudioConfig, Processor, BACKENDS};
+use unsound::engine::{MAIN_OUTPUT, MASTER_TRACK};
 use unsound::input;
+use unsound::project;
 use unsound::view::{self, View};
 
 #[cfg(debug_assertions)]
@@ -34,8 +37,80 @@ fn main() {
 }
 
 fn run() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut audio = String::from("cpal");
+    let mut config = AudioConfig::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{arg} requires a value"));
+        match arg.as_str() {
+            "--audio" => audio = value()?,
+            "--buffer-size" => config.buffer_size = Some(value()?.parse()?),
+            "--channels" => config.channels = backend::parse_channels(&value()?)?,
+            "--sample-rate" => config.sample_rate = value()?.parse()?,
+            _ => {
+                return Err(anyhow!(
+                    "usage: unsound [--audio {BACKENDS}] [--buffer-size <frames>] \
+                     [--channels <left>,<right>] [--sample-rate <hz>]"
+                ))
+            }
+        }
+    }
+    let mut backend = backend::new(&audio)?;
+
     let (mut app, app_state, engine, engine_state) = app::new()?;
+    // The backend sets up the engine at this rate when it's started
+    app.send(Msg::SetSampleRate(config.sample_rate))?;
+
+    let recovery_dir = Utf8Path::new(autosave::RECOVERY_DIR);
+    let mut restored = false;
+    if let Some(recovery) = autosave::recover(recovery_dir) {
+        if confirm_restore()? {
+            match restore(&mut app, recovery, recovery_dir) {
+                Ok(()) => restored = true,
+                Err(err) => eprintln!("error: unable to restore project: {:?}", err),
+            }
+        }
+    }
+    if !restored {
+        create_default_project(&mut app)?;
+    }
+    let mut autosave = Autosave::start(recovery_dir, autosave::INTERVAL)?;
+
+    backend.start(Processor::new(engine, app_state), config)?;
+    app.set_audio_backend(backend);
+
+    let terminal = ratatui::init();
+
+    let result = run_app(&mut app, &mut autosave, engine_state, terminal);
+    ratatui::restore();
+    match &result {
+        Ok(()) => autosave.finish()?,
+        // Save what we can, the recovery files are kept for the next start
+        Err(_) => autosave.save(&app),
+    }
+    result
+}
 
+fn confirm_restore() -> Result<bool> {
+    print!("The last session didn't shut down cleanly. Restore the autosaved project? [Y/n] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(!answer.trim().eq_ignore_ascii_case("n"))
+}
+
+fn restore(app: &mut App, recovery: Recovery, recovery_dir: &Utf8Path) -> Result<()> {
+    // LoadProject only shows its errors, but a project that can't be restored has to fall back
+    // to the default one
+    app.load_project(project::load(&recovery.project)?)?;
+    app.send(Msg::Noop)?;
+    // Starting the new session removes the recovery files
+    app.embed_sounds(recovery_dir)?;
+    app.project_path = recovery.project_path;
+    Ok(())
+}
+
+fn create_default_project(app: &mut App) -> Result<()> {
     app.send(Msg::CreateTrack(
         MASTER_TRACK,
         MAIN_OUTPUT,
@@ -66,53 +141,13 @@ fn run() -> Result<()> {
     for _ in 0..8 {
         app.send(Msg::CreatePattern(None))?
     }
-
-    let stream = run_audio(app_state, engine)?;
-    stream.play()?;
-
-    let terminal = ratatui::init();
-
-    let result = run_app(app, engine_state, terminal);
-    ratatui::restore();
-    result
-}
-
-fn run_audio(mut app_state: Output<AppState>, mut engine: Engine) -> Result<cpal::Stream> {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .ok_or_else(|| anyhow!("can't find output device"))?;
-
-    let mut config = device.default_output_config()?.config();
-    config.sample_rate = cpal::SampleRate(unsound::SAMPLE_RATE as u32);
-    config.buffer_size = cpal::BufferSize::Fixed(unsound::FRAMES_PER_BUFFER as u32);
-    config.channels = 2;
-
-    let mut buf = [Stereo::ZERO; unsound::INTERNAL_BUFFER_SIZE];
-    let stream = device.build_output_stream(
-        &config,
-        move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            assert_no_alloc(|| {
-                let buf_size = output.len() / 2;
-                engine.process(app_state.read(), &mut buf[..buf_size]);
-                let mut i = 0;
-                for frame in &mut buf[..buf_size] {
-                    output[i] = frame.channel(0);
-                    output[i + 1] = frame.channel(1);
-                    i += 2;
-                    *frame = Stereo::ZERO;
-                }
-            });
-        },
-        move |err| eprintln!("error while processing audio {}", err),
-        None,
-    )?;
-
-    Ok(stream)
+    app.clear_history();
+    Ok(())
 }
 
 fn run_app(
-    mut app: App,
+    app: &mut App,
+    autosave: &mut Autosave,
     mut engine_state_handle: Output<EngineState>,
     mut terminal: DefaultTerminal,
 ) -> Result<()> {
@@ -122,12 +157,12 @@ fn run_app(
     loop {
         let engine_state = engine_state_handle.read();
         app.engine_state.clone_from(engine_state);
-        terminal.draw(|f| view::render(&app, &mut view, f))?;
+        terminal.draw(|f| view::render(app, &mut view, f))?;
 
         match input.recv()? {
             Input::Event(event) => match event {
                 Event::Key(event) if event.kind == KeyEventKind::Press => {
-                    let msg = input::handle_key_event(&app, &mut view, event);
+                    let msg = input::handle_key_event(app, &mut view, event);
                     if msg.is_exit() {
                         return Ok(());
                     }
@@ -135,7 +170,7 @@ fn run_app(
                 }
                 _ => {}
             },
-            Input::Tick => {}
+            Input::Tick => autosave.tick(app),
         }
     }
 }
@@ -0,0 +1,361 @@
+//! Import and export of Standard MIDI Files.
+//!
+//! On import, notes are quantized to lines, with the remainder stored in the offset (`O`) effect and the
+//! velocity in the velocity (`V`) effect. Every MIDI channel gets its own tracks. Overlapping
+//! notes on a channel are spread over extra tracks, because a step holds a single note.
+//!
+//! Exporting writes the compiled song, which has chords and offsets already resolved, with a MIDI
+//! tick for every engine tick.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::app::{AppState, Track};
+use crate::engine::{Note as EngineNote, MAX_INSTRUMENTS, TICKS_PER_LINE};
+use crate::pattern::{Pattern, Step, DEFAULT_VELOCITY, MAX_PITCH, NOTE_OFF};
+
+const BEATS_PER_PATTERN: usize = 16;
+const MAX_LINES_PER_PATTERN: usize = 256;
+/// Channel 10 in General MIDI is reserved for drums, which play until the end of the sample.
+const DRUM_CHANNEL: u8 = 9;
+
+pub struct Import {
+    pub patterns: Vec<Pattern>,
+    /// The MIDI channel of each track in the imported patterns
+    pub channels: Vec<u8>,
+}
+
+/// Reads a MIDI file into patterns. Notes use the instrument with the same index as the track
+/// they end up in, where the first track of the import goes to `first_track`.
+pub fn import(path: &Utf8Path, lines_per_beat: u16, first_track: usize) -> Result<Import> {
+    let data = std::fs::read(path)?;
+    let smf = Smf::parse(&data)?;
+    import_smf(&smf, lines_per_beat, first_track)
+}
+
+struct Note {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    start: u64,
+    end: Option<u64>,
+}
+
+pub fn import_smf(smf: &Smf, lines_per_beat: u16, first_track: usize) -> Result<Import> {
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int() as u64,
+        Timing::Timecode(..) => return Err(anyhow!("timecode based MIDI files are not supported")),
+    };
+    if ticks_per_beat == 0 {
+        return Err(anyhow!("invalid MIDI timing"));
+    }
+    if smf.header.format == Format::Sequential {
+        return Err(anyhow!("sequential MIDI files are not supported"));
+    }
+    let ticks_per_line = TICKS_PER_LINE as u64;
+    let to_ticks = |time: u64| {
+        (time * lines_per_beat as u64 * ticks_per_line + ticks_per_beat / 2) / ticks_per_beat
+    };
+
+    let mut notes: Vec<Note> = Vec::new();
+    for track in &smf.tracks {
+        let mut time = 0;
+        let mut playing: BTreeMap<(u8, u8), usize> = BTreeMap::new();
+        for event in track {
+            time += event.delta.as_int() as u64;
+            let (channel, message) = match event.kind {
+                TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+                _ => continue,
+            };
+            let (key, velocity) = match message {
+                MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int()),
+                MidiMessage::NoteOff { key, .. } => (key.as_int(), 0),
+                _ => continue,
+            };
+            if let Some(idx) = playing.remove(&(channel, key)) {
+                notes[idx].end = Some(to_ticks(time));
+            }
+            if velocity > 0 && key < MAX_PITCH {
+                playing.insert((channel, key), notes.len());
+                notes.push(Note {
+                    channel,
+                    key,
+                    velocity,
+                    start: to_ticks(time),
+                    end: None,
+                });
+            }
+        }
+    }
+    if notes.is_empty() {
+        return Err(anyhow!("no notes found"));
+    }
+    notes.sort_by_key(|n| (n.start, n.channel, n.key));
+
+    // Assign every note to a track of its channel that isn't playing anything else at that line.
+    // Note offs are only written to lines that are still empty, so a note that starts on the
+    // same line as the end of the previous one takes precedence.
+    let lines_per_pattern = usize::min(
+        BEATS_PER_PATTERN * lines_per_beat as usize,
+        MAX_LINES_PER_PATTERN,
+    )
+    .max(1);
+    let mut channels: Vec<u8> = Vec::new();
+    // Track index and first line at which a new note can start
+    let mut voices: BTreeMap<u8, Vec<(usize, usize)>> = BTreeMap::new();
+    let mut steps: BTreeMap<(usize, usize), Step> = BTreeMap::new();
+    let mut num_lines = 0;
+    for note in &notes {
+        let line = (note.start / ticks_per_line) as usize;
+        let is_drum = note.channel == DRUM_CHANNEL;
+        let end = note
+            .end
+            .filter(|end| !is_drum && end / ticks_per_line > line as u64);
+
+        let voices = voices.entry(note.channel).or_default();
+        let voice = match voices.iter_mut().find(|(_, free)| *free <= line) {
+            Some(voice) => voice,
+            None => {
+                voices.push((channels.len(), 0));
+                channels.push(note.channel);
+                voices.last_mut().unwrap()
+            }
+        };
+        voice.1 = match end {
+            Some(end) => (end / ticks_per_line) as usize,
+            // A note that doesn't end blocks the track until the end of the song
+            None if note.end.is_none() && !is_drum => usize::MAX,
+            None => line + 1,
+        };
+        let track = voice.0;
+
+        let offset = (note.start % ticks_per_line) as u8;
+        let instrument = first_track + track;
+        steps.insert(
+            (track, line),
+            note_step(instrument, note.key, note.velocity, offset),
+        );
+        num_lines = usize::max(num_lines, line + 1);
+
+        if let Some(end) = end {
+            let end_line = (end / ticks_per_line) as usize;
+            let offset = (end % ticks_per_line) as u8;
+            steps
+                .entry((track, end_line))
+                .or_insert_with(|| note_off_step(offset));
+            num_lines = usize::max(num_lines, end_line + 1);
+        }
+    }
+
+    let num_patterns = num_lines.div_ceil(lines_per_pattern);
+    let mut patterns: Vec<Pattern> = (0..num_patterns)
+        .map(|_| {
+            let mut pattern = Pattern::new(channels.len());
+            pattern.set_len(lines_per_pattern);
+            pattern
+        })
+        .collect();
+    for ((track, line), step) in steps {
+        let pattern = &mut patterns[line / lines_per_pattern];
+        pattern.tracks[track].steps[line % lines_per_pattern] = step;
+    }
+
+    Ok(Import { patterns, channels })
+}
+
+fn note_step(instrument: usize, key: u8, velocity: u8, offset: u8) -> Step {
+    let instrument = Some(instrument)
+        .filter(|i| *i < MAX_INSTRUMENTS)
+        .map(|i| i as u8);
+    let velocity = Some(velocity).filter(|v| *v != DEFAULT_VELOCITY);
+    let offset = Some(offset).filter(|o| *o > 0);
+    Step::with_cells([
+        Some(key),
+        instrument,
+        velocity.map(|_| b'V'),
+        velocity,
+        offset.map(|_| b'O'),
+        offset,
+    ])
+}
+
+fn note_off_step(offset: u8) -> Step {
+    let offset = Some(offset).filter(|o| *o > 0);
+    Step::with_cells([
+        Some(NOTE_OFF),
+        None,
+        None,
+        None,
+        offset.map(|_| b'O'),
+        offset,
+    ])
+}
+
+/// Writes the song as a type 1 MIDI file, with a tempo track followed by a track for every track in
+/// the project.
+pub fn export(path: &Utf8Path, state: &AppState, tracks: &[Track]) -> Result<()> {
+    let names: Vec<String> = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| track.name.clone().unwrap_or_else(|| format!("Track {i}")))
+        .collect();
+    let smf = song_to_smf(state, tracks, &names)?;
+    smf.save(path)?;
+    Ok(())
+}
+
+fn song_to_smf<'a>(state: &AppState, tracks: &[Track], names: &'a [String]) -> Result<Smf<'a>> {
+    let ticks_per_beat = state.lines_per_beat as usize * TICKS_PER_LINE;
+    if ticks_per_beat > u15::max_value().as_int() as usize {
+        return Err(anyhow!("lines per beat is too large for a MIDI file"));
+    }
+    let timing = Timing::Metrical(u15::new(ticks_per_beat as u16));
+    let mut smf = Smf::new(Header::new(Format::Parallel, timing));
+
+    let tempo = (60_000_000 / u32::max(1, state.bpm as u32)).min(u24::max_value().as_int());
+    smf.tracks.push(vec![
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo))),
+        },
+        end_of_track(0),
+    ]);
+
+    for (i, (track, name)) in tracks.iter().zip(names).enumerate() {
+        let channel = u4::new((i % 16) as u8);
+        let mut events = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+        }];
+        // Like the engine, a new note or note off stops every note still playing on the track
+        let mut playing: Vec<u8> = Vec::new();
+        let mut playing_since = 0;
+        let mut last_event = 0;
+        let mut push = |time: usize, message: MidiMessage| {
+            events.push(TrackEvent {
+                delta: u28::new((time - last_event) as u32),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+            last_event = time;
+        };
+
+        let mut pattern_start = 0;
+        for id in &state.song {
+            let Some(pattern) = state.patterns.get(id) else {
+                continue;
+            };
+            for event in &pattern.events {
+                if event.track_index != track.node_index {
+                    continue;
+                }
+                let time = pattern_start + event.offset;
+                if time != playing_since || matches!(event.note, EngineNote::Off) {
+                    for key in playing.drain(..) {
+                        push(time, note_off(key));
+                    }
+                }
+                if let EngineNote::On(pitch, velocity) = event.note {
+                    let key = u7::new(pitch.min(127));
+                    let vel = u7::new(velocity.clamp(1, 127));
+                    push(time, MidiMessage::NoteOn { key, vel });
+                    playing.push(pitch.min(127));
+                    playing_since = time;
+                }
+            }
+            pattern_start += pattern.length;
+        }
+        for key in playing.drain(..) {
+            push(pattern_start, note_off(key));
+        }
+        let delta = pattern_start.saturating_sub(last_event);
+        events.push(end_of_track(delta as u32));
+        smf.tracks.push(events);
+    }
+
+    Ok(smf)
+}
+
+fn note_off(key: u8) -> MidiMessage {
+    MidiMessage::NoteOff {
+        key: u7::new(key),
+        vel: u7::new(0),
+    }
+}
+
+fn end_of_track(delta: u32) -> TrackEvent<'static> {
+    TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+        let message = MidiMessage::NoteOn {
+            key: u7::new(key),
+            vel: u7::new(vel),
+        };
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Midi {
+                channel: u4::new(channel),
+                message,
+            },
+        }
+    }
+
+    #[test]
+    fn quantizes_notes_to_lines() -> Result<()> {
+        let header = Header::new(Format::Parallel, Timing::Metrical(u15::new(96)));
+        let mut smf = Smf::new(header);
+        smf.tracks.push(vec![
+            note(0, 0, 48, 100),
+            // A sixteenth note later, which is one line at 4 lines per beat
+            note(24, 0, 48, 0),
+            note(0, 0, 50, 64),
+            // Overlaps with the previous note, so it goes on a second track
+            note(6, 0, 52, 100),
+            note(70, 0, 50, 0),
+            note(0, 0, 52, 0),
+            // Starts in the second pattern
+            note(96 * 16, 1, 36, 100),
+        ]);
+
+        let import = import_smf(&smf, 4, 0)?;
+        assert_eq!(2, import.patterns.len());
+        assert_eq!(vec![0, 0, 1], import.channels);
+
+        let pattern = &import.patterns[0];
+        assert_eq!(64, pattern.len());
+        let steps = pattern.steps(0);
+        assert_eq!(Some(48), steps[0].pitch());
+        assert_eq!(Some(50), steps[1].pitch());
+        assert_eq!(64, steps[1].velocity());
+        assert_eq!(Some(NOTE_OFF), steps[4].pitch());
+
+        let steps = pattern.steps(1);
+        assert_eq!(Some(52), steps[1].pitch());
+        assert_eq!(Some(1), steps[1].instrument());
+        assert_eq!(Some(3), steps[1].offset());
+        assert_eq!(Some(NOTE_OFF), steps[4].pitch());
+
+        let steps = import.patterns[1].steps(2);
+        assert_eq!(Some(36), steps[4].pitch());
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_past_the_last_instrument() {
+        let step = note_step(MAX_INSTRUMENTS - 1, 48, DEFAULT_VELOCITY, 0);
+        assert_eq!(Some(MAX_INSTRUMENTS as u8 - 1), step.instrument());
+        // Would wrap around to the first instrument as a u8
+        assert_eq!(None, note_step(256, 48, DEFAULT_VELOCITY, 0).instrument());
+    }
+}
@@ -7,6 +7,13 @@ use std::sync::Arc;
 pub trait Params {
     fn get_param(&self, index: usize) -> &Param;
     fn len(&self) -> usize;
+
+    /// Recomputes the smoothing of all parameters for a new sample rate
+    fn set_sample_rate(&self, sample_rate: f64) {
+        for i in 0..self.len() {
+            self.get_param(i).set_sample_rate(sample_rate);
+        }
+    }
 }
 
 pub struct Param {
@@ -81,6 +88,10 @@ impl Param {
     pub fn as_bool(&self) -> bool {
         self.target() == self.info.true_value
     }
+
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        self.info.smoothing.set_sample_rate(sample_rate);
+    }
 }
 
 pub struct ParamInfo {
@@ -156,6 +167,19 @@ pub fn db_to_amp(db: f64) -> f64 {
     f64::powf(10.0, db / 20.0)
 }
 
+/// Maps a value from 0 to 1 onto `min..max` on a logarithmic scale, e.g. for frequencies
+pub fn map_log(v: f64, min: f64, max: f64) -> f64 {
+    min * (max / min).powf(v)
+}
+
+pub fn format_hz(hz: f64) -> String {
+    if hz >= 1000.0 {
+        format!("{:.2}kHz", hz / 1000.0)
+    } else {
+        format!("{:.0}Hz", hz)
+    }
+}
+
 fn format_default(v: f64) -> String {
     format!("{:.2}", v)
 }
@@ -165,24 +189,37 @@ pub fn format_millis(v: f64) -> String {
 }
 
 pub enum Smoothing {
-    Exp(f64),
+    /// Exponential smoothing over a time in milliseconds. The rate per sample depends on the
+    /// sample rate, so it's atomic to be updated when that changes.
+    Exp {
+        ms: f64,
+        rate: AtomicF64,
+    },
     None,
 }
 
 impl Smoothing {
     pub fn exp(ms: f64, sample_rate: f64) -> Self {
-        let num_samples = (sample_rate * ms / 1000.0).round();
-        let rate = 0.0001f64.powf(1.0 / num_samples);
-        Self::Exp(rate)
+        Self::Exp {
+            ms,
+            rate: AtomicF64::new(exp_rate(ms, sample_rate)),
+        }
+    }
+
+    pub fn exp_default(sample_rate: f64) -> Self {
+        Self::exp(5.0, sample_rate)
     }
 
-    pub fn exp_default() -> Self {
-        Self::exp(5.0, crate::SAMPLE_RATE)
+    fn set_sample_rate(&self, sample_rate: f64) {
+        if let Self::Exp { ms, rate } = self {
+            rate.store(exp_rate(*ms, sample_rate), Ordering::Relaxed);
+        }
     }
 
     fn next(&self, current: f64, target: f64) -> f64 {
         match self {
-            Self::Exp(rate) => {
+            Self::Exp { rate, .. } => {
+                let rate = rate.load(Ordering::Relaxed);
                 let mut current = rate * current + (1.0 - rate) * target;
                 if (target - current).abs() < 0.0001 {
                     current = target;
@@ -194,6 +231,11 @@ impl Smoothing {
     }
 }
 
+fn exp_rate(ms: f64, sample_rate: f64) -> f64 {
+    let num_samples = (sample_rate * ms / 1000.0).round();
+    0.0001f64.powf(1.0 / num_samples)
+}
+
 pub struct ParamIter<'a> {
     current: usize,
     params: &'a Arc<dyn Params>,
@@ -267,4 +309,25 @@ mod tests {
         }
         assert_eq!(target, previous);
     }
+
+    #[test]
+    fn test_smoothing_sample_rate() {
+        let time = 1.0;
+        let param = Param::new(
+            0.0,
+            ParamInfo::new("Test", 0.0, 100.0).with_smoothing(Smoothing::exp(time, 44100.0)),
+        );
+        // Twice the sample rate takes twice the number of samples to reach the target
+        param.set_sample_rate(88200.0);
+        param.set(1.0);
+        let samples = (88200.0 * time / 1000.0) as usize;
+        for _ in 0..samples / 2 {
+            param.value();
+        }
+        assert!(param.value() < 0.999);
+        for _ in samples / 2 + 1..samples {
+            param.value();
+        }
+        assert_eq!(1.0, param.value());
+    }
 }
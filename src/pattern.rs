@@ -82,7 +82,7 @@ impl Rect {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Pattern {
     pub color: Color,
     pub tracks: Vec<Track>,
@@ -100,8 +100,14 @@ impl Pattern {
         }
     }
 
-    pub fn delete_track(&mut self, idx: usize) {
-        self.tracks.remove(idx);
+    pub fn delete_track(&mut self, idx: usize) -> Track {
+        self.tracks.remove(idx)
+    }
+
+    /// Puts back a track that was removed with [`Pattern::delete_track`]
+    pub fn insert_track(&mut self, idx: usize, track: Track) {
+        self.tracks
+            .insert(usize::min(idx, self.tracks.len()), track);
     }
 
     pub fn add_track(&mut self, idx: usize) {
@@ -155,8 +161,15 @@ impl Pattern {
                 let pitch = key_to_pitch(octave, key);
                 if let Some(p) = pitch {
                     if p != NOTE_OFF && step.instrument().is_none() {
+                        // Notes need an instrument that fits into a step
+                        let Some(instr) = u8::try_from(instr)
+                            .ok()
+                            .filter(|i| (*i as usize) < MAX_INSTRUMENTS)
+                        else {
+                            return;
+                        };
                         let instr_pos = pos + Position::new(pos.line, pos.column + 1);
-                        step.set(instr_pos.input(), instr as u8);
+                        step.set(instr_pos.input(), instr);
                     }
                 }
                 pitch
@@ -174,7 +187,7 @@ impl Pattern {
                 (None, Some(d)) => Some(d as u8),
                 _ => None,
             },
-            _ => Some(key as u8),
+            _ => u8::try_from(key).ok(),
         };
 
         if let Some(val) = val {
@@ -228,7 +241,7 @@ impl Pattern {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Track {
     pub steps: Vec<Step>,
 }
@@ -261,12 +274,37 @@ enum InputKind {
     EffectVal,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Step {
     cells: [Option<u8>; INPUTS_PER_STEP],
 }
 
 impl Step {
+    /// Creates a step from raw cell values. Values that can't be entered in the editor are
+    /// dropped.
+    pub fn with_cells(cells: [Option<u8>; INPUTS_PER_STEP]) -> Self {
+        let mut step = Step::default();
+        for (column, cell) in cells.into_iter().enumerate() {
+            if let Some(val) = cell {
+                step.set(Position::new(0, column).input(), val);
+            }
+        }
+        step
+    }
+
+    pub fn cells(&self) -> &[Option<u8>; INPUTS_PER_STEP] {
+        &self.cells
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.iter().all(|c| c.is_none())
+    }
+
+    /// Returns whether the cell at `idx` holds an effect command character
+    pub fn is_effect_cmd(idx: usize) -> bool {
+        idx == FX_CMD1 || idx == FX_CMD2
+    }
+
     fn incr(&mut self, input: Input, step_size: StepSize) {
         let step = step_size.for_input(input);
         if let Some(v) = self.cell(input.idx) {
@@ -548,4 +586,19 @@ mod tests {
         let s = Selection::new(Position::new(0, 0), Position::new(0, 11));
         p1.copy(Position::new(0, 6), &p2, &s);
     }
+
+    #[test]
+    fn instruments_that_dont_fit_a_step() {
+        let mut p = Pattern::new(1);
+        p.set_len(4);
+        p.handle_input(Position::new(0, 0), 4, 'z', MAX_INSTRUMENTS - 1);
+        assert_eq!(Some(MAX_INSTRUMENTS as u8 - 1), p.steps(0)[0].instrument());
+
+        p.handle_input(Position::new(1, 0), 4, 'z', MAX_INSTRUMENTS);
+        p.handle_input(Position::new(2, 0), 4, 'z', 256);
+        for step in &p.steps(0)[1..3] {
+            assert_eq!(None, step.pitch());
+            assert_eq!(None, step.instrument());
+        }
+    }
 }
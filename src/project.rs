@@ -0,0 +1,727 @@
+//! On-disk project format.
+//!
+//! Projects are stored as line based text files. Every line starts with a keyword followed by
+//! whitespace separated arguments. Strings (names and paths) are written in double quotes with `\"`
+//! and `\\` as the only escapes. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! unsound-project 1
+//! bpm 120
+//! lines-per-beat 4
+//! octave 4
+//! loop 0 3
+//!
+//! instrument 0 sampler path "sounds/kick.wav"
+//! param 0 1
+//!
+//! track instrument output 1
+//! param 0 -6
+//! effect delay
+//! track bus output main name "Master"
+//!
+//! pattern 32 color 12 200 37
+//! step 0 0 48 0 V 100 - -
+//!
+//! song 0 0 1
+//! ```
+//!
+//! - `unsound-project <version>` must be the first line.
+//! - `instrument <slot> <device> [path <path>]` loads a device into an instrument slot.
+//! - `track <instrument|bus> output <track|main> [name <name>]` adds a track. Tracks are routed
+//!   by their index in the file, or to the main output.
+//! - `effect <device> [path <path>] [bypass 1] [sidechain <track>]` appends an effect to the most
+//!   recent track. Effects with a sidechain listen to the output of the track with that index.
+//! - `param <index> <value>` sets a parameter of the most recent instrument, track or effect.
+//! - `pattern <length> [color <r> <g> <b>]` adds a pattern. Patterns are numbered in file order.
+//! - `step <track> <line> <cells>` sets the six cells (pitch, instrument, two effect commands and
+//!   their values) of a step in the most recent pattern. Empty cells are written as `-` and effect
+//!   commands as their letter.
+//! - `song <pattern>...` lists the song order.
+//!
+//! When the format changes, [`VERSION`] is increased and a migration is added to `MIGRATIONS`, so
+//! older projects are upgraded line by line before they are parsed.
+
+use std::fmt::Write;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use ratatui::style::Color;
+
+use crate::app::TrackType;
+use crate::pattern::{Pattern, Step, INPUTS_PER_STEP};
+
+pub const VERSION: u32 = 1;
+
+const HEADER: &str = "unsound-project";
+
+type Migration = fn(Vec<Line>) -> Result<Vec<Line>>;
+
+/// Upgrades a project from version `i + 1` to `i + 2`.
+const MIGRATIONS: &[Migration] = &[];
+
+pub struct Project {
+    pub bpm: u16,
+    pub lines_per_beat: u16,
+    pub octave: u16,
+    pub loop_range: Option<(usize, usize)>,
+    pub instruments: Vec<(usize, DeviceData)>,
+    pub tracks: Vec<TrackData>,
+    pub patterns: Vec<Pattern>,
+    /// Song order as indices into `patterns`
+    pub song: Vec<usize>,
+}
+
+pub struct TrackData {
+    pub track_type: TrackType,
+    pub name: Option<String>,
+    /// Index of the track this track is routed to, or `None` for the main output
+    pub output: Option<usize>,
+    pub params: Vec<f64>,
+    pub effects: Vec<DeviceData>,
+}
+
+#[derive(Clone)]
+pub struct DeviceData {
+    pub id: String,
+    pub path: Option<Utf8PathBuf>,
+    pub params: Vec<f64>,
+    pub bypass: bool,
+    /// Index of the track that the effect's sidechain listens to
+    pub sidechain: Option<usize>,
+}
+
+impl DeviceData {
+    pub fn new(id: &str, path: Option<Utf8PathBuf>) -> Self {
+        Self {
+            id: String::from(id),
+            path,
+            params: Vec::new(),
+            bypass: false,
+            sidechain: None,
+        }
+    }
+}
+
+pub fn save(path: &Utf8Path, project: &Project) -> Result<()> {
+    fs::write(path, project.serialize()).with_context(|| format!("unable to write {path}"))
+}
+
+pub fn load(path: &Utf8Path) -> Result<Project> {
+    let input = fs::read_to_string(path).with_context(|| format!("unable to read {path}"))?;
+    Project::parse(&input).with_context(|| format!("unable to load {path}"))
+}
+
+impl Project {
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String can't fail
+        let _ = self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "{HEADER} {VERSION}")?;
+        writeln!(out, "bpm {}", self.bpm)?;
+        writeln!(out, "lines-per-beat {}", self.lines_per_beat)?;
+        writeln!(out, "octave {}", self.octave)?;
+        if let Some((start, end)) = self.loop_range {
+            writeln!(out, "loop {start} {end}")?;
+        }
+
+        for (slot, device) in &self.instruments {
+            writeln!(out)?;
+            write!(out, "instrument {slot} ")?;
+            write_device(out, device)?;
+        }
+
+        for track in &self.tracks {
+            writeln!(out)?;
+            let track_type = match track.track_type {
+                TrackType::Instrument => "instrument",
+                TrackType::Bus => "bus",
+            };
+            write!(out, "track {track_type} output ")?;
+            match track.output {
+                Some(output) => write!(out, "{output}")?,
+                None => write!(out, "main")?,
+            }
+            if let Some(name) = &track.name {
+                write!(out, " name {}", quote(name))?;
+            }
+            writeln!(out)?;
+            write_params(out, &track.params)?;
+            for effect in &track.effects {
+                write!(out, "effect ")?;
+                write_device(out, effect)?;
+            }
+        }
+
+        for pattern in &self.patterns {
+            writeln!(out)?;
+            write!(out, "pattern {}", pattern.len())?;
+            if let Color::Rgb(r, g, b) = pattern.color {
+                write!(out, " color {r} {g} {b}")?;
+            }
+            writeln!(out)?;
+            for (i, track) in pattern.tracks.iter().enumerate() {
+                for (line, step) in track.steps.iter().enumerate() {
+                    if step.is_empty() {
+                        continue;
+                    }
+                    write!(out, "step {i} {line}")?;
+                    for (idx, cell) in step.cells().iter().enumerate() {
+                        match cell {
+                            Some(v) if Step::is_effect_cmd(idx) => write!(out, " {}", *v as char)?,
+                            Some(v) => write!(out, " {v}")?,
+                            None => write!(out, " -")?,
+                        }
+                    }
+                    writeln!(out)?;
+                }
+            }
+        }
+
+        writeln!(out)?;
+        write!(out, "song")?;
+        for idx in &self.song {
+            write!(out, " {idx}")?;
+        }
+        writeln!(out)
+    }
+
+    pub fn parse(input: &str) -> Result<Project> {
+        let mut lines = Vec::new();
+        for (i, text) in input.lines().enumerate() {
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let tokens = tokenize(text).map_err(|err| anyhow!("line {}: {err}", i + 1))?;
+            lines.push(Line {
+                number: i + 1,
+                tokens,
+            });
+        }
+
+        let Some(header) = lines.first() else {
+            return Err(anyhow!("empty project file"));
+        };
+        let version = match header.tokens.as_slice() {
+            [header, version] if header == HEADER => version
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid project version {version}"))?,
+            _ => return Err(anyhow!("not an unsound project")),
+        };
+        if version == 0 || version > VERSION {
+            return Err(anyhow!("unsupported project version {version}"));
+        }
+        lines.remove(0);
+
+        for migrate in &MIGRATIONS[version as usize - 1..] {
+            lines = migrate(lines)?;
+        }
+
+        let mut parser = Parser::new();
+        for line in &lines {
+            parser
+                .parse_line(line)
+                .map_err(|err| anyhow!("line {}: {err}", line.number))?;
+        }
+        parser.finish()
+    }
+
+    /// Checks that tracks are routed to and listen to existing tracks, and that no sidechain
+    /// listens to a track that needs the output of the sidechain's own track
+    pub fn check_routing(&self) -> Result<()> {
+        let num_tracks = self.tracks.len();
+        for (i, track) in self.tracks.iter().enumerate() {
+            if let Some(output) = track.output {
+                if output == i || output >= num_tracks {
+                    return Err(anyhow!("invalid output {output} for track {i}"));
+                }
+            }
+            let mut sources = track.effects.iter().filter_map(|e| e.sidechain);
+            if let Some(source) = sources.find(|source| *source >= num_tracks) {
+                return Err(anyhow!("invalid sidechain {source} for track {i}"));
+            }
+        }
+        // Every source is checked the way the app checks a new sidechain, so no track ends up
+        // waiting for its own output
+        let mut routing = Routing::new(num_tracks);
+        for (i, track) in self.tracks.iter().enumerate() {
+            if let Some(output) = track.output {
+                routing.add_input(output, i);
+            }
+            for source in track.effects.iter().filter_map(|e| e.sidechain) {
+                routing.add_input(i, source);
+            }
+        }
+        for (i, track) in self.tracks.iter().enumerate() {
+            for source in track.effects.iter().filter_map(|e| e.sidechain) {
+                if !routing.can_sidechain(i, source) {
+                    return Err(anyhow!("invalid sidechain {source} for track {i}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which tracks have to be processed before each track, by track index: the tracks routed to it
+/// and the tracks its sidechains listen to
+pub struct Routing {
+    inputs: Vec<Vec<usize>>,
+}
+
+impl Routing {
+    pub fn new(num_tracks: usize) -> Self {
+        Self {
+            inputs: vec![Vec::new(); num_tracks],
+        }
+    }
+
+    /// Makes track `idx` wait for the output of track `input`
+    pub fn add_input(&mut self, idx: usize, input: usize) {
+        let inputs = &mut self.inputs[idx];
+        if let Err(pos) = inputs.binary_search(&input) {
+            inputs.insert(pos, input);
+        }
+    }
+
+    /// Returns whether an effect on track `idx` can listen to track `source`. The source is
+    /// processed first, so it can't be a track that depends on the output of the effect's track.
+    pub fn can_sidechain(&self, idx: usize, source: usize) -> bool {
+        source < self.inputs.len() && source != idx && !self.depends_on(source, idx)
+    }
+
+    /// Returns whether track `a` needs the output of track `b`, directly or through its inputs
+    pub fn depends_on(&self, a: usize, b: usize) -> bool {
+        let mut visited = vec![false; self.inputs.len()];
+        let mut stack = vec![a];
+        while let Some(idx) = stack.pop() {
+            for &dep in &self.inputs[idx] {
+                if dep == b {
+                    return true;
+                }
+                if !visited[dep] {
+                    visited[dep] = true;
+                    stack.push(dep);
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the order in which tracks are processed. Tracks keep their order, except that a
+    /// track comes after all tracks it depends on.
+    pub fn order(&self) -> Vec<usize> {
+        fn visit(routing: &Routing, idx: usize, visited: &mut [bool], order: &mut Vec<usize>) {
+            // Tracks are marked before their inputs are visited, so a loop can't recurse forever
+            if visited[idx] {
+                return;
+            }
+            visited[idx] = true;
+            for &input in &routing.inputs[idx] {
+                visit(routing, input, visited, order);
+            }
+            order.push(idx);
+        }
+
+        let mut visited = vec![false; self.inputs.len()];
+        let mut order = Vec::with_capacity(self.inputs.len());
+        for idx in 0..self.inputs.len() {
+            visit(self, idx, &mut visited, &mut order);
+        }
+        order
+    }
+}
+
+fn write_device(out: &mut String, device: &DeviceData) -> std::fmt::Result {
+    write!(out, "{}", device.id)?;
+    if let Some(path) = &device.path {
+        write!(out, " path {}", quote(path.as_str()))?;
+    }
+    if device.bypass {
+        write!(out, " bypass 1")?;
+    }
+    if let Some(track) = device.sidechain {
+        write!(out, " sidechain {track}")?;
+    }
+    writeln!(out)?;
+    write_params(out, &device.params)
+}
+
+fn write_params(out: &mut String, params: &[f64]) -> std::fmt::Result {
+    for (i, value) in params.iter().enumerate() {
+        writeln!(out, "param {i} {value}")?;
+    }
+    Ok(())
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A line of a project file split into tokens. Quotes are removed from string tokens.
+pub struct Line {
+    pub number: usize,
+    pub tokens: Vec<String>,
+}
+
+fn tokenize(line: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => token.push(c),
+                        None => return Err(anyhow!("unterminated string")),
+                    },
+                    Some(c) => token.push(c),
+                    None => return Err(anyhow!("unterminated string")),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+/// What `param` lines currently apply to
+enum ParamTarget {
+    None,
+    Instrument,
+    Track,
+    Effect,
+}
+
+struct Parser {
+    project: Project,
+    target: ParamTarget,
+}
+
+impl Parser {
+    fn new() -> Self {
+        Self {
+            project: Project {
+                bpm: 120,
+                lines_per_beat: 4,
+                octave: 4,
+                loop_range: None,
+                instruments: Vec::new(),
+                tracks: Vec::new(),
+                patterns: Vec::new(),
+                song: Vec::new(),
+            },
+            target: ParamTarget::None,
+        }
+    }
+
+    fn parse_line(&mut self, line: &Line) -> Result<()> {
+        let tokens: Vec<&str> = line.tokens.iter().map(|t| t.as_str()).collect();
+        let project = &mut self.project;
+        match tokens.as_slice() {
+            ["bpm", bpm] => project.bpm = parse(bpm)?,
+            ["lines-per-beat", lpb] => project.lines_per_beat = parse(lpb)?,
+            ["octave", oct] => project.octave = parse(oct)?,
+            ["loop", start, end] => project.loop_range = Some((parse(start)?, parse(end)?)),
+            ["instrument", slot, device @ ..] => {
+                let slot = parse(slot)?;
+                if project.instruments.iter().any(|(s, _)| *s == slot) {
+                    return Err(anyhow!("duplicate instrument {slot}"));
+                }
+                project.instruments.push((slot, parse_device(device)?));
+                self.target = ParamTarget::Instrument;
+            }
+            ["track", track_type, args @ ..] => {
+                let track_type = match *track_type {
+                    "instrument" => TrackType::Instrument,
+                    "bus" => TrackType::Bus,
+                    _ => return Err(anyhow!("invalid track type {track_type}")),
+                };
+                let mut output = None;
+                let mut name = None;
+                for (key, value) in pairs(args)? {
+                    match key {
+                        "output" if value == "main" => output = None,
+                        "output" => output = Some(parse(value)?),
+                        "name" => name = Some(String::from(value)),
+                        _ => return Err(anyhow!("unknown track option {key}")),
+                    }
+                }
+                project.tracks.push(TrackData {
+                    track_type,
+                    name,
+                    output,
+                    params: Vec::new(),
+                    effects: Vec::new(),
+                });
+                self.target = ParamTarget::Track;
+            }
+            ["effect", device @ ..] => {
+                let Some(track) = project.tracks.last_mut() else {
+                    return Err(anyhow!("effect without track"));
+                };
+                track.effects.push(parse_device(device)?);
+                self.target = ParamTarget::Effect;
+            }
+            ["param", idx, value] => {
+                let idx: usize = parse(idx)?;
+                let value = parse(value)?;
+                let params = match self.target {
+                    ParamTarget::Instrument => {
+                        project.instruments.last_mut().map(|(_, d)| &mut d.params)
+                    }
+                    ParamTarget::Track => project.tracks.last_mut().map(|t| &mut t.params),
+                    ParamTarget::Effect => project
+                        .tracks
+                        .last_mut()
+                        .and_then(|t| t.effects.last_mut())
+                        .map(|d| &mut d.params),
+                    ParamTarget::None => None,
+                };
+                let Some(params) = params else {
+                    return Err(anyhow!("param without device"));
+                };
+                if idx != params.len() {
+                    return Err(anyhow!("expected param {}, got {idx}", params.len()));
+                }
+                params.push(value);
+            }
+            ["pattern", len, args @ ..] => {
+                let num_tracks = project
+                    .tracks
+                    .iter()
+                    .filter(|t| matches!(t.track_type, TrackType::Instrument))
+                    .count();
+                if num_tracks == 0 {
+                    return Err(anyhow!("pattern without instrument tracks"));
+                }
+                let len = parse(len)?;
+                let mut pattern = Pattern::new(num_tracks);
+                pattern.set_len(len);
+                if len == 0 || pattern.len() != len {
+                    return Err(anyhow!("invalid pattern length {len}"));
+                }
+                match args {
+                    [] => {}
+                    ["color", r, g, b] => {
+                        pattern.color = Color::Rgb(parse(r)?, parse(g)?, parse(b)?)
+                    }
+                    _ => return Err(anyhow!("invalid pattern options")),
+                }
+                project.patterns.push(pattern);
+                self.target = ParamTarget::None;
+            }
+            ["step", track, line, cells @ ..] if cells.len() == INPUTS_PER_STEP => {
+                let Some(pattern) = project.patterns.last_mut() else {
+                    return Err(anyhow!("step without pattern"));
+                };
+                let (track, line): (usize, usize) = (parse(track)?, parse(line)?);
+                let Some(step) = pattern
+                    .tracks
+                    .get_mut(track)
+                    .and_then(|t| t.steps.get_mut(line))
+                else {
+                    return Err(anyhow!("step {track} {line} is outside of pattern"));
+                };
+                let mut values = [None; INPUTS_PER_STEP];
+                for (idx, cell) in cells.iter().enumerate() {
+                    values[idx] = match *cell {
+                        "-" => None,
+                        cmd if Step::is_effect_cmd(idx) => match cmd.as_bytes() {
+                            [c] => Some(*c),
+                            _ => return Err(anyhow!("invalid effect {cmd}")),
+                        },
+                        val => Some(parse(val)?),
+                    };
+                }
+                *step = Step::with_cells(values);
+            }
+            ["song", patterns @ ..] => {
+                project.song = patterns.iter().map(|p| parse(p)).collect::<Result<_>>()?;
+            }
+            [keyword, ..] => return Err(anyhow!("invalid {keyword} line")),
+            [] => {}
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Project> {
+        let project = self.project;
+        if project.song.is_empty() {
+            return Err(anyhow!("song is empty"));
+        }
+        if let Some(idx) = project.song.iter().find(|&&p| p >= project.patterns.len()) {
+            return Err(anyhow!("song refers to unknown pattern {idx}"));
+        }
+        if let Some((start, end)) = project.loop_range {
+            if start > end || end >= project.song.len() {
+                return Err(anyhow!("invalid loop range {start} {end}"));
+            }
+        }
+        if project.tracks.is_empty() {
+            return Err(anyhow!("project has no tracks"));
+        }
+        project.check_routing()?;
+        let num_tracks = project
+            .tracks
+            .iter()
+            .filter(|t| matches!(t.track_type, TrackType::Instrument))
+            .count();
+        if project
+            .patterns
+            .iter()
+            .any(|p| p.tracks.len() != num_tracks)
+        {
+            return Err(anyhow!("patterns must be defined after all tracks"));
+        }
+        Ok(project)
+    }
+}
+
+fn parse_device(tokens: &[&str]) -> Result<DeviceData> {
+    let [id, args @ ..] = tokens else {
+        return Err(anyhow!("missing device"));
+    };
+    let mut device = DeviceData::new(id, None);
+    for (key, value) in pairs(args)? {
+        match key {
+            "path" => device.path = Some(Utf8PathBuf::from(value)),
+            "bypass" => device.bypass = parse::<u8>(value)? != 0,
+            "sidechain" => device.sidechain = Some(parse(value)?),
+            _ => return Err(anyhow!("unknown device option {key}")),
+        }
+    }
+    Ok(device)
+}
+
+fn pairs<'a>(args: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>> {
+    let chunks = args.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return Err(anyhow!("expected key value pairs"));
+    }
+    Ok(chunks.map(|kv| (kv[0], kv[1])).collect())
+}
+
+fn parse<T: FromStr>(s: &str) -> Result<T> {
+    s.parse().map_err(|_| anyhow!("invalid value {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::Position;
+
+    fn project() -> Project {
+        let mut pattern = Pattern::new(1);
+        pattern.set_len(16);
+        pattern.handle_input(Position::default(), 4, 'z', 3);
+        pattern.color = Color::Rgb(1, 2, 3);
+
+        let mut delay = DeviceData::new("delay", None);
+        delay.params = vec![0.5];
+        delay.bypass = true;
+        let mut compressor = DeviceData::new("compressor", None);
+        compressor.sidechain = Some(0);
+        let mut sampler = DeviceData::new("sampler", Some("sounds/a \"b\".wav".into()));
+        sampler.params = vec![1.0, 200.0];
+
+        Project {
+            bpm: 140,
+            lines_per_beat: 8,
+            octave: 3,
+            loop_range: Some((0, 1)),
+            instruments: vec![(3, sampler)],
+            tracks: vec![
+                TrackData {
+                    track_type: TrackType::Instrument,
+                    name: None,
+                    output: Some(1),
+                    params: vec![-6.0, 1.0, 1.0],
+                    effects: vec![delay],
+                },
+                TrackData {
+                    track_type: TrackType::Bus,
+                    name: Some(String::from("Master")),
+                    output: None,
+                    params: vec![-3.5, 1.0, 1.0],
+                    effects: vec![compressor],
+                },
+            ],
+            patterns: vec![pattern],
+            song: vec![0, 0],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let project = project();
+        let text = project.serialize();
+        let parsed = Project::parse(&text).unwrap();
+        assert_eq!(text, parsed.serialize());
+
+        assert_eq!(140, parsed.bpm);
+        assert_eq!(Some((0, 1)), parsed.loop_range);
+        assert_eq!(vec![0, 0], parsed.song);
+        assert_eq!(
+            Some(Utf8PathBuf::from("sounds/a \"b\".wav")),
+            parsed.instruments[0].1.path
+        );
+        assert_eq!(Some(String::from("Master")), parsed.tracks[1].name);
+        assert!(parsed.tracks[0].effects[0].bypass);
+        assert_eq!(Some(0), parsed.tracks[1].effects[0].sidechain);
+        let step = &parsed.patterns[0].tracks[0].steps[0];
+        assert_eq!(Some(48), step.pitch());
+        assert_eq!(Some(3), step.instrument());
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let text = format!("{HEADER} {}\n", VERSION + 1);
+        assert!(Project::parse(&text).is_err());
+    }
+
+    #[test]
+    fn rejects_sidechain_loops() {
+        // The first track is routed to the master, so it can't listen to it
+        let mut project = project();
+        project.tracks[0].effects[0].sidechain = Some(1);
+        assert!(project.check_routing().is_err());
+        assert!(Project::parse(&project.serialize()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_steps() {
+        let text = project().serialize().replace("step 0 0", "step 0 16");
+        assert!(Project::parse(&text).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Offline rendering of a project to a WAV file, without an audio device.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::app::{self, AppState, Msg};
+use crate::audio::Stereo;
+use crate::engine::{self, MAIN_OUTPUT};
+use crate::project::Project;
+use crate::{DEFAULT_SAMPLE_RATE, INTERNAL_BUFFER_SIZE};
+
+pub enum RenderRange {
+    Song,
+    Loop,
+    /// Inclusive range of positions in the song
+    Patterns(usize, usize),
+}
+
+impl RenderRange {
+    fn resolve(&self, state: &AppState) -> Result<(usize, usize)> {
+        let (start, end) = match self {
+            Self::Song => (0, state.song.len() - 1),
+            Self::Loop => state
+                .loop_range
+                .ok_or_else(|| anyhow!("project has no loop range"))?,
+            Self::Patterns(start, end) => (*start, *end),
+        };
+        if start > end || end >= state.song.len() {
+            return Err(anyhow!("invalid pattern range {start}-{end}"));
+        }
+        Ok((start, end))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl BitDepth {
+    fn spec(&self, sample_rate: u32) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            Self::Int16 => (16, SampleFormat::Int),
+            Self::Int24 => (24, SampleFormat::Int),
+            Self::Float32 => (32, SampleFormat::Float),
+        };
+        WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "16" => Ok(Self::Int16),
+            "24" => Ok(Self::Int24),
+            "32f" => Ok(Self::Float32),
+            _ => Err(anyhow!("invalid bit depth {s}, expected 16, 24 or 32f")),
+        }
+    }
+}
+
+pub struct RenderOptions {
+    pub range: RenderRange,
+    pub bit_depth: BitDepth,
+    pub sample_rate: u32,
+    /// Seconds to keep rendering after the last pattern, so effects and releases can ring out
+    pub tail: f64,
+    /// Directory to write the post-fader output of every track to, next to the mixdown
+    pub stems: Option<Utf8PathBuf>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            range: RenderRange::Song,
+            bit_depth: BitDepth::Int24,
+            sample_rate: DEFAULT_SAMPLE_RATE as u32,
+            tail: 2.0,
+            stems: None,
+        }
+    }
+}
+
+/// Renders a project as fast as possible. If `options.stems` is set, every track except the master
+/// is also written to its own file in that directory. `progress` is called after every buffer with the
+/// fraction of the output that has been written.
+pub fn render<F>(
+    project: Project,
+    path: &Utf8Path,
+    options: &RenderOptions,
+    progress: F,
+) -> Result<()>
+where
+    F: FnMut(f64),
+{
+    let (mut app, mut app_state, mut engine, _) = app::new()?;
+    let sample_rate = options.sample_rate as f64;
+    app.send(Msg::SetSampleRate(options.sample_rate))?;
+    engine.set_sample_rate(sample_rate);
+    app.load_project(project)?;
+
+    let (start, end) = options.range.resolve(&app.state)?;
+    let ticks: usize = (start..=end)
+        .map(|i| app.state.pattern(i).map_or(0, |p| p.length))
+        .sum();
+    let song_frames =
+        engine::frames_for_ticks(ticks, app.state.bpm, app.state.lines_per_beat, sample_rate);
+    let tail_frames = (options.tail.max(0.0) * sample_rate) as usize;
+
+    // The engine wraps around at the loop, so the loop is the range to render
+    app.state.loop_range = Some((start, end));
+    engine.seek(start);
+    app.send(Msg::TogglePlay)?;
+
+    let spec = options.bit_depth.spec(options.sample_rate);
+    let mut wav = WavWriter::create(path, spec)?;
+    let mut stems = Vec::new();
+    if let Some(dir) = &options.stems {
+        std::fs::create_dir_all(dir)?;
+        for (i, track) in app.tracks.iter().enumerate() {
+            // The master track writes the mix itself
+            if track.output_node_index == MAIN_OUTPUT {
+                continue;
+            }
+            let path = dir.join(stem_file_name(i, track.name.as_deref()));
+            engine.tap(track.node_index);
+            stems.push((track.node_index, WavWriter::create(path, spec)?));
+        }
+    }
+
+    let mut buf = [Stereo::ZERO; INTERNAL_BUFFER_SIZE];
+    let write = |wav: &mut WavWriter<_>, frames: &[Stereo]| -> Result<()> {
+        for frame in frames {
+            for ch in 0..2 {
+                write_sample(wav, options.bit_depth, frame.channel(ch))?;
+            }
+        }
+        Ok(())
+    };
+
+    let total_frames = song_frames + tail_frames;
+    let mut progress = progress;
+    let mut offset = 0;
+    while offset < total_frames {
+        // Stop playback exactly at the end of the range so the song doesn't wrap around into the
+        // tail.
+        if offset == song_frames {
+            app.send(Msg::TogglePlay)?;
+        }
+        let end = if offset < song_frames {
+            song_frames
+        } else {
+            total_frames
+        };
+        let num_frames = usize::min(buf.len(), end - offset);
+        engine.process(app_state.read(), &mut buf[..num_frames]);
+        write(&mut wav, &buf[..num_frames])?;
+        for (node_index, stem) in stems.iter_mut() {
+            let frames = engine.tapped(*node_index).unwrap_or_default();
+            write(stem, &frames[..num_frames])?;
+        }
+        offset += num_frames;
+        progress(offset as f64 / total_frames as f64);
+    }
+    wav.finalize()?;
+    for (_, stem) in stems {
+        stem.finalize()?;
+    }
+
+    Ok(())
+}
+
+fn stem_file_name(track_idx: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => {
+            let name = name.replace(['/', '\\'], "_");
+            format!("{track_idx:02} {name}.wav")
+        }
+        None => format!("{track_idx:02}.wav"),
+    }
+}
+
+fn write_sample<W>(wav: &mut WavWriter<W>, bit_depth: BitDepth, sample: f32) -> Result<()>
+where
+    W: std::io::Write + std::io::Seek,
+{
+    match bit_depth {
+        BitDepth::Int16 => wav.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?,
+        BitDepth::Int24 => wav.write_sample((sample.clamp(-1.0, 1.0) * 8_388_607.0) as i32)?,
+        BitDepth::Float32 => wav.write_sample(sample)?,
+    }
+    Ok(())
+}
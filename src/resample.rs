@@ -0,0 +1,141 @@
+//! Band-limited sample rate conversion with a windowed sinc filter.
+
+use std::f64::consts::PI;
+use std::fmt::{self, Display, Formatter};
+
+use crate::audio::Stereo;
+
+/// How sounds are converted to the engine's sample rate when they're loaded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// Keep the sample rate of the file and interpolate linearly during playback
+    #[default]
+    Off,
+    Normal,
+    High,
+}
+
+impl Quality {
+    pub fn from_value(value: f64) -> Self {
+        match value.round() as i64 {
+            1 => Self::Normal,
+            2 => Self::High,
+            _ => Self::Off,
+        }
+    }
+
+    pub fn value(self) -> f64 {
+        self as usize as f64
+    }
+
+    /// Number of zero crossings of the sinc function on either side of the filter's center
+    fn zero_crossings(self) -> usize {
+        match self {
+            Self::Off => 0,
+            Self::Normal => 8,
+            Self::High => 32,
+        }
+    }
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Off => "off",
+            Self::Normal => "normal",
+            Self::High => "high",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Converts `input` from sample rate `from` to sample rate `to`. When downsampling, the cutoff of
+/// the filter is lowered to the new Nyquist frequency, so higher frequencies don't alias.
+pub fn resample(input: &[Stereo], from: usize, to: usize, quality: Quality) -> Vec<Stereo> {
+    let zero_crossings = quality.zero_crossings();
+    if from == to || zero_crossings == 0 || input.is_empty() {
+        return input.to_vec();
+    }
+    let ratio = to as f64 / from as f64;
+    let cutoff = f64::min(1.0, ratio);
+    // Half the length of the filter in input samples
+    let width = zero_crossings as f64 / cutoff;
+    let len = (input.len() as f64 * ratio).ceil() as usize;
+
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 / ratio;
+            let start = (pos - width).ceil().max(0.0) as usize;
+            let end = usize::min((pos + width).floor() as usize, input.len() - 1);
+            let mut output = Stereo::ZERO;
+            for (j, frame) in input.iter().enumerate().take(end + 1).skip(start) {
+                let x = j as f64 - pos;
+                let weight = cutoff * sinc(cutoff * x) * blackman(x / width);
+                output += *frame * weight as f32;
+            }
+            output
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window for `x` between -1 and 1
+fn blackman(x: f64) -> f64 {
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: usize, len: usize) -> Vec<Stereo> {
+        (0..len)
+            .map(|i| {
+                let s = (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32;
+                Stereo::new([s, s])
+            })
+            .collect()
+    }
+
+    fn rms(frames: &[Stereo]) -> f32 {
+        let sum: f32 = frames.iter().map(|f| f.channel(0) * f.channel(0)).sum();
+        (sum / frames.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn same_rate() {
+        let input = sine(440.0, 44100, 64);
+        assert_eq!(input, resample(&input, 44100, 44100, Quality::High));
+    }
+
+    #[test]
+    fn upsample() {
+        let input = sine(1000.0, 22050, 2205);
+        let output = resample(&input, 22050, 44100, Quality::Normal);
+        assert_eq!(4410, output.len());
+        // Away from the edges the output follows the sine at the new rate
+        let expected = sine(1000.0, 44100, 4410);
+        for (out, exp) in output[100..4300].iter().zip(&expected[100..4300]) {
+            assert!((out.channel(0) - exp.channel(0)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn downsample_removes_frequencies_above_nyquist() {
+        // 30kHz can't be represented at 44.1kHz and would alias to 14.1kHz
+        let input = sine(30_000.0, 96000, 9600);
+        let output = resample(&input, 96000, 44100, Quality::High);
+        assert!(rms(&output[100..4300]) < 0.01);
+
+        let input = sine(1000.0, 96000, 9600);
+        let output = resample(&input, 96000, 44100, Quality::High);
+        assert!((rms(&output[100..4300]) - 0.5f32.sqrt()).abs() < 0.01);
+    }
+}
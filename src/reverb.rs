@@ -0,0 +1,388 @@
+use std::sync::Arc;
+
+use crate::audio::Stereo;
+use crate::engine::{Plugin, PluginEvent, ProcessContext, ProcessStatus};
+use crate::params::{self, format_millis, Param, ParamInfo, Params, Smoothing};
+use param_derive::Params;
+
+/// Comb and allpass lengths in samples at 44.1kHz, from Freeverb
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+/// Extra length of the right channel's delay lines, which decorrelates the channels
+const STEREO_SPREAD: usize = 23;
+const TUNING_SAMPLE_RATE: f64 = 44100.0;
+
+/// Scale of the comb lengths at the smallest and largest size
+const MIN_SCALE: f64 = 0.5;
+const MAX_SCALE: f64 = 1.5;
+const MAX_PRE_DELAY: f64 = 200.0;
+/// Time in milliseconds in which the comb lengths glide to a new size
+const SIZE_SMOOTHING: f64 = 100.0;
+const INPUT_GAIN: f32 = 0.015;
+/// Level below which the tail counts as decayed, about -100dB
+const SILENCE: f32 = 1e-5;
+
+#[derive(Params)]
+pub struct ReverbParams {
+    size: Param,
+    decay: Param,
+    pre_delay: Param,
+    damping: Param,
+    width: Param,
+    mix: Param,
+}
+
+impl ReverbParams {
+    fn new(sample_rate: f64) -> Self {
+        Self {
+            size: Param::new(
+                0.5,
+                ParamInfo::new("Size", 0, 1)
+                    .with_smoothing(Smoothing::exp(SIZE_SMOOTHING, sample_rate)),
+            ),
+            decay: Param::new(
+                2.0,
+                ParamInfo::new("Decay", 0.1, 20.0)
+                    .with_steps([0.1, 1.0])
+                    .with_formatter(|v| format!("{:.1}s", v)),
+            ),
+            pre_delay: Param::new(
+                10.0,
+                ParamInfo::new("Pre-Delay", 0.0, MAX_PRE_DELAY)
+                    .with_steps([1, 10])
+                    .with_formatter(format_millis),
+            ),
+            damping: Param::new(
+                0.5,
+                ParamInfo::new("Damping", 0, 1).with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            width: Param::new(
+                1.0,
+                ParamInfo::new("Width", 0, 1).with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+            mix: Param::new(
+                0.25,
+                ParamInfo::new("Mix", 0, 1).with_smoothing(Smoothing::exp_default(sample_rate)),
+            ),
+        }
+    }
+}
+
+/// Low-pass feedback comb filter. The buffer fits the largest size, so a new size only moves
+/// the read position instead of cutting the delay line.
+struct Comb {
+    buffer: Vec<f32>,
+    /// Length in samples at a scale of 1
+    tuning: f64,
+    pos: usize,
+    feedback: f32,
+    filtered: f32,
+}
+
+impl Comb {
+    fn new(tuning: f64) -> Self {
+        Self {
+            buffer: vec![0.0; (tuning * MAX_SCALE) as usize + 2],
+            tuning,
+            pos: 0,
+            feedback: 0.0,
+            filtered: 0.0,
+        }
+    }
+
+    /// Length in samples for the scale of the size
+    fn len(&self, scale: f64) -> f64 {
+        (self.tuning * scale).clamp(1.0, (self.buffer.len() - 2) as f64)
+    }
+
+    fn process(&mut self, input: f32, scale: f64, damping: f32) -> f32 {
+        // Reads between samples, because the length glides while the size changes
+        let len = self.buffer.len();
+        let read_pos = self.pos as f64 + len as f64 - self.len(scale);
+        let idx = read_pos as usize;
+        let frac = (read_pos - idx as f64) as f32;
+        let a = self.buffer[idx % len];
+        let b = self.buffer[(idx + 1) % len];
+        let output = a + (b - a) * frac;
+
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.pos] = input + self.filtered * self.feedback;
+        self.pos = (self.pos + 1) % len;
+        output
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.filtered = 0.0;
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    const FEEDBACK: f32 = 0.5;
+
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0.0; len],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = input + delayed * Self::FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - input
+    }
+
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+/// Stereo reverb after Freeverb, with parallel combs into serial allpasses for each channel.
+/// The decay is the time in which the tail drops by 60dB, independent of the size.
+pub struct Reverb {
+    params: Arc<ReverbParams>,
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+    pre_delay: Vec<f32>,
+    pre_delay_pos: usize,
+    /// Size and decay time the comb feedback was computed for
+    feedback_for: Option<(f64, f64)>,
+    /// Frames in a row without input and with a silent tail
+    quiet_frames: usize,
+    sample_rate: f64,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_params(Arc::new(ReverbParams::new(sample_rate)), sample_rate)
+    }
+
+    fn with_params(params: Arc<ReverbParams>, sample_rate: f64) -> Self {
+        let scale = sample_rate / TUNING_SAMPLE_RATE;
+        let length = |tuning: usize, ch: usize| (tuning + ch * STEREO_SPREAD) as f64 * scale;
+        let combs = [0, 1].map(|ch| {
+            COMB_TUNING
+                .iter()
+                .map(|t| Comb::new(length(*t, ch)))
+                .collect()
+        });
+        let allpasses = [0, 1].map(|ch| {
+            ALLPASS_TUNING
+                .iter()
+                .map(|t| Allpass::new(length(*t, ch) as usize + 1))
+                .collect()
+        });
+        let pre_delay_len = (MAX_PRE_DELAY / 1000.0 * sample_rate) as usize + 1;
+        Self {
+            params,
+            combs,
+            allpasses,
+            pre_delay: vec![0.0; pre_delay_len],
+            pre_delay_pos: 0,
+            feedback_for: None,
+            quiet_frames: 0,
+            sample_rate,
+        }
+    }
+
+    /// Sets the comb feedback for the size and decay time, if either of them changed
+    fn update_feedback(&mut self) {
+        let size = self.params.size.target();
+        let decay = self.params.decay.target();
+        if self.feedback_for == Some((size, decay)) {
+            return;
+        }
+        self.feedback_for = Some((size, decay));
+        for comb in self.combs.iter_mut().flatten() {
+            // Gain per pass through the comb for a drop of 60dB within the decay time
+            let passes = decay * self.sample_rate / comb.len(comb_scale(size));
+            comb.feedback = 0.001f64.powf(1.0 / passes) as f32;
+        }
+    }
+
+    /// Number of quiet frames after which everything in the delay lines has come out
+    fn tail_len(&self) -> usize {
+        // The right channel's delay lines are the longest
+        let scale = comb_scale(self.params.size.target());
+        let comb = self.combs[1]
+            .iter()
+            .map(|c| c.len(scale) as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let allpasses: usize = self.allpasses[1].iter().map(|a| a.buffer.len()).sum();
+        self.pre_delay.len() + comb + allpasses
+    }
+
+    fn clear(&mut self) {
+        self.combs.iter_mut().flatten().for_each(Comb::clear);
+        self.allpasses.iter_mut().flatten().for_each(Allpass::clear);
+        self.pre_delay.fill(0.0);
+    }
+}
+
+/// Scale of the comb lengths for a size
+fn comb_scale(size: f64) -> f64 {
+    MIN_SCALE + (MAX_SCALE - MIN_SCALE) * size
+}
+
+impl Plugin for Reverb {
+    fn send_event(&mut self, _event: PluginEvent) {}
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    /// Clears the tail, because the delay lines are reallocated for the new sample rate
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.params.set_sample_rate(sample_rate);
+        *self = Self::with_params(self.params.clone(), sample_rate);
+    }
+
+    fn process(&mut self, ctx: &mut ProcessContext) -> ProcessStatus {
+        self.update_feedback();
+        let tail_len = self.tail_len();
+        let pre_delay = (self.params.pre_delay.target() / 1000.0 * self.sample_rate) as usize;
+        let pre_delay_len = self.pre_delay.len();
+
+        for mut frame in ctx.buffers() {
+            let scale = comb_scale(self.params.size.value());
+            let damping = 0.4 * self.params.damping.value() as f32;
+            let width = self.params.width.value() as f32;
+            let mix = self.params.mix.value() as f32;
+            let input = *frame.input;
+
+            self.pre_delay[self.pre_delay_pos] = (input.channel(0) + input.channel(1)) * INPUT_GAIN;
+            let read_pos = (self.pre_delay_pos + pre_delay_len - pre_delay) % pre_delay_len;
+            let delayed = self.pre_delay[read_pos];
+            self.pre_delay_pos = (self.pre_delay_pos + 1) % pre_delay_len;
+
+            let mut level = delayed.abs();
+            let mut wet = [0.0; 2];
+            for (ch, out) in wet.iter_mut().enumerate() {
+                for comb in &mut self.combs[ch] {
+                    let output = comb.process(delayed, scale, damping);
+                    level += output.abs();
+                    *out += output;
+                }
+                for allpass in &mut self.allpasses[ch] {
+                    *out = allpass.process(*out);
+                }
+            }
+
+            if input == Stereo::ZERO && level < SILENCE {
+                self.quiet_frames += 1;
+            } else {
+                self.quiet_frames = 0;
+            }
+
+            let wet1 = 0.5 + 0.5 * width;
+            let wet2 = 0.5 - 0.5 * width;
+            let wet = Stereo::new([wet[0] * wet1 + wet[1] * wet2, wet[1] * wet1 + wet[0] * wet2]);
+            frame.write(input * (1.0 - mix) + wet * mix);
+        }
+
+        if self.quiet_frames >= tail_len {
+            // Clear what's left of the tail, so it doesn't come back when there's input again
+            self.clear();
+            ProcessStatus::Idle
+        } else {
+            ProcessStatus::Continue
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Buffer;
+
+    const SAMPLE_RATE: f64 = 8000.0;
+
+    fn process(reverb: &mut Reverb, input: Buffer) -> (Buffer, ProcessStatus) {
+        let len = input.len();
+        let mut buffers = vec![input, vec![Stereo::ZERO; len]];
+        let mut ctx = ProcessContext::new(&mut buffers, len);
+        ctx.buffer_indices = Some((0, 1));
+        let status = reverb.process(&mut ctx);
+        (buffers.pop().unwrap(), status)
+    }
+
+    fn level(buf: &[Stereo]) -> f32 {
+        buf.iter()
+            .map(|frame| frame.channel(0).abs().max(frame.channel(1).abs()))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn tail_decays_to_idle() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.params.decay.set(0.5);
+        reverb.params.mix.set(1.0);
+        let frames = 400;
+
+        let mut input = vec![Stereo::ZERO; frames];
+        input[0] = Stereo::new([1.0, 1.0]);
+        let (output, status) = process(&mut reverb, input);
+        assert!(matches!(status, ProcessStatus::Continue));
+        // Only the dry impulse comes out before the pre-delay and the shortest comb
+        assert_eq!(0.0, level(&output[1..280]));
+        assert!(level(&output) > 0.0);
+
+        // The tail keeps the reverb running, then drops by 60dB after the decay time
+        let mut statuses = Vec::new();
+        let mut levels = Vec::new();
+        for _ in 0..100 {
+            let (output, status) = process(&mut reverb, vec![Stereo::ZERO; frames]);
+            statuses.push(status);
+            levels.push(level(&output));
+        }
+        assert!(matches!(statuses[0], ProcessStatus::Continue));
+        assert!(levels[0] > 0.001);
+        assert!(levels[15] < 0.001 * levels[0]);
+        let idle = statuses
+            .iter()
+            .position(|s| matches!(s, ProcessStatus::Idle))
+            .expect("reverb should become idle");
+        assert!(idle > 10);
+    }
+
+    /// Largest difference between neighbouring samples, which jumps where there's a click
+    fn max_step(buf: &[Stereo]) -> f32 {
+        let steps = buf.windows(2).map(|w| level(&[w[1] - w[0]]));
+        steps.fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn size_change_glides() {
+        let mut reverb = Reverb::new(SAMPLE_RATE);
+        reverb.params.mix.set(1.0);
+        let frames = 400;
+        let sine = |block: usize| {
+            (0..frames)
+                .map(|i| {
+                    let t = (block * frames + i) as f32 / SAMPLE_RATE as f32;
+                    let v = (t * 100.0 * std::f32::consts::TAU).sin();
+                    Stereo::new([v, v])
+                })
+                .collect::<Buffer>()
+        };
+
+        let mut steady = 0.0;
+        for block in 0..40 {
+            let (output, _) = process(&mut reverb, sine(block));
+            steady = max_step(&output);
+        }
+        reverb.params.size.set(1.0);
+        for block in 40..50 {
+            let (output, _) = process(&mut reverb, sine(block));
+            assert!(max_step(&output) < 2.0 * steady);
+        }
+    }
+}
@@ -1,11 +1,14 @@
+mod decode;
+
 use crate::audio::{Buffer, Frame, Stereo};
 use crate::engine::{Note, Plugin, PluginEvent, ProcessContext, ProcessStatus};
 use crate::env::{Envelope, State as EnvelopeState};
 use crate::params::{self, format_millis, Param, ParamInfo, Params};
-use crate::SAMPLE_RATE;
+use crate::resample::{self, Quality};
+use crate::DEFAULT_SAMPLE_RATE;
 use anyhow::Result;
-use camino::Utf8PathBuf;
-use hound::{SampleFormat, WavReader};
+use camino::{Utf8Path, Utf8PathBuf};
+use hound::{SampleFormat, WavSpec, WavWriter};
 use param_derive::Params;
 use std::ops::Range;
 use std::sync::Arc;
@@ -18,6 +21,7 @@ pub struct SamplerParams {
     env_decay: Param,
     env_sustain: Param,
     env_release: Param,
+    resampling: Param,
 }
 
 impl SamplerParams {
@@ -60,6 +64,12 @@ impl Default for SamplerParams {
                     .with_steps([5, 100])
                     .with_formatter(format_millis),
             ),
+            resampling: Param::new(
+                Quality::Off.value(),
+                ParamInfo::new("Resampling", 0, 2)
+                    .with_steps([1, 1])
+                    .with_formatter(|v| Quality::from_value(v).to_string()),
+            ),
         }
     }
 }
@@ -83,7 +93,7 @@ pub enum VoiceState {
 }
 
 impl Voice {
-    fn new(params: Arc<SamplerParams>, sample: Arc<Buffer>) -> Self {
+    fn new(params: Arc<SamplerParams>, sample: Arc<Buffer>, sample_rate: f64) -> Self {
         let adsr = params.adsr();
         Self {
             params,
@@ -92,7 +102,7 @@ impl Voice {
             velocity: 0.0,
             pitch_ratio: 0.,
             state: VoiceState::Free,
-            env: Envelope::new(adsr),
+            env: Envelope::new(adsr, sample_rate),
             sample,
             gate: 0.0,
         }
@@ -149,37 +159,69 @@ impl Sound {
         Self {
             buf: Arc::new(zero.to_vec()),
             offset: 0,
-            sample_rate: crate::SAMPLE_RATE as usize,
+            sample_rate: DEFAULT_SAMPLE_RATE as usize,
         }
     }
 
-    fn new(buf: Buffer, offset: usize, sample_rate: usize) -> Self {
+    pub fn new(buf: Buffer, offset: usize, sample_rate: usize) -> Self {
         Self {
             buf: Arc::new(buf),
             offset,
             sample_rate,
         }
     }
+
+    pub fn frames(&self) -> &[Stereo] {
+        &self.buf
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Converts the sound to the engine's sample rate, unless `quality` is off
+    pub fn resample(&self, quality: Quality, sample_rate: f64) -> Self {
+        let rate = sample_rate as usize;
+        if quality == Quality::Off || self.sample_rate == rate {
+            return self.clone();
+        }
+        let buf = resample::resample(&self.buf, self.sample_rate, rate, quality);
+        let offset = self.offset * rate / self.sample_rate;
+        Self::new(buf, offset, rate)
+    }
 }
 
-pub fn load_file(path: &Utf8PathBuf) -> Result<Sound> {
-    let mut wav = WavReader::open(path.clone())?;
-    let wav_spec = wav.spec();
-    let bit_depth = wav_spec.bits_per_sample as f32;
-
-    let samples: Vec<f32> = match wav_spec.sample_format {
-        SampleFormat::Int => wav
-            .samples::<i32>()
-            .map(|s| s.unwrap() as f32 / (f32::powf(2., bit_depth - 1.)))
-            .collect::<Vec<f32>>(),
-        SampleFormat::Float => wav
-            .samples::<f32>()
-            .map(|s| s.unwrap())
-            .collect::<Vec<f32>>(),
+/// Returns the resampling quality from the parameter values of a sampler
+pub fn resampling_quality(params: &[f64]) -> Quality {
+    params
+        .get(SamplerParams::RESAMPLING)
+        .map_or(Quality::default(), |v| Quality::from_value(*v))
+}
+
+/// Writes a sound to a 32-bit float WAV file
+pub fn save_file(sound: &Sound, path: &Utf8Path) -> Result<()> {
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: sound.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
     };
+    let mut wav = WavWriter::create(path, spec)?;
+    for frame in sound.buf.iter() {
+        wav.write_sample(frame.channel(0))?;
+        wav.write_sample(frame.channel(1))?;
+    }
+    wav.finalize()?;
+    Ok(())
+}
 
-    let frames: Vec<Stereo> = samples
-        .chunks(wav_spec.channels as usize)
+/// Loads a sound from a WAV, FLAC, AIFF, Ogg Vorbis or MP3 file at the sample rate of the file.
+/// Use [`Sound::resample`] to convert it to the engine's sample rate.
+pub fn load_file(path: &Utf8PathBuf) -> Result<Sound> {
+    let audio = decode::read(path)?;
+    let frames: Vec<Stereo> = audio
+        .samples
+        .chunks(audio.channels)
         .map(|f| {
             let left = *f.first().unwrap();
             let right = *f.get(1).unwrap_or(&left);
@@ -197,7 +239,7 @@ pub fn load_file(path: &Utf8PathBuf) -> Result<Sound> {
             break;
         }
     }
-    Ok(Sound::new(frames, offset, wav_spec.sample_rate as usize))
+    Ok(Sound::new(frames, offset, audio.sample_rate))
 }
 
 pub struct Sampler {
@@ -205,20 +247,22 @@ pub struct Sampler {
     events: Vec<PluginEvent>,
     sound: Arc<Sound>,
     params: Arc<SamplerParams>,
+    sample_rate: f64,
 }
 
 impl Sampler {
-    pub fn new(sound: Sound) -> Self {
+    pub fn new(sound: Sound, sample_rate: f64) -> Self {
         let mut voices = Vec::with_capacity(12);
         let params = Arc::new(SamplerParams::default());
         for _ in 0..voices.capacity() {
-            voices.push(Voice::new(params.clone(), sound.buf.clone()));
+            voices.push(Voice::new(params.clone(), sound.buf.clone(), sample_rate));
         }
         Self {
             voices,
             events: Vec::with_capacity(64),
             sound: sound.into(),
             params,
+            sample_rate,
         }
     }
 
@@ -233,14 +277,14 @@ impl Sampler {
 
             voice.gate = 1.0;
             voice.state = VoiceState::Busy(track_idx);
-            voice.env = Envelope::new(self.params.adsr());
+            voice.env = Envelope::new(self.params.adsr(), self.sample_rate);
             voice.pitch = pitch;
             voice.velocity =
                 params::db_to_amp(map(velocity.into(), (0.0, 127.0), (-60.0, 0.0))) as f32;
 
             let pitch = pitch as i8 - ROOT_PITCH as i8;
             voice.pitch_ratio = f32::powf(2., pitch as f32 / 12.0)
-                * (self.sound.sample_rate as f32 / SAMPLE_RATE as f32);
+                * (self.sound.sample_rate as f32 / self.sample_rate as f32);
             voice.position = self.sound.offset as f32;
         } else {
             eprintln!("dropped event");
@@ -305,6 +349,15 @@ impl Plugin for Sampler {
     fn send_event(&mut self, event: PluginEvent) {
         self.events.push(event);
     }
+
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        for voice in self.voices.iter_mut() {
+            voice.pitch_ratio *= (self.sample_rate / sample_rate) as f32;
+            voice.env.set_sample_rate(sample_rate);
+        }
+        self.sample_rate = sample_rate;
+        self.params.set_sample_rate(sample_rate);
+    }
 }
 
 fn map(v: f64, from: (f64, f64), to: (f64, f64)) -> f64 {
@@ -312,7 +365,7 @@ fn map(v: f64, from: (f64, f64), to: (f64, f64)) -> f64 {
 }
 
 pub fn can_load_file(path: &Utf8PathBuf) -> bool {
-    path.extension() == Some("wav")
+    decode::extension(path).is_some_and(|ext| decode::EXTENSIONS.contains(&ext.as_str()))
 }
 
 #[cfg(test)]
@@ -332,7 +385,7 @@ mod tests {
         let sample = Stereo::new([0.5, 0.5]);
 
         let sound = Sound::new(vec![sample; 16], 0, 44100);
-        let mut sampler = Sampler::new(sound);
+        let mut sampler = Sampler::new(sound, 44100.0);
         let note = Note::On(ROOT_PITCH, 127);
 
         let ev = PluginEvent::new(8, track1, note);
@@ -354,4 +407,53 @@ mod tests {
         assert_eq!(vec![Stereo::ZERO; 16], buffers[track2][0..16]);
         assert_ne!(vec![Stereo::ZERO; 16], buffers[track2][16..32]);
     }
+
+    #[test]
+    fn load_aiff() -> Result<()> {
+        // 16-bit stereo AIFF at 44.1kHz with two frames
+        let samples: [i16; 4] = [0, 0, 16384, -16384];
+        let mut ssnd = vec![0; 8];
+        ssnd.extend(samples.iter().flat_map(|s| s.to_be_bytes()));
+        let mut comm = Vec::new();
+        comm.extend(2i16.to_be_bytes());
+        comm.extend(2u32.to_be_bytes());
+        comm.extend(16i16.to_be_bytes());
+        comm.extend([0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+
+        let mut form = b"AIFF".to_vec();
+        for (id, chunk) in [(b"COMM", comm), (b"SSND", ssnd)] {
+            form.extend(id);
+            form.extend((chunk.len() as u32).to_be_bytes());
+            form.extend(chunk);
+        }
+        let mut data = b"FORM".to_vec();
+        data.extend((form.len() as u32).to_be_bytes());
+        data.extend(form);
+
+        let path = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-sampler.aiff");
+        std::fs::write(&path, data)?;
+        assert!(can_load_file(&path));
+        let sound = load_file(&path)?;
+        assert_eq!(44100, sound.sample_rate);
+        assert_eq!(vec![Stereo::ZERO, Stereo::new([0.5, -0.5])], *sound.buf);
+        Ok(())
+    }
+
+    #[test]
+    fn resample_sound() {
+        let sound = Sound::new(vec![Stereo::new([0.5, 0.5]); 100], 10, 22050);
+        let off = sound.resample(Quality::Off, 44100.0);
+        assert_eq!(22050, off.sample_rate);
+        assert_eq!(100, off.buf.len());
+
+        let converted = sound.resample(Quality::Normal, 44100.0);
+        assert_eq!(44100, converted.sample_rate);
+        assert_eq!(200, converted.buf.len());
+        assert_eq!(20, converted.offset);
+
+        let converted = sound.resample(Quality::Normal, 88200.0);
+        assert_eq!(88200, converted.sample_rate);
+        assert_eq!(400, converted.buf.len());
+        assert_eq!(40, converted.offset);
+    }
 }
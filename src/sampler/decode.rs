@@ -0,0 +1,121 @@
+//! Decoding of audio files into interleaved samples. WAV files are read with hound, other formats
+//! are decoded with symphonia.
+
+use std::fs::File;
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use hound::{SampleFormat, WavReader};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+pub(super) const EXTENSIONS: [&str; 8] =
+    ["wav", "flac", "aif", "aiff", "aifc", "ogg", "oga", "mp3"];
+
+pub(super) struct Audio {
+    /// Interleaved samples between -1 and 1
+    pub samples: Vec<f32>,
+    pub channels: usize,
+    pub sample_rate: usize,
+}
+
+pub(super) fn read(path: &Utf8Path) -> Result<Audio> {
+    match extension(path).as_deref() {
+        Some("wav") => read_wav(path),
+        _ => decode(path),
+    }
+}
+
+pub(super) fn extension(path: &Utf8Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_ascii_lowercase())
+}
+
+fn read_wav(path: &Utf8Path) -> Result<Audio> {
+    let mut wav = WavReader::open(path)?;
+    let wav_spec = wav.spec();
+    let bit_depth = wav_spec.bits_per_sample as f32;
+
+    let samples: Vec<f32> = match wav_spec.sample_format {
+        SampleFormat::Int => wav
+            .samples::<i32>()
+            .map(|s| s.unwrap() as f32 / (f32::powf(2., bit_depth - 1.)))
+            .collect::<Vec<f32>>(),
+        SampleFormat::Float => wav
+            .samples::<f32>()
+            .map(|s| s.unwrap())
+            .collect::<Vec<f32>>(),
+    };
+
+    Ok(Audio {
+        samples,
+        channels: wav_spec.channels as usize,
+        sample_rate: wav_spec.sample_rate as usize,
+    })
+}
+
+fn decode(path: &Utf8Path) -> Result<Audio> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension() {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow!("no audio track in {path}"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut sample_rate = track.codec_params.sample_rate;
+    let mut channels = track.codec_params.channels.map(|c| c.count());
+
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(Error::ResetRequired) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet only causes a short gap
+            Err(Error::DecodeError(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let spec = *decoded.spec();
+        sample_rate = Some(spec.rate);
+        channels = Some(spec.channels.count());
+        let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buf.samples());
+    }
+
+    let (Some(sample_rate), Some(channels)) = (sample_rate, channels) else {
+        return Err(anyhow!("unknown audio format in {path}"));
+    };
+    Ok(Audio {
+        samples,
+        channels,
+        sample_rate: sample_rate as usize,
+    })
+}
@@ -0,0 +1,171 @@
+//! Import of modules made with other trackers.
+//!
+//! Modules are converted into a project with an instrument track for every channel, routed to a
+//! master track. Their samples are loaded into instrument slots. Anything that can't be
+//! represented, like most effect commands, is skipped and reported in [`Module::warnings`].
+
+mod fasttracker;
+mod protracker;
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+
+use crate::app::TrackType;
+use crate::engine::TICKS_PER_LINE;
+use crate::pattern::{Pattern, Step, DEFAULT_VELOCITY, MAX_PITCH, NOTE_OFF};
+use crate::project::{Project, TrackData};
+use crate::sampler::{Sound, ROOT_PITCH};
+
+const LINES_PER_BEAT: u16 = 4;
+/// Neither format supports more channels than this
+const MAX_CHANNELS: usize = 32;
+
+pub struct Module {
+    /// The converted project, without instruments
+    pub project: Project,
+    pub samples: Vec<Sample>,
+    pub warnings: Vec<String>,
+}
+
+pub struct Sample {
+    pub slot: usize,
+    pub name: String,
+    pub sound: Sound,
+    /// Parameter values for the sampler, defaults are used for missing values
+    pub params: Vec<f64>,
+}
+
+pub fn can_load_file(path: &Utf8Path) -> bool {
+    matches!(path.extension(), Some("mod" | "xm"))
+}
+
+pub fn load(path: &Utf8Path) -> Result<Module> {
+    let data = std::fs::read(path)?;
+    match path.extension() {
+        Some("mod") => protracker::parse(&data),
+        Some("xm") => fasttracker::parse(&data),
+        _ => Err(anyhow!("unsupported module format")),
+    }
+}
+
+/// Counts features of a module that were skipped during conversion
+#[derive(Default)]
+struct Warnings(BTreeMap<String, usize>);
+
+impl Warnings {
+    fn add(&mut self, warning: impl Into<String>) {
+        *self.0.entry(warning.into()).or_default() += 1;
+    }
+
+    fn into_vec(self) -> Vec<String> {
+        self.0
+            .into_iter()
+            .map(|(warning, count)| format!("{warning} ({count}x)"))
+            .collect()
+    }
+}
+
+/// Builds a project with a track for each channel, which are all routed to a master track
+fn project(bpm: u16, num_channels: usize, patterns: Vec<Pattern>, song: Vec<usize>) -> Project {
+    let mut tracks: Vec<TrackData> = (0..num_channels)
+        .map(|i| TrackData {
+            track_type: TrackType::Instrument,
+            name: Some(format!("Channel {}", i + 1)),
+            output: Some(num_channels),
+            params: Vec::new(),
+            effects: Vec::new(),
+        })
+        .collect();
+    tracks.push(TrackData {
+        track_type: TrackType::Bus,
+        name: Some(String::from("Master")),
+        output: None,
+        params: Vec::new(),
+        effects: Vec::new(),
+    });
+    Project {
+        bpm,
+        lines_per_beat: LINES_PER_BEAT,
+        octave: 4,
+        loop_range: None,
+        instruments: Vec::new(),
+        tracks,
+        patterns,
+        song,
+    }
+}
+
+/// Drops patterns that aren't part of the song, which refers to patterns by their index in
+/// `patterns`. Returns the remaining patterns and the song with updated indices.
+fn song(patterns: Vec<Option<Pattern>>, orders: &[u8]) -> (Vec<Pattern>, Vec<usize>) {
+    let mut indices = vec![0; patterns.len()];
+    let mut used = Vec::new();
+    for (i, pattern) in patterns.into_iter().enumerate() {
+        if let Some(pattern) = pattern {
+            indices[i] = used.len();
+            used.push(pattern);
+        }
+    }
+    let song = orders.iter().map(|idx| indices[*idx as usize]).collect();
+    (used, song)
+}
+
+/// Converts a row rate in ticks per row and ticks per second (as 2.5 / tempo) into beats per
+/// minute at [`LINES_PER_BEAT`] lines per beat.
+fn bpm(speed: u8, tempo: u8) -> u16 {
+    let rows_per_minute = 60.0 * tempo as f64 / (2.5 * speed.max(1) as f64);
+    (rows_per_minute / LINES_PER_BEAT as f64).round() as u16
+}
+
+/// Returns the pitch that plays a sample at `ratio` times its recorded speed, where the sample
+/// rate of the sound is set to play it at its recorded speed at the root pitch.
+fn pitch(ratio: f64) -> Option<u8> {
+    let pitch = ROOT_PITCH as f64 + 12.0 * ratio.log2();
+    let pitch = pitch.round();
+    (0.0..MAX_PITCH as f64)
+        .contains(&pitch)
+        .then_some(pitch as u8)
+}
+
+/// Converts a linear volume between 0 and 1 to a velocity, following the sampler's velocity
+/// curve. Returns `None` for the default velocity.
+fn velocity(volume: f64) -> Option<u8> {
+    let velocity = if volume <= 0.0 {
+        0
+    } else {
+        let db = 20.0 * volume.min(1.0).log10();
+        ((db + 60.0) / 60.0 * 127.0).round().max(0.0) as u8
+    };
+    Some(velocity).filter(|v| *v != DEFAULT_VELOCITY)
+}
+
+/// Converts a delay of `ticks` at `speed` ticks per row into a note offset
+fn offset(ticks: u8, speed: u8) -> u8 {
+    let offset = ticks as usize * TICKS_PER_LINE / speed.max(1) as usize;
+    usize::min(offset, TICKS_PER_LINE - 1) as u8
+}
+
+/// Returns a step that plays `pitch` on the instrument in `slot`
+fn note(pitch: u8, slot: usize, velocity: Option<u8>, offset: Option<u8>) -> Step {
+    Step::with_cells([
+        Some(pitch),
+        Some(slot as u8),
+        velocity.map(|_| b'V'),
+        velocity,
+        offset.map(|_| b'O'),
+        offset,
+    ])
+}
+
+fn note_off(offset: Option<u8>) -> Step {
+    Step::with_cells([
+        Some(NOTE_OFF),
+        None,
+        None,
+        None,
+        offset.map(|_| b'O'),
+        offset,
+    ])
+}
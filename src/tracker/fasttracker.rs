@@ -0,0 +1,654 @@
+//! FastTracker 2 XM modules, version 1.04.
+//!
+//! Every sample of an instrument is loaded into its own instrument slot, and notes are assigned
+//! to the slot of the sample that the instrument maps them to.
+
+use anyhow::{anyhow, Result};
+
+use super::protracker::EFFECT_NAMES;
+use super::{Module, Sample, Warnings, MAX_CHANNELS};
+use crate::audio::{Buffer, Frame};
+use crate::engine::MAX_INSTRUMENTS;
+use crate::pattern::{Pattern, MAX_PITCH};
+use crate::sampler::{Adsr, SamplerParams, Sound, ROOT_PITCH};
+
+const ID: &[u8] = b"Extended Module: ";
+const VERSION: u16 = 0x0104;
+/// Offset of the header, after the ID, name and version
+const HEADER_OFFSET: usize = 60;
+const HEADER_SIZE: usize = 276;
+const INSTRUMENT_HEADER_SIZE: usize = 243;
+const SAMPLE_HEADER_SIZE: usize = 40;
+const NUM_NOTES: usize = 96;
+const KEY_OFF: u8 = 97;
+/// Note that plays a sample at its base rate, C-4
+const BASE_NOTE: u8 = 48;
+/// Sample rate of a sample at the base note, without relative note or finetune
+const BASE_RATE: f64 = 8363.0;
+const MAX_VOLUME: f64 = 64.0;
+const MAX_ENVELOPE_POINTS: usize = 12;
+/// Marks a sample that is compressed with ModPlug's ADPCM scheme
+const ADPCM: u8 = 0xAD;
+
+struct Instrument {
+    name: String,
+    keymap: [u8; NUM_NOTES],
+    volume_envelope: Option<Envelope>,
+    has_panning_envelope: bool,
+    has_vibrato: bool,
+    fadeout: u16,
+    samples: Vec<SampleHeader>,
+}
+
+struct Envelope {
+    /// Points as ticks and volume
+    points: Vec<(u16, u16)>,
+    sustain: Option<usize>,
+    has_loop: bool,
+}
+
+struct SampleHeader {
+    name: String,
+    /// Length in bytes
+    length: usize,
+    has_loop: bool,
+    volume: u8,
+    finetune: i8,
+    relative_note: i8,
+    is_16_bit: bool,
+    is_compressed: bool,
+    panning: u8,
+}
+
+#[derive(Clone, Default)]
+struct Cell {
+    note: u8,
+    instrument: usize,
+    volume: u8,
+    effect: u8,
+    param: u8,
+}
+
+struct XmPattern {
+    rows: usize,
+    cells: Vec<Cell>,
+}
+
+pub(super) fn parse(data: &[u8]) -> Result<Module> {
+    if data.len() < HEADER_OFFSET + HEADER_SIZE || !data.starts_with(ID) {
+        return Err(anyhow!("not an XM module"));
+    }
+    let version = u16_at(data, 58);
+    if version != VERSION {
+        return Err(anyhow!("unsupported XM version {version:04x}"));
+    }
+    let header = &data[HEADER_OFFSET..];
+    let header_size = u32_at(header, 0) as usize;
+    let song_len = usize::from(u16_at(header, 4)).clamp(1, 256);
+    let num_channels = usize::from(u16_at(header, 8));
+    let num_patterns = usize::from(u16_at(header, 10));
+    let num_instruments = usize::from(u16_at(header, 12));
+    let speed = u16_at(header, 16).clamp(1, 31) as u8;
+    let tempo = u16_at(header, 18).clamp(32, 255) as u8;
+    let orders = &header[20..20 + song_len];
+    if num_channels == 0 || num_channels > MAX_CHANNELS {
+        return Err(anyhow!(
+            "modules with {num_channels} channels are not supported"
+        ));
+    }
+
+    let mut offset = HEADER_OFFSET + header_size;
+    let mut xm_patterns = Vec::with_capacity(num_patterns);
+    for _ in 0..num_patterns {
+        let pattern_header = bytes(data, offset, 9)?;
+        let header_len = u32_at(pattern_header, 0) as usize;
+        let rows = usize::from(u16_at(pattern_header, 5)).max(1);
+        let packed_size = usize::from(u16_at(pattern_header, 7));
+        let packed = bytes(data, offset + header_len, packed_size)?;
+        xm_patterns.push(XmPattern {
+            rows,
+            cells: cells(packed, rows * num_channels)?,
+        });
+        offset += header_len + packed_size;
+    }
+
+    let mut warnings = Warnings::default();
+    let mut instruments = Vec::with_capacity(num_instruments);
+    let mut samples = Vec::new();
+    // Instrument slot for every sample of every instrument. Instrument numbers start at 1.
+    let mut slots = vec![Vec::new()];
+    for _ in 0..num_instruments {
+        let size = u32_at(bytes(data, offset, 4)?, 0) as usize;
+        let header = padded(data, offset, size);
+        offset += size;
+        // Sample data follows all the sample headers of an instrument
+        let num_samples = usize::from(u16_at(&header, 27));
+        let sample_header_size = if num_samples > 0 {
+            u32_at(&header, 29) as usize
+        } else {
+            0
+        };
+        let sample_headers = (0..num_samples)
+            .map(|i| {
+                sample_header(&padded(
+                    data,
+                    offset + i * sample_header_size,
+                    SAMPLE_HEADER_SIZE,
+                ))
+            })
+            .collect();
+        offset += num_samples * sample_header_size;
+        let instrument = instrument(&header, sample_headers);
+
+        if instrument
+            .volume_envelope
+            .as_ref()
+            .is_some_and(|e| e.has_loop)
+        {
+            warnings.add("volume envelope loop");
+        }
+        if instrument.has_panning_envelope {
+            warnings.add("panning envelope");
+        }
+        if instrument.has_vibrato {
+            warnings.add("auto-vibrato");
+        }
+        let params = params(&instrument, tempo);
+
+        let mut instrument_slots = vec![None; num_samples];
+        for (i, header) in instrument.samples.iter().enumerate() {
+            let length = if header.is_compressed {
+                16 + header.length.div_ceil(2)
+            } else {
+                header.length
+            };
+            let end = usize::min(offset + length, data.len());
+            let sample_data = data.get(offset..end).unwrap_or_default();
+            offset = end;
+            if sample_data.len() < length {
+                warnings.add("truncated sample data");
+            }
+            if header.is_compressed {
+                warnings.add("compressed sample");
+                continue;
+            }
+            if sample_data.is_empty() || !instrument.keymap.contains(&(i as u8)) {
+                continue;
+            }
+            if samples.len() == MAX_INSTRUMENTS {
+                warnings.add(format!("more than {MAX_INSTRUMENTS} samples"));
+                continue;
+            }
+            if header.has_loop {
+                warnings.add("sample loop");
+            }
+            if header.panning != 0x80 {
+                warnings.add("sample panning");
+            }
+            let semitones = header.relative_note as f64 + header.finetune as f64 / 128.0;
+            let rate = BASE_RATE * 2f64.powf(semitones / 12.0);
+            let name = if num_samples > 1 && !header.name.is_empty() {
+                format!("{}: {}", instrument.name, header.name)
+            } else {
+                instrument.name.clone()
+            };
+            instrument_slots[i] = Some(samples.len());
+            samples.push(Sample {
+                slot: samples.len(),
+                name,
+                sound: Sound::new(
+                    decode(sample_data, header.is_16_bit),
+                    0,
+                    rate.round() as usize,
+                ),
+                params: params.clone(),
+            });
+        }
+        slots.push(instrument_slots);
+        instruments.push(instrument);
+    }
+
+    let song_orders: Vec<u8> = orders
+        .iter()
+        .copied()
+        .filter(|idx| {
+            let exists = (*idx as usize) < num_patterns;
+            if !exists {
+                warnings.add("order of a missing pattern");
+            }
+            exists
+        })
+        .collect();
+    if song_orders.is_empty() {
+        return Err(anyhow!("module has no patterns"));
+    }
+
+    // The speed and the instrument of every channel carry over between patterns, so patterns are
+    // converted in song order.
+    let mut speed = speed;
+    let mut tempo = tempo;
+    let mut channel_instruments = vec![0; num_channels];
+    let mut patterns: Vec<Option<Pattern>> = vec![None; num_patterns];
+    for (position, &idx) in song_orders.iter().enumerate() {
+        let idx = idx as usize;
+        if patterns[idx].is_some() {
+            continue;
+        }
+        let xm_pattern = &xm_patterns[idx];
+        let mut pattern = Pattern::new(num_channels);
+        pattern.set_len(xm_pattern.rows);
+        let mut len = xm_pattern.rows;
+
+        for (row, cells) in xm_pattern.cells.chunks(num_channels).enumerate() {
+            // Speed changes apply to the whole row
+            for cell in cells {
+                if cell.effect == 0xF && cell.param > 0 {
+                    if position > 0 || row > 0 {
+                        warnings.add("speed or tempo change");
+                    } else if cell.param < 0x20 {
+                        speed = cell.param;
+                    } else {
+                        tempo = cell.param;
+                    }
+                }
+            }
+
+            for (ch, cell) in cells.iter().enumerate() {
+                if cell.instrument > 0 {
+                    channel_instruments[ch] = cell.instrument;
+                }
+                let instrument_idx = channel_instruments[ch];
+
+                let mut volume = match cell.volume {
+                    0 => None,
+                    0x10..=0x50 => Some(cell.volume - 0x10),
+                    _ => {
+                        warnings.add("volume column effect");
+                        None
+                    }
+                };
+                let mut offset = None;
+                let mut key_off = None;
+                match (cell.effect, cell.param) {
+                    (0x0, 0) | (0xF, _) => {}
+                    (0xC, param) => volume = Some(param.min(MAX_VOLUME as u8)),
+                    (0xD, param) => {
+                        len = usize::min(len, row + 1);
+                        if param > 0 {
+                            warnings.add("pattern break to a row other than the first");
+                        }
+                    }
+                    (0xE, param) if param >> 4 == 0xD => {
+                        offset = Some(super::offset(param & 0x0F, speed));
+                    }
+                    (0xE, param) => warnings.add(format!("effect E{:X}x", param >> 4)),
+                    // Key off
+                    (0x14, param) => key_off = Some(super::offset(param, speed)),
+                    (effect, _) => warnings.add(effect_name(effect)),
+                }
+
+                let step = match cell.note {
+                    note @ 1..=96 => {
+                        if key_off.is_some() {
+                            warnings.add("key off in a row with a note");
+                        }
+                        let Some(pitch) = pitch(note - 1) else {
+                            warnings.add("note out of range");
+                            continue;
+                        };
+                        let sample = instruments
+                            .get(instrument_idx.wrapping_sub(1))
+                            .map(|instr| instr.keymap[note as usize - 1] as usize);
+                        let slot = sample.and_then(|s| slots[instrument_idx].get(s).copied());
+                        let Some(slot) = slot.flatten() else {
+                            warnings.add("note without sample");
+                            continue;
+                        };
+                        let header = &instruments[instrument_idx - 1].samples[sample.unwrap()];
+                        let volume = volume.unwrap_or(header.volume);
+                        let velocity = super::velocity(volume as f64 / MAX_VOLUME);
+                        super::note(pitch, slot, velocity, offset)
+                    }
+                    KEY_OFF => super::note_off(offset),
+                    _ if key_off.is_some() => super::note_off(key_off.filter(|o| *o > 0)),
+                    _ if volume == Some(0) => super::note_off(offset),
+                    _ => {
+                        if volume.is_some() {
+                            warnings.add("volume change without a note");
+                        }
+                        continue;
+                    }
+                };
+                pattern.tracks[ch].steps[row] = step;
+            }
+        }
+        pattern.set_len(len);
+        patterns[idx] = Some(pattern);
+    }
+
+    let (patterns, song) = super::song(patterns, &song_orders);
+    Ok(Module {
+        project: super::project(super::bpm(speed, tempo), num_channels, patterns, song),
+        samples,
+        warnings: warnings.into_vec(),
+    })
+}
+
+/// Unpacks the cells of a pattern. Patterns without data are empty.
+fn cells(packed: &[u8], num_cells: usize) -> Result<Vec<Cell>> {
+    if packed.is_empty() {
+        return Ok(vec![Cell::default(); num_cells]);
+    }
+    let mut bytes = packed.iter().copied();
+    let mut next = || {
+        bytes
+            .next()
+            .ok_or_else(|| anyhow!("pattern data is truncated"))
+    };
+    let mut cells = Vec::with_capacity(num_cells);
+    for _ in 0..num_cells {
+        let first = next()?;
+        // The high bit marks a packed cell, where the other bits tell which fields follow
+        let cell = if first & 0x80 != 0 {
+            let mut field = |bit: u8| if first & bit != 0 { next() } else { Ok(0) };
+            Cell {
+                note: field(0x01)?,
+                instrument: field(0x02)? as usize,
+                volume: field(0x04)?,
+                effect: field(0x08)?,
+                param: field(0x10)?,
+            }
+        } else {
+            Cell {
+                note: first,
+                instrument: next()? as usize,
+                volume: next()?,
+                effect: next()?,
+                param: next()?,
+            }
+        };
+        cells.push(cell);
+    }
+    Ok(cells)
+}
+
+fn instrument(data: &[u8; INSTRUMENT_HEADER_SIZE], samples: Vec<SampleHeader>) -> Instrument {
+    let mut keymap = [0; NUM_NOTES];
+    keymap.copy_from_slice(&data[33..33 + NUM_NOTES]);
+
+    let num_points = usize::from(data[225]).min(MAX_ENVELOPE_POINTS);
+    let volume_type = data[233];
+    let volume_envelope = (volume_type & 0x01 != 0 && num_points > 0).then(|| Envelope {
+        points: (0..num_points)
+            .map(|i| (u16_at(data, 129 + i * 4), u16_at(data, 131 + i * 4)))
+            .collect(),
+        sustain: (volume_type & 0x02 != 0).then_some(usize::from(data[227]).min(num_points - 1)),
+        has_loop: volume_type & 0x04 != 0,
+    });
+
+    Instrument {
+        name: name(&data[4..26]),
+        keymap,
+        volume_envelope,
+        has_panning_envelope: data[234] & 0x01 != 0,
+        has_vibrato: data[237] > 0,
+        fadeout: u16_at(data, 239),
+        samples,
+    }
+}
+
+fn sample_header(data: &[u8; SAMPLE_HEADER_SIZE]) -> SampleHeader {
+    let sample_type = data[14];
+    let loop_length = u32_at(data, 8);
+    SampleHeader {
+        name: name(&data[18..40]),
+        length: u32_at(data, 0) as usize,
+        has_loop: sample_type & 0x03 != 0 && loop_length > 0,
+        volume: data[12].min(MAX_VOLUME as u8),
+        finetune: data[13] as i8,
+        is_16_bit: sample_type & 0x10 != 0,
+        panning: data[15],
+        relative_note: data[16] as i8,
+        is_compressed: data[17] == ADPCM,
+    }
+}
+
+/// Approximates the volume envelope and fadeout of an instrument with the sampler's envelope.
+/// Times are converted at the initial tempo, where a tick lasts 2.5 / `tempo` seconds.
+fn params(instrument: &Instrument, tempo: u8) -> Vec<f64> {
+    let millis = |ticks: u16| ticks as f64 * 2500.0 / tempo as f64;
+    // Without an envelope, key off silences a note right away
+    let mut adsr = Adsr {
+        attack: 1.0,
+        decay: 200.0,
+        sustain: 1.0,
+        release: 5.0,
+    };
+
+    if let Some(env) = &instrument.volume_envelope {
+        let points = &env.points;
+        let peak = points
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, (_, level))| (*level, usize::MAX - i))
+            .map_or(0, |(i, _)| i);
+        // Without a sustain point the envelope keeps the level of the last point
+        let end = env.sustain.unwrap_or(points.len() - 1);
+        adsr.attack = millis(points[peak].0.saturating_sub(points[0].0));
+        if end > peak {
+            adsr.decay = millis(points[end].0 - points[peak].0);
+            adsr.sustain = points[end].1 as f64 / MAX_VOLUME;
+        }
+        // After key off the instrument fades out, or finishes the rest of its envelope
+        let tail = points.last().unwrap().0.saturating_sub(points[end].0);
+        adsr.release = if instrument.fadeout > 0 {
+            millis((65536 / instrument.fadeout as u32) as u16)
+        } else if env.sustain.is_some() && tail > 0 {
+            millis(tail)
+        } else {
+            20_000.0
+        };
+    }
+
+    let mut params = vec![0.0; 4];
+    params[SamplerParams::ENV_ATTACK] = adsr.attack.clamp(1.0, 20_000.0);
+    params[SamplerParams::ENV_DECAY] = adsr.decay.clamp(5.0, 20_000.0);
+    params[SamplerParams::ENV_SUSTAIN] = adsr.sustain.clamp(0.01, 1.0);
+    params[SamplerParams::ENV_RELEASE] = adsr.release.clamp(5.0, 20_000.0);
+    params
+}
+
+/// Decodes sample data, which is stored as deltas between consecutive samples
+fn decode(data: &[u8], is_16_bit: bool) -> Buffer {
+    let frame = |s: f32| Frame::new([s, s]);
+    if is_16_bit {
+        let mut value = 0i16;
+        data.chunks_exact(2)
+            .map(|d| {
+                value = value.wrapping_add(i16::from_le_bytes([d[0], d[1]]));
+                frame(value as f32 / 32768.0)
+            })
+            .collect()
+    } else {
+        let mut value = 0i8;
+        data.iter()
+            .map(|d| {
+                value = value.wrapping_add(*d as i8);
+                frame(value as f32 / 128.0)
+            })
+            .collect()
+    }
+}
+
+/// Returns the pitch for a note number starting at C-0
+fn pitch(note: u8) -> Option<u8> {
+    let pitch = (note + ROOT_PITCH).checked_sub(BASE_NOTE)?;
+    (pitch < MAX_PITCH).then_some(pitch)
+}
+
+fn effect_name(effect: u8) -> String {
+    let name = match effect {
+        0x0..=0xF => EFFECT_NAMES[effect as usize],
+        0x10 => "set global volume",
+        0x11 => "global volume slide",
+        0x15 => "set envelope position",
+        0x19 => "panning slide",
+        0x1B => "multi retrig note",
+        0x1D => "tremor",
+        0x21 => "extra fine portamento",
+        _ => "unknown",
+    };
+    let letter = char::from_digit(effect as u32, 36).unwrap_or('?');
+    format!("effect {}xx ({name})", letter.to_ascii_uppercase())
+}
+
+fn name(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+fn bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| anyhow!("module is truncated"))
+}
+
+/// Copies a header of `size` bytes into a buffer of a fixed size. Files may store shorter
+/// headers, in which case the missing fields are zero.
+fn padded<const N: usize>(data: &[u8], offset: usize, size: usize) -> [u8; N] {
+    let mut buf = [0; N];
+    let end = usize::min(offset + size.min(N), data.len());
+    if let Some(header) = data.get(offset..end) {
+        buf[..header.len()].copy_from_slice(header);
+    }
+    buf
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::NOTE_OFF;
+
+    fn header(num_patterns: u16, num_instruments: u16) -> Vec<u8> {
+        let mut data = vec![0; HEADER_OFFSET + HEADER_SIZE];
+        data[..ID.len()].copy_from_slice(ID);
+        data[58..60].copy_from_slice(&VERSION.to_le_bytes());
+        let header = &mut data[HEADER_OFFSET..];
+        header[0..4].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[8..10].copy_from_slice(&2u16.to_le_bytes());
+        header[10..12].copy_from_slice(&num_patterns.to_le_bytes());
+        header[12..14].copy_from_slice(&num_instruments.to_le_bytes());
+        header[16..18].copy_from_slice(&6u16.to_le_bytes());
+        header[18..20].copy_from_slice(&125u16.to_le_bytes());
+        // Orders
+        header[20] = 0;
+        header[21] = 0;
+        data
+    }
+
+    fn pattern(data: &mut Vec<u8>, rows: u16, packed: &[u8]) {
+        data.extend(9u32.to_le_bytes());
+        data.push(0);
+        data.extend(rows.to_le_bytes());
+        data.extend((packed.len() as u16).to_le_bytes());
+        data.extend(packed);
+    }
+
+    #[test]
+    fn parse_module() -> Result<()> {
+        let mut data = header(1, 1);
+        let cells: [&[u8]; 6] = [
+            // C-4 on instrument 1 at half volume, stored as a full cell
+            &[49, 1, 0x30, 0, 0],
+            &[0x80],
+            // D#5 on the same instrument, delayed by 3 ticks
+            &[0x80 | 0x01 | 0x08 | 0x10, 64, 0xE, 0xD3],
+            &[0x80],
+            &[0x80 | 0x01, KEY_OFF],
+            &[0x80 | 0x08 | 0x10, 0x4, 0x44],
+        ];
+        pattern(&mut data, 3, &cells.concat());
+
+        let mut instrument = [0; INSTRUMENT_HEADER_SIZE];
+        instrument[..4].copy_from_slice(&(INSTRUMENT_HEADER_SIZE as u32).to_le_bytes());
+        instrument[4..9].copy_from_slice(b"piano");
+        instrument[27] = 2;
+        instrument[29] = SAMPLE_HEADER_SIZE as u8;
+        // The second sample plays from C-5
+        instrument[33 + 60..33 + NUM_NOTES].fill(1);
+        // Volume envelope with a peak at 10 ticks and sustain at half volume at 30 ticks
+        let points: [(u16, u16); 3] = [(0, 0), (10, 64), (30, 32)];
+        for (i, (x, y)) in points.iter().enumerate() {
+            instrument[129 + i * 4..131 + i * 4].copy_from_slice(&x.to_le_bytes());
+            instrument[131 + i * 4..133 + i * 4].copy_from_slice(&y.to_le_bytes());
+        }
+        instrument[225] = 3;
+        instrument[227] = 2;
+        instrument[233] = 0x01 | 0x02;
+        instrument[239..241].copy_from_slice(&250u16.to_le_bytes());
+        data.extend(instrument);
+
+        for (length, flags, relative_note) in [(4u32, 0u8, 0i8), (4, 0x10, -12)] {
+            let mut sample = [0; SAMPLE_HEADER_SIZE];
+            sample[..4].copy_from_slice(&length.to_le_bytes());
+            sample[12] = 64;
+            sample[14] = flags;
+            sample[15] = 0x80;
+            sample[16] = relative_note as u8;
+            data.extend(sample);
+        }
+        // 8-bit deltas
+        data.extend([64, 0, 192, 192]);
+        // 16-bit deltas
+        data.extend(16384i16.to_le_bytes());
+        data.extend((-32768i16).to_le_bytes());
+
+        let module = parse(&data)?;
+        assert_eq!(2, module.samples.len());
+        assert_eq!("piano", module.samples[0].name);
+
+        // 10 ticks at tempo 125 take 200 ms
+        let params = &module.samples[0].params;
+        assert_eq!(200.0, params[SamplerParams::ENV_ATTACK]);
+        assert_eq!(400.0, params[SamplerParams::ENV_DECAY]);
+        assert_eq!(0.5, params[SamplerParams::ENV_SUSTAIN]);
+        assert_eq!(5240.0, params[SamplerParams::ENV_RELEASE]);
+
+        assert_eq!(vec![0, 0], module.project.song);
+        assert_eq!(125, module.project.bpm);
+        let pattern = &module.project.patterns[0];
+        assert_eq!(3, pattern.len());
+        let steps = pattern.steps(0);
+        assert_eq!(Some(48), steps[0].pitch());
+        assert_eq!(Some(0), steps[0].instrument());
+        assert_eq!(114, steps[0].velocity());
+        assert_eq!(Some(63), steps[1].pitch());
+        assert_eq!(Some(1), steps[1].instrument());
+        assert_eq!(Some(6), steps[1].offset());
+        assert_eq!(Some(NOTE_OFF), steps[2].pitch());
+        assert!(pattern.steps(1).iter().all(|s| s.is_empty()));
+        assert_eq!(vec!["effect 4xx (vibrato) (1x)"], module.warnings);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_deltas() {
+        let channel = |buf: Buffer| buf.iter().map(|f| f.channel(0)).collect::<Vec<_>>();
+        assert_eq!(
+            vec![0.5, 0.5, 0.0, -0.5],
+            channel(decode(&[64, 0, 192, 192], false))
+        );
+        let data = [16384i16.to_le_bytes(), (-32768i16).to_le_bytes()].concat();
+        assert_eq!(vec![0.5, -0.5], channel(decode(&data, true)));
+    }
+}
@@ -0,0 +1,307 @@
+//! ProTracker MOD files with 31 samples and 4, 6 or 8 channels.
+
+use anyhow::{anyhow, Result};
+
+use super::{Module, Sample, Warnings, MAX_CHANNELS};
+use crate::audio::Frame;
+use crate::engine::MAX_INSTRUMENTS;
+use crate::pattern::Pattern;
+use crate::sampler::Sound;
+
+const NUM_SAMPLES: usize = 31;
+const SAMPLE_HEADER_SIZE: usize = 30;
+const ORDERS_OFFSET: usize = 950;
+const SIGNATURE_OFFSET: usize = 1080;
+const PATTERNS_OFFSET: usize = 1084;
+const ROWS_PER_PATTERN: usize = 64;
+/// Period of C-2, which plays a sample at its recorded speed
+const BASE_PERIOD: f64 = 428.0;
+const PAL_CLOCK: f64 = 7_093_789.2;
+const MAX_VOLUME: f64 = 64.0;
+const DEFAULT_SPEED: u8 = 6;
+const DEFAULT_TEMPO: u8 = 125;
+
+pub(super) const EFFECT_NAMES: [&str; 16] = [
+    "arpeggio",
+    "portamento up",
+    "portamento down",
+    "tone portamento",
+    "vibrato",
+    "tone portamento and volume slide",
+    "vibrato and volume slide",
+    "tremolo",
+    "panning",
+    "sample offset",
+    "volume slide",
+    "position jump",
+    "set volume",
+    "pattern break",
+    "extended",
+    "set speed",
+];
+
+struct SampleHeader {
+    name: String,
+    /// Length in bytes
+    length: usize,
+    finetune: i8,
+    volume: u8,
+    loop_length: usize,
+}
+
+struct Cell {
+    sample: usize,
+    period: u16,
+    effect: u8,
+    param: u8,
+}
+
+pub(super) fn parse(data: &[u8]) -> Result<Module> {
+    if data.len() < PATTERNS_OFFSET {
+        return Err(anyhow!("file is too short for a module"));
+    }
+    let num_channels = num_channels(&data[SIGNATURE_OFFSET..PATTERNS_OFFSET])?;
+    if num_channels > MAX_CHANNELS {
+        return Err(anyhow!(
+            "modules with {num_channels} channels are not supported"
+        ));
+    }
+
+    let headers: Vec<SampleHeader> = (0..NUM_SAMPLES)
+        .map(|i| sample_header(&data[20 + i * SAMPLE_HEADER_SIZE..]))
+        .collect();
+    let song_len = usize::from(data[ORDERS_OFFSET]).clamp(1, 128);
+    let orders = &data[ORDERS_OFFSET + 2..ORDERS_OFFSET + 130];
+    let num_patterns = *orders.iter().max().unwrap() as usize + 1;
+    let pattern_size = ROWS_PER_PATTERN * num_channels * 4;
+    let samples_offset = PATTERNS_OFFSET + num_patterns * pattern_size;
+    if data.len() < samples_offset {
+        return Err(anyhow!("module is truncated"));
+    }
+
+    let mut warnings = Warnings::default();
+    let mut samples = Vec::new();
+    // Instrument slot for every sample number, which starts at 1
+    let mut slots = [None; NUM_SAMPLES + 1];
+    let mut offset = samples_offset;
+    for (i, header) in headers.iter().enumerate() {
+        let end = usize::min(offset + header.length, data.len());
+        let sample_data = &data[offset..end];
+        offset = end;
+        if sample_data.len() < header.length {
+            warnings.add("truncated sample data");
+        }
+        if sample_data.is_empty() {
+            continue;
+        }
+        if samples.len() == MAX_INSTRUMENTS {
+            warnings.add(format!("more than {MAX_INSTRUMENTS} samples"));
+            continue;
+        }
+        if header.loop_length > 2 {
+            warnings.add("sample loop");
+        }
+        let frames = sample_data
+            .iter()
+            .map(|s| {
+                let s = *s as i8 as f32 / 128.0;
+                Frame::new([s, s])
+            })
+            .collect();
+        let rate = PAL_CLOCK / (2.0 * BASE_PERIOD) * 2f64.powf(header.finetune as f64 / 96.0);
+        slots[i + 1] = Some(samples.len());
+        samples.push(Sample {
+            slot: samples.len(),
+            name: header.name.clone(),
+            sound: Sound::new(frames, 0, rate.round() as usize),
+            params: Vec::new(),
+        });
+    }
+
+    // The speed and the sample of every channel carry over between patterns, so patterns are
+    // converted in song order.
+    let mut speed = DEFAULT_SPEED;
+    let mut tempo = DEFAULT_TEMPO;
+    let mut channel_samples = vec![0; num_channels];
+    let mut patterns: Vec<Option<Pattern>> = vec![None; num_patterns];
+    for (position, &idx) in orders[..song_len].iter().enumerate() {
+        let idx = idx as usize;
+        if patterns[idx].is_some() {
+            continue;
+        }
+        let start = PATTERNS_OFFSET + idx * pattern_size;
+        let cells = &data[start..start + pattern_size];
+        let mut pattern = Pattern::new(num_channels);
+        pattern.set_len(ROWS_PER_PATTERN);
+        let mut len = ROWS_PER_PATTERN;
+
+        for row in 0..ROWS_PER_PATTERN {
+            // Speed changes apply to the whole row
+            for ch in 0..num_channels {
+                let cell = cell(&cells[(row * num_channels + ch) * 4..]);
+                if cell.effect == 0xF && cell.param > 0 {
+                    if position > 0 || row > 0 {
+                        warnings.add("speed or tempo change");
+                    } else if cell.param < 0x20 {
+                        speed = cell.param;
+                    } else {
+                        tempo = cell.param;
+                    }
+                }
+            }
+
+            for (ch, current_sample) in channel_samples.iter_mut().enumerate() {
+                let cell = cell(&cells[(row * num_channels + ch) * 4..]);
+                if cell.sample > 0 {
+                    *current_sample = cell.sample;
+                }
+                let header = current_sample.checked_sub(1).and_then(|i| headers.get(i));
+
+                let mut volume = None;
+                let mut offset = None;
+                match (cell.effect, cell.param) {
+                    (0x0, 0) | (0xF, _) => {}
+                    (0xC, param) => volume = Some(param.min(MAX_VOLUME as u8)),
+                    (0xD, param) => {
+                        len = usize::min(len, row + 1);
+                        if param > 0 {
+                            warnings.add("pattern break to a row other than the first");
+                        }
+                    }
+                    (0xE, param) if param >> 4 == 0xD => {
+                        offset = Some(super::offset(param & 0x0F, speed));
+                    }
+                    (0xE, param) => warnings.add(format!("effect E{:X}x", param >> 4)),
+                    (effect, _) => warnings.add(format!(
+                        "effect {effect:X}xx ({})",
+                        EFFECT_NAMES[effect as usize]
+                    )),
+                }
+
+                let step = if cell.period > 0 {
+                    let Some(pitch) = super::pitch(BASE_PERIOD / cell.period as f64) else {
+                        warnings.add("note out of range");
+                        continue;
+                    };
+                    let Some(slot) = slots.get(*current_sample).copied().flatten() else {
+                        warnings.add("note without sample");
+                        continue;
+                    };
+                    let volume = volume.or_else(|| header.map(|h| h.volume)).unwrap_or(64);
+                    let velocity = super::velocity(volume as f64 / MAX_VOLUME);
+                    super::note(pitch, slot, velocity, offset)
+                } else if volume == Some(0) {
+                    super::note_off(offset)
+                } else {
+                    if volume.is_some() {
+                        warnings.add("volume change without a note");
+                    }
+                    continue;
+                };
+                pattern.tracks[ch].steps[row] = step;
+            }
+        }
+        pattern.set_len(len);
+        patterns[idx] = Some(pattern);
+    }
+
+    let (patterns, song) = super::song(patterns, &orders[..song_len]);
+    Ok(Module {
+        project: super::project(super::bpm(speed, tempo), num_channels, patterns, song),
+        samples,
+        warnings: warnings.into_vec(),
+    })
+}
+
+fn num_channels(signature: &[u8]) -> Result<usize> {
+    let channels = match signature {
+        b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" => 4,
+        b"6CHN" => 6,
+        b"8CHN" | b"FLT8" | b"OKTA" | b"CD81" => 8,
+        [a, b, b'C', b'H'] if a.is_ascii_digit() && b.is_ascii_digit() => {
+            ((a - b'0') * 10 + (b - b'0')) as usize
+        }
+        _ => return Err(anyhow!("unsupported module format")),
+    };
+    Ok(channels)
+}
+
+fn sample_header(data: &[u8]) -> SampleHeader {
+    let word = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]) as usize * 2;
+    let name = String::from_utf8_lossy(&data[..22])
+        .trim_end_matches('\0')
+        .trim()
+        .to_string();
+    // Finetune is a signed nibble
+    let finetune = ((data[24] & 0x0F) << 4) as i8 >> 4;
+    SampleHeader {
+        name,
+        length: word(22),
+        finetune,
+        volume: data[25],
+        loop_length: word(28),
+    }
+}
+
+fn cell(data: &[u8]) -> Cell {
+    Cell {
+        sample: ((data[0] & 0xF0) | (data[2] >> 4)) as usize,
+        period: u16::from(data[0] & 0x0F) << 8 | u16::from(data[1]),
+        effect: data[2] & 0x0F,
+        param: data[3],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::NOTE_OFF;
+
+    fn write_cell(data: &mut [u8], sample: u8, period: u16, effect: u8, param: u8) {
+        data[0] = (sample & 0xF0) | (period >> 8) as u8;
+        data[1] = period as u8;
+        data[2] = (sample << 4) | effect;
+        data[3] = param;
+    }
+
+    #[test]
+    fn parse_module() -> Result<()> {
+        let mut data = vec![0; PATTERNS_OFFSET + ROWS_PER_PATTERN * 4 * 4 + 8];
+        let sample = &mut data[20..20 + SAMPLE_HEADER_SIZE];
+        sample[..4].copy_from_slice(b"kick");
+        sample[23] = 4; // 8 bytes
+        sample[25] = 64;
+        data[ORDERS_OFFSET] = 2;
+        data[SIGNATURE_OFFSET..PATTERNS_OFFSET].copy_from_slice(b"M.K.");
+
+        let pattern = &mut data[PATTERNS_OFFSET..];
+        let at = |row: usize, ch: usize| (row * 4 + ch) * 4;
+        write_cell(&mut pattern[at(0, 0)..], 0, 0, 0xF, 3);
+        write_cell(&mut pattern[at(0, 1)..], 1, 428, 0xC, 16);
+        write_cell(&mut pattern[at(1, 1)..], 0, 214, 0xE, 0xD1);
+        write_cell(&mut pattern[at(2, 1)..], 0, 0, 0xC, 0);
+        write_cell(&mut pattern[at(3, 2)..], 0, 0, 0x4, 0x44);
+        write_cell(&mut pattern[at(7, 3)..], 0, 0, 0xD, 0);
+
+        let module = parse(&data)?;
+        assert_eq!(1, module.samples.len());
+        assert_eq!("kick", module.samples[0].name);
+        assert_eq!(vec![0, 0], module.project.song);
+        // 4 channels and the master
+        assert_eq!(5, module.project.tracks.len());
+        // 3 ticks per row at tempo 125 is 250 beats per minute
+        assert_eq!(250, module.project.bpm);
+
+        let pattern = &module.project.patterns[0];
+        assert_eq!(8, pattern.len());
+        let steps = pattern.steps(1);
+        assert_eq!(Some(48), steps[0].pitch());
+        assert_eq!(Some(0), steps[0].instrument());
+        assert_eq!(Some(60), steps[1].pitch());
+        assert_eq!(Some(4), steps[1].offset());
+        assert_eq!(127, steps[1].velocity());
+        assert_eq!(Some(NOTE_OFF), steps[2].pitch());
+        assert_eq!(vec!["effect 4xx (vibrato) (1x)"], module.warnings);
+        Ok(())
+    }
+}
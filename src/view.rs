@@ -1,5 +1,6 @@
 pub mod editor;
 
+use std::sync::Arc;
 use std::time::Duration;
 
 use camino::Utf8PathBuf;
@@ -14,7 +15,8 @@ use ratatui::{
 };
 
 use crate::app::App;
-use crate::params::ParamIterExt;
+use crate::effects::EFFECTS;
+use crate::params::{ParamIterExt, Params};
 use crate::pattern::{Pattern, Selection};
 use crate::sampler;
 use crate::view::editor::EditorState;
@@ -35,7 +37,11 @@ pub enum ProjectTreeState {
     Instruments,
     Tracks,
     Devices(usize),
+    /// Effects that can be added to a track
+    EffectBrowser(usize),
     InstrumentParams(usize),
+    /// Params of an effect, by track and effect index
+    DeviceParams(usize, usize),
 }
 
 pub struct View {
@@ -45,10 +51,14 @@ pub struct View {
     pub params: ListState,
     pub tracks: ListState,
     pub devices: ListState,
+    pub effects: ListState,
     pub patterns: ListState,
     pub project_tree_state: ProjectTreeState,
     pub selection: Option<Selection>,
     pub clipboard: Option<(Pattern, Selection)>,
+    /// Effect that the file loader loads an impulse response into, by track and effect index.
+    /// Without one it loads sounds into instruments.
+    pub ir_target: Option<(usize, usize)>,
     pub command: String,
     pub editor: EditorState,
     frames: usize,
@@ -64,6 +74,7 @@ impl View {
             params: list.clone(),
             tracks: list.clone(),
             devices: list.clone(),
+            effects: list.clone(),
             patterns: list.clone(),
             editor: EditorState::default(),
             focus: Focus::Editor,
@@ -71,6 +82,7 @@ impl View {
             project_tree_state: ProjectTreeState::Instruments,
             selection: None,
             clipboard: None,
+            ir_target: None,
         }
     }
 }
@@ -129,6 +141,8 @@ pub fn render(app: &App, view: &mut View, f: &mut Frame) {
         let spans = Line::from(vec![Span::raw(":"), Span::raw(&*view.command)]);
         let paragraph = Paragraph::new(spans);
         f.render_widget(paragraph, command)
+    } else if let Some(message) = &app.message {
+        f.render_widget(Paragraph::new(message.as_str()), command)
     }
 
     let area = render_outer_block(f.buffer_mut(), status, Borders::TOP | Borders::BOTTOM);
@@ -212,7 +226,12 @@ fn render_status_line(app: &App, _view: &mut View, f: &mut Frame, area: Rect) {
     let paragraph = Paragraph::new(playback_position).alignment(Alignment::Left);
     f.render_widget(paragraph, area);
 
-    let paragraph = Paragraph::new("*Untitled*").alignment(Alignment::Center);
+    let title = app
+        .project_path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .unwrap_or("*Untitled*");
+    let paragraph = Paragraph::new(title).alignment(Alignment::Center);
     f.render_widget(paragraph, area);
 
     let settings = format!(
@@ -223,6 +242,39 @@ fn render_status_line(app: &App, _view: &mut View, f: &mut Frame, area: Rect) {
     f.render_widget(paragraph, area);
 }
 
+fn render_params<'a>(
+    params: &Arc<dyn Params>,
+    title: &'a str,
+    area: Rect,
+    highlight_style: Style,
+) -> ListView<'a> {
+    // TODO: maybe use a table here to align values?
+    let w = (area.width as f32 * 0.6) as usize;
+    let params: Vec<ListItem> = params
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            ListItem::new(Span::raw(format!(
+                " {:0nwidth$} {:lwidth$} {}",
+                i,
+                p.label(),
+                p.as_string(),
+                nwidth = 2,
+                lwidth = w
+            )))
+        })
+        .collect();
+
+    ListView::new(params)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(BORDER_COLOR)),
+        )
+        .highlight_style(highlight_style)
+}
+
 fn render_project_tree(app: &App, view: &mut View, f: &mut Frame, area: Rect) {
     let highlight_style = highlight_style(view, Focus::ProjectTree);
     match view.project_tree_state {
@@ -257,7 +309,24 @@ fn render_project_tree(app: &App, view: &mut View, f: &mut Frame, area: Rect) {
                 .iter()
                 .enumerate()
                 .map(|(i, dev)| {
-                    ListItem::new(Span::raw(format!(" {:0width$} {}", i, dev.name, width = 2)))
+                    let mut name = format!(" {:0width$} {}", i, dev.name, width = 2);
+                    let source = dev
+                        .sidechain
+                        .and_then(|node| app.tracks.iter().position(|t| t.node_index == node));
+                    // Effects with a sidechain show the track they listen to
+                    if let Some(source) = source {
+                        let source_name = app.tracks[source]
+                            .name
+                            .clone()
+                            .unwrap_or_else(|| format!("Track {source}"));
+                        name += &format!(" < {source_name}");
+                    }
+                    let item = ListItem::new(Span::raw(name));
+                    if dev.bypass {
+                        item.style(Style::default().fg(Color::DarkGray))
+                    } else {
+                        item
+                    }
                 })
                 .collect();
 
@@ -275,36 +344,56 @@ fn render_project_tree(app: &App, view: &mut View, f: &mut Frame, area: Rect) {
                 .highlight_style(highlight_style);
             f.render_stateful_widget(devices, area, &mut view.devices);
         }
-        ProjectTreeState::InstrumentParams(instrument_idx) => {
-            let instrument = app.instruments[instrument_idx].as_ref().unwrap();
-            let params = app.params(instrument.node_index);
-
-            // TODO: maybe use a table here to align values?
-            let w = (area.width as f32 * 0.6) as usize;
-            let params: Vec<ListItem> = params
+        ProjectTreeState::EffectBrowser(_) => {
+            let w = (area.width as f32 * 0.4) as usize;
+            let effects: Vec<ListItem> = EFFECTS
                 .iter()
-                .enumerate()
-                .map(|(i, p)| {
+                .map(|effect| {
                     ListItem::new(Span::raw(format!(
-                        " {:0nwidth$} {:lwidth$} {}",
-                        i,
-                        p.label(),
-                        p.as_string(),
-                        nwidth = 2,
-                        lwidth = w
+                        " {:w$} {}",
+                        effect.category.to_string(),
+                        effect.name,
                     )))
                 })
                 .collect();
-
-            let params = ListView::new(params)
+            let effects = ListView::new(effects)
                 .block(
                     Block::default()
-                        .title(instrument.name.as_ref())
+                        .title("Add Effect")
                         .borders(Borders::ALL)
                         .border_style(Style::default().fg(BORDER_COLOR)),
                 )
                 .highlight_style(highlight_style);
-            f.render_stateful_widget(params, area, &mut view.params);
+            f.render_stateful_widget(effects, area, &mut view.effects);
+        }
+        ProjectTreeState::InstrumentParams(instrument_idx) => {
+            // The sound can be gone after an undo
+            let Some(instrument) = app.instruments.get(instrument_idx).and_then(Option::as_ref)
+            else {
+                view.project_tree_state = ProjectTreeState::Instruments;
+                return;
+            };
+            let params = app.params(instrument.node_index);
+            let list = render_params(params, &instrument.name, area, highlight_style);
+            f.render_stateful_widget(list, area, &mut view.params);
+        }
+        ProjectTreeState::DeviceParams(track_idx, device_idx) => {
+            // The effect can be gone after an undo
+            let Some(effect) = app
+                .tracks
+                .get(track_idx)
+                .and_then(|track| track.effects.get(device_idx))
+            else {
+                view.project_tree_state = ProjectTreeState::Devices(track_idx);
+                return;
+            };
+            let params = app.params(effect.node_index);
+            let title = match effect.gain_reduction() {
+                Some(reduction) => format!("{} GR {:.1}dB", effect.name, -reduction),
+                None => effect.name.clone(),
+            };
+            let list = render_params(params, &title, area, highlight_style);
+            f.render_stateful_widget(list, area, &mut view.params);
         }
         ProjectTreeState::Instruments => {
             let instruments: Vec<ListItem> = app
@@ -364,7 +453,11 @@ fn render_file_browser(app: &App, view: &mut View, f: &mut Frame, area: Rect) {
     f.render_stateful_widget(files, sections[0], &mut view.files);
 
     let dir = shorten_path(&app.file_browser.dir, sections[1].width as usize - 8);
-    let header = Paragraph::new(format!(" {}", dir)).block(
+    let target = match view.ir_target {
+        Some(_) => "IR ",
+        None => "",
+    };
+    let header = Paragraph::new(format!(" {}{}", target, dir)).block(
         Block::default()
             .borders(Borders::TOP)
             .border_style(Style::default().fg(BORDER_COLOR)),
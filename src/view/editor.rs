@@ -16,9 +16,11 @@ use ratatui::{
     widgets::{Block, Borders, Widget},
 };
 
-const TRACK_WIDTH: u16 = "| C#4 05 v 20 R-10 |".len() as u16;
+const TRACK_WIDTH: u16 = "| C#4 005 v 20 R-10 |".len() as u16;
 const BUS_TRACK_WIDTH: u16 = 12;
 const STEPS_WIDTH: u16 = " 256 ".len() as u16;
+/// Gain reduction in dB for each row of the gain reduction meter
+const GAIN_REDUCTION_STEP: f64 = 1.0;
 
 #[derive(Clone, Default)]
 pub struct EditorState {
@@ -205,6 +207,24 @@ fn render_mixer_controls(app: &App, track: &Track, buf: &mut Buffer, area: Rect,
         db -= 6;
     }
 
+    // Gain reduction of dynamics effects like a limiter, growing down from the top
+    let reduction = track
+        .effects
+        .iter()
+        .filter_map(|effect| effect.gain_reduction())
+        .reduce(f64::max);
+    let x = meter.x + meter_width + 2;
+    if let Some(reduction) = reduction.filter(|_| x < area.right()) {
+        for i in 0..meter.height {
+            let color = if reduction > i as f64 * GAIN_REDUCTION_STEP {
+                Color::Indexed(202)
+            } else {
+                Color::Gray
+            };
+            buf.set_string(x, meter.y + i, "▇", Style::default().fg(color));
+        }
+    }
+
     // Volume control
     let volume_area = Rect {
         x: area.x,
@@ -269,8 +289,8 @@ fn render_track_steps(
         };
 
         let snd = match step.instrument() {
-            Some(v) => format!("{:0width$}", v, width = 2),
-            None => String::from("--"),
+            Some(v) => format!("{:03}", v),
+            None => String::from("---"),
         };
 
         let fx_cmd1 = step
@@ -2,13 +2,17 @@ use std::fs;
 use std::path;
 
 use anyhow::Result;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use hound::{WavReader, WavSpec, WavWriter};
+use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
 
 use unsound::app::{self, Msg, TrackType};
 use unsound::audio::Stereo;
+use unsound::backend;
 use unsound::engine::{MAIN_OUTPUT, MASTER_TRACK};
-use unsound::pattern::Position;
+use unsound::params;
+use unsound::pattern::{Position, StepSize};
+use unsound::render::{self, BitDepth, RenderOptions, RenderRange};
 
 #[test]
 fn test_app() -> Result<()> {
@@ -71,6 +75,613 @@ fn test_app() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_render() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    let messages = vec![
+        CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None),
+        CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None),
+        LoadSound(0, "sounds/kick.wav".into()),
+        CreatePattern(None),
+        CreatePattern(None),
+    ];
+    for msg in messages {
+        app.send(msg)?;
+    }
+    app.send(app.update_pattern(|p| {
+        p.set_len(16);
+        p.handle_input(Position::default(), 4, 'z', 0);
+    }))?;
+
+    // test_app clears tests/output while it runs
+    let output_dir = Utf8PathBuf::try_from(std::env::temp_dir())?;
+    let output_file = output_dir.join("unsound-render.wav");
+    let stems_dir = output_dir.join("unsound-stems");
+    if stems_dir.exists() {
+        fs::remove_dir_all(&stems_dir)?;
+    }
+
+    let options = RenderOptions {
+        range: RenderRange::Patterns(0, 0),
+        bit_depth: BitDepth::Int16,
+        tail: 0.5,
+        stems: Some(stems_dir.clone()),
+        ..RenderOptions::default()
+    };
+    let mut last_progress = 0.0;
+    render::render(app.project(), &output_file, &options, |p| last_progress = p)?;
+    assert_eq!(1.0, last_progress);
+
+    let wav = WavReader::open(&output_file)?;
+    assert_eq!(16, wav.spec().bits_per_sample);
+    // 16 lines at 120 bpm and 4 lines per beat take 2 seconds
+    assert_eq!(2 * (2 * 44100 + 44100 / 2), wav.len());
+
+    // The only instrument track goes into the master, whose fader is at -6dB, and the master
+    // has no stem of its own
+    assert!(!stems_dir.join("01.wav").exists());
+    let stem = WavReader::open(stems_dir.join("00.wav"))?;
+    assert_eq!(wav.len(), stem.len());
+    let mix: Vec<i16> = wav.into_samples().collect::<Result<_, _>>()?;
+    let stem: Vec<i16> = stem.into_samples().collect::<Result<_, _>>()?;
+    assert!(stem.iter().any(|s| *s != 0));
+    let gain = params::db_to_amp(-6.0);
+    for (mix, stem) in mix.iter().zip(&stem) {
+        assert!((*mix as f64 - *stem as f64 * gain).abs() <= 1.0);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_render_song() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    let messages = vec![
+        CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None),
+        CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None),
+        LoadSound(0, "sounds/kick.wav".into()),
+        CreatePattern(None),
+        CreatePattern(None),
+    ];
+    for msg in messages {
+        app.send(msg)?;
+    }
+    // Only the second pattern has a kick
+    app.send(app.update_pattern(|p| p.set_len(16)))?;
+    app.send(SelectPattern(1))?;
+    app.send(app.update_pattern(|p| {
+        p.set_len(16);
+        p.handle_input(Position::default(), 4, 'z', 0);
+    }))?;
+
+    let output_file = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-song.wav");
+    let options = RenderOptions {
+        range: RenderRange::Song,
+        tail: 0.0,
+        ..RenderOptions::default()
+    };
+    render::render(app.project(), &output_file, &options, |_| {})?;
+
+    let wav = WavReader::open(&output_file)?;
+    let samples: Vec<i32> = wav.into_samples().collect::<Result<_, _>>()?;
+    // Both patterns take 2 seconds, and the song doesn't wrap back to the first one
+    assert_eq!(2 * 2 * 2 * 44100, samples.len());
+    let (first, second) = samples.split_at(samples.len() / 2);
+    assert!(first.iter().all(|s| *s == 0));
+    assert!(second.iter().any(|s| *s != 0));
+    Ok(())
+}
+
+#[test]
+fn test_render_sample_rate() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+    assert!(app.send(SetSampleRate(44101)).is_err());
+
+    let messages = vec![
+        CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None),
+        CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None),
+        LoadSound(0, "sounds/kick.wav".into()),
+        CreatePattern(None),
+    ];
+    for msg in messages {
+        app.send(msg)?;
+    }
+    app.send(app.update_pattern(|p| {
+        p.set_len(16);
+        p.handle_input(Position::default(), 4, 'z', 0);
+    }))?;
+
+    let output_file = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-render-96k.wav");
+    let options = RenderOptions {
+        range: RenderRange::Patterns(0, 0),
+        sample_rate: 96000,
+        tail: 0.5,
+        ..RenderOptions::default()
+    };
+    render::render(app.project(), &output_file, &options, |_| {})?;
+
+    let wav = WavReader::open(&output_file)?;
+    assert_eq!(96000, wav.spec().sample_rate);
+    // The song takes 2 seconds at any sample rate
+    assert_eq!(2 * (2 * 96000 + 96000 / 2), wav.len());
+    let samples: Vec<i32> = wav.into_samples().collect::<Result<_, _>>()?;
+    assert!(samples.iter().any(|s| *s != 0));
+    Ok(())
+}
+
+#[test]
+fn test_large_project() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    for i in 0..80 {
+        app.send(CreateTrack(i, MASTER_TRACK, TrackType::Instrument, None))?;
+    }
+    app.send(LoadSound(200, "sounds/kick.wav".into()))?;
+    app.send(CreatePattern(None))?;
+    app.send(app.update_pattern(|p| {
+        p.set_len(4);
+        p.handle_input(Position::default(), 4, 'z', 200);
+    }))?;
+    assert_eq!(81, app.tracks.len());
+
+    let output_file = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-large.wav");
+    let options = RenderOptions {
+        range: RenderRange::Patterns(0, 0),
+        tail: 0.0,
+        ..RenderOptions::default()
+    };
+    render::render(app.project(), &output_file, &options, |_| {})?;
+
+    let wav = WavReader::open(&output_file)?;
+    let samples: Vec<i32> = wav.into_samples().collect::<Result<_, _>>()?;
+    assert!(samples.iter().any(|s| *s != 0));
+    Ok(())
+}
+
+#[test]
+fn test_edit_effects() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    app.send(CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None))?;
+    app.clear_history();
+    let initial = app.project().serialize();
+
+    let messages = vec![
+        LoadEffect(0, String::from("delay")),
+        LoadEffect(0, String::from("delay")),
+        ToggleEffectBypass(0, 1),
+        MoveEffect(0, 1, 0),
+        DeleteEffect(0, 1),
+    ];
+    let num_edits = messages.len();
+    for msg in messages {
+        app.send(msg)?;
+    }
+    let effects = &app.tracks[0].effects;
+    assert_eq!(1, effects.len());
+    assert!(effects[0].bypass);
+    let last = app.project().serialize();
+    assert!(last.contains("bypass 1"));
+
+    assert!(app.send(DeleteEffect(0, 1)).is_err());
+    assert!(app.send(MoveEffect(0, 0, 1)).is_err());
+
+    app.send(Undo)?;
+    let effects = &app.tracks[0].effects;
+    assert_eq!(2, effects.len());
+    assert!(effects[0].bypass);
+    assert!(!effects[1].bypass);
+
+    for _ in 1..num_edits {
+        app.send(Undo)?;
+    }
+    assert_eq!(initial, app.project().serialize());
+    for _ in 0..num_edits {
+        app.send(Redo)?;
+    }
+    assert_eq!(last, app.project().serialize());
+
+    // Param edits refer to the effect by its position, so they can be undone after the effect
+    // was removed and restored
+    let node_index = app.tracks[0].effects[0].node_index;
+    app.send(ParamInc(node_index, 0, StepSize::Large))?;
+    let edited = app.project().serialize();
+    assert_ne!(last, edited);
+    app.send(DeleteEffect(0, 0))?;
+    app.send(Undo)?;
+    assert_eq!(edited, app.project().serialize());
+    app.send(Undo)?;
+    assert_eq!(last, app.project().serialize());
+    Ok(())
+}
+
+#[test]
+fn test_impulse_response() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    app.send(LoadEffect(0, String::from("delay")))?;
+    app.send(LoadEffect(0, String::from("convolution")))?;
+    app.clear_history();
+    let initial = app.project().serialize();
+
+    // Files that can't be loaded are shown, and leave the effect chain as it was
+    let path = Utf8PathBuf::from("sounds/snare.wav");
+    app.send(LoadImpulseResponse(0, 0, path.clone()))?;
+    assert!(app.message.is_some());
+    app.send(LoadImpulseResponse(0, 1, "sounds/missing.wav".into()))?;
+    assert!(app.message.is_some());
+    assert_eq!(2, app.tracks[0].effects.len());
+    assert_eq!(initial, app.project().serialize());
+
+    let node_index = app.tracks[0].effects[1].node_index;
+    app.send(ParamInc(node_index, 1, StepSize::Large))?;
+    let edited = app.project().serialize();
+    app.send(LoadImpulseResponse(0, 1, path.clone()))?;
+    let effects = &app.tracks[0].effects;
+    assert_eq!(2, effects.len());
+    assert_eq!(Some(&path), effects[1].path.as_ref());
+    let loaded = app.project().serialize();
+    assert!(loaded.contains("effect convolution path \"sounds/snare.wav\""));
+    // The params are kept
+    assert_eq!(edited, loaded.replace(" path \"sounds/snare.wav\"", ""));
+
+    app.send(Undo)?;
+    assert_eq!(edited, app.project().serialize());
+    app.send(Redo)?;
+    assert_eq!(loaded, app.project().serialize());
+
+    // Projects with effects that can't be created don't replace the current one
+    let mut project = app.project();
+    project.tracks[0].effects[1].path = Some("sounds/missing.wav".into());
+    assert!(app.load_project(project).is_err());
+    let mut project = app.project();
+    project.tracks[0].effects[0].id = String::from("flanger");
+    assert!(app.load_project(project).is_err());
+    assert_eq!(loaded, app.project().serialize());
+    Ok(())
+}
+
+#[test]
+fn test_command_errors() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    app.send(CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None))?;
+    app.send(CreatePattern(None))?;
+    let initial = app.project().serialize();
+
+    // Mistakes in commands are shown instead of ending the app
+    app.send(SaveProject(None))?;
+    assert_eq!(Some("no file name"), app.message.as_deref());
+    app.send(LoadProject("missing.unsound".into()))?;
+    assert!(app.message.is_some());
+    app.send(LoadEffect(0, String::from("flanger")))?;
+    assert_eq!(Some("unknown effect flanger"), app.message.as_deref());
+    app.send(ImportMidi("missing.mid".into(), 0))?;
+    assert!(app.message.is_some());
+    app.send(SelectDevice(String::from("speakers")))?;
+    assert!(app.message.is_some());
+    assert_eq!(initial, app.project().serialize());
+
+    app.send(ListDevices)?;
+    assert_eq!(Some("no audio backend"), app.message.as_deref());
+    app.send(SetBufferSize(Some(256)))?;
+    assert_eq!(Some("no audio backend"), app.message.as_deref());
+
+    app.send(Noop)?;
+    assert!(app.message.is_none());
+
+    // The null backend runs with the configuration it was started with
+    app.set_audio_backend(backend::new("null")?);
+    let unsupported = Some("not supported by this backend");
+    app.send(SetBufferSize(Some(256)))?;
+    assert_eq!(unsupported, app.message.as_deref());
+    app.send(SetOutputChannels([2, 3]))?;
+    assert_eq!(unsupported, app.message.as_deref());
+    app.send(SetSampleRate(48000))?;
+    assert_eq!(unsupported, app.message.as_deref());
+    Ok(())
+}
+
+#[test]
+fn test_undo_errors() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+    let dir = Utf8PathBuf::try_from(std::env::temp_dir())?;
+    let path = dir.join("unsound-undo.wav");
+    fs::copy("sounds/kick.wav", &path)?;
+    app.send(LoadSound(0, path.clone()))?;
+    app.send(LoadSound(0, "sounds/snare.wav".into()))?;
+    let name = |app: &app::App| app.instruments[0].as_ref().map(|i| i.name.clone());
+
+    // The sound is loaded again from its file, which is gone
+    fs::remove_file(&path)?;
+    app.send(Undo)?;
+    assert!(app.message.is_some());
+    assert_eq!(Some(String::from("snare.wav")), name(&app));
+
+    // The edit is still there once the file is back
+    fs::copy("sounds/kick.wav", &path)?;
+    app.send(Undo)?;
+    assert!(app.message.is_none());
+    assert_eq!(Some(String::from("unsound-undo.wav")), name(&app));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_midi_export() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    let messages = vec![
+        SetBpm(100),
+        CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None),
+        CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None),
+        LoadSound(0, "sounds/kick.wav".into()),
+        CreatePattern(None),
+    ];
+    for msg in messages {
+        app.send(msg)?;
+    }
+    app.send(app.update_pattern(|p| {
+        p.set_len(8);
+        let at = |line, column| Position { line, column };
+        // Major chord on the first line
+        p.handle_input(at(0, 0), 4, 'z', 0);
+        p.handle_input(at(0, 2), 4, 'C', 0);
+        p.handle_input(at(0, 3), 4, '4', 0);
+        p.handle_input(at(0, 3), 4, '7', 0);
+        // Single note, delayed by 3 ticks
+        p.handle_input(at(2, 0), 4, 'x', 0);
+        p.handle_input(at(2, 2), 4, 'O', 0);
+        p.handle_input(at(2, 3), 4, '3', 0);
+        p.handle_input(at(4, 0), 4, 'a', 0);
+    }))?;
+
+    let output_file = Utf8PathBuf::try_from(std::env::temp_dir())?.join("unsound-export.mid");
+    app.send(ExportMidi(output_file.clone()))?;
+
+    let data = fs::read(&output_file)?;
+    let smf = Smf::parse(&data)?;
+    // Tempo track, instrument track and master track
+    assert_eq!(3, smf.tracks.len());
+    assert!(smf.tracks[0]
+        .iter()
+        .any(|ev| ev.kind == TrackEventKind::Meta(MetaMessage::Tempo(600_000.into()))));
+
+    let mut time = 0;
+    let mut notes = Vec::new();
+    for event in &smf.tracks[1] {
+        time += event.delta.as_int();
+        if let TrackEventKind::Midi { message, .. } = event.kind {
+            match message {
+                MidiMessage::NoteOn { key, .. } => notes.push((time, key.as_int(), true)),
+                MidiMessage::NoteOff { key, .. } => notes.push((time, key.as_int(), false)),
+                _ => {}
+            }
+        }
+    }
+    let expected = vec![
+        (0, 48, true),
+        (0, 55, true),
+        (0, 52, true),
+        (27, 48, false),
+        (27, 55, false),
+        (27, 52, false),
+        (27, 50, true),
+        (48, 50, false),
+    ];
+    assert_eq!(expected, notes);
+
+    // Importing past the last track goes to a new track, and plays its instrument
+    app.send(ImportMidi(output_file, 10))?;
+    assert!(app.message.is_none());
+    app.send(SelectPattern(app.state.song.len() - 1))?;
+    let steps = app.pattern_steps(1, &(0..1));
+    assert_eq!(Some(48), steps[0].pitch());
+    assert_eq!(Some(1), steps[0].instrument());
+    Ok(())
+}
+
+#[test]
+fn test_undo_redo() -> Result<()> {
+    use Msg::*;
+    let (mut app, _, _, _) = app::new()?;
+
+    let messages = vec![
+        CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None),
+        CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None),
+        LoadSound(0, "sounds/kick.wav".into()),
+        CreatePattern(None),
+    ];
+    for msg in messages {
+        app.send(msg)?;
+    }
+    app.clear_history();
+    let initial = app.project().serialize();
+
+    let node_index = app.instruments[0].as_ref().unwrap().node_index;
+    app.send(app.update_pattern(|p| p.handle_input(Position::default(), 4, 'z', 0)))?;
+    let edited = app.project().serialize();
+    let messages = vec![
+        ClonePattern(0),
+        CreateTrack(1, MASTER_TRACK, TrackType::Instrument, None),
+        RenameTrack(0, Some(String::from("kick"))),
+        ParamInc(node_index, 0, StepSize::Large),
+        ParamInc(node_index, 0, StepSize::Large),
+        ToggleMute(0),
+        LoadSound(1, "sounds/snare.wav".into()),
+        DeleteInstrument(0),
+        DeleteTrack(0),
+    ];
+    // Both parameter changes are undone at once
+    let num_edits = messages.len() - 1;
+    for msg in messages {
+        app.send(msg)?;
+    }
+    let last = app.project().serialize();
+    assert_eq!(2, app.tracks.len());
+
+    for _ in 0..num_edits {
+        app.send(Undo)?;
+    }
+    assert_eq!(edited, app.project().serialize());
+    app.send(Undo)?;
+    assert_eq!(initial, app.project().serialize());
+    app.send(Undo)?;
+    assert_eq!(Some("nothing to undo"), app.message.as_deref());
+
+    for _ in 0..num_edits + 1 {
+        app.send(Redo)?;
+    }
+    assert_eq!(last, app.project().serialize());
+
+    // A new edit discards the undone edits
+    app.send(Undo)?;
+    app.send(RenameTrack(0, Some(String::from("bass"))))?;
+    app.send(Redo)?;
+    assert_eq!(Some("nothing to redo"), app.message.as_deref());
+    Ok(())
+}
+
+#[test]
+fn test_release_removed_tracks() -> Result<()> {
+    use Msg::*;
+    let (mut app, mut app_state, mut engine, _) = app::new()?;
+
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    app.send(CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None))?;
+    app.send(LoadEffect(0, String::from("delay")))?;
+    let track = &app.tracks[0];
+    let freed = usize::min(track.node_index, track.effects[0].node_index);
+
+    // The track can't be restored once its edit is gone, so its nodes are deleted
+    app.send(DeleteTrack(0))?;
+    app.clear_history();
+    app.send(Noop)?;
+    // Deleted nodes fade out before the engine returns them
+    let mut buf = vec![Stereo::ZERO; 64];
+    for _ in 0..1000 {
+        engine.process(app_state.read(), &mut buf);
+    }
+    app.send(Noop)?;
+
+    app.send(CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None))?;
+    assert_eq!(freed, app.tracks[0].node_index);
+    Ok(())
+}
+
+#[test]
+fn test_sidechain() -> Result<()> {
+    use Msg::*;
+    let (mut app, mut app_state, mut engine, _) = app::new()?;
+
+    app.send(CreateTrack(MASTER_TRACK, MAIN_OUTPUT, TrackType::Bus, None))?;
+    app.send(CreateTrack(0, MASTER_TRACK, TrackType::Instrument, None))?;
+    app.send(CreateTrack(1, MASTER_TRACK, TrackType::Instrument, None))?;
+    app.send(LoadSound(0, "sounds/kick.wav".into()))?;
+    app.send(LoadEffect(1, String::from("compressor")))?;
+    app.send(LoadEffect(1, String::from("delay")))?;
+    let threshold = app.tracks[1].effects[0].node_index;
+    for _ in 0..10 {
+        app.send(ParamDec(threshold, 0, StepSize::Large))?;
+    }
+    app.send(CreatePattern(None))?;
+    app.send(app.update_pattern(|p| p.handle_input(Position::default(), 4, 'z', 0)))?;
+    app.clear_history();
+    let initial = app.project().serialize();
+
+    // The master track and the track itself would have to be processed before the compressor
+    assert!(app.send(SetSidechain(1, 0, Some(2))).is_err());
+    assert!(app.send(SetSidechain(1, 0, Some(1))).is_err());
+    assert!(app.send(SetSidechain(1, 1, Some(0))).is_err());
+    app.send(SetSidechain(1, 0, Some(0)))?;
+    let routed = app.project().serialize();
+    assert!(routed.contains("effect compressor sidechain 0"));
+    app.send(Undo)?;
+    assert_eq!(initial, app.project().serialize());
+    app.send(Redo)?;
+    assert_eq!(routed, app.project().serialize());
+
+    // The kick track now has to come first
+    app.send(LoadEffect(0, String::from("compressor")))?;
+    assert!(app.send(SetSidechain(0, 0, Some(1))).is_err());
+    app.send(DeleteEffect(0, 0))?;
+
+    let kick = app.tracks[0].node_index;
+    let compressor = app.tracks[1].effects[0].node_index;
+    let order = &app.state.node_order;
+    let kick_pos = order.iter().position(|e| e.node_index == kick).unwrap();
+    let compressor_pos = order
+        .iter()
+        .position(|e| e.node_index == compressor)
+        .unwrap();
+    assert!(kick_pos < compressor_pos);
+    assert!(order[kick_pos].send.is_some());
+    assert_eq!(order[kick_pos].send, order[compressor_pos].sidechain);
+
+    assert_eq!(routed, app.project().serialize());
+    app.load_project(app.project())?;
+    assert_eq!(routed, app.project().serialize());
+    // A loop in a project is rejected before the current one is replaced
+    let mut project = app.project();
+    project.tracks[1].effects[1].sidechain = Some(2);
+    assert!(app.load_project(project).is_err());
+    assert_eq!(routed, app.project().serialize());
+
+    // The compressor's own track is silent, so it only reduces gain while the kick plays
+    let mut max_reduction = |app: &mut app::App| -> Result<f64> {
+        let mut buf = vec![Stereo::ZERO; 512];
+        // Let the compressor recover from the last run
+        for _ in 0..200 {
+            engine.process(app_state.read(), &mut buf);
+        }
+        app.send(TogglePlay)?;
+        engine.seek(0);
+        let mut max = 0.0f64;
+        for _ in 0..20 {
+            engine.process(app_state.read(), &mut buf);
+            let reduction = app.tracks[1].effects[0].gain_reduction().unwrap();
+            max = max.max(reduction);
+        }
+        app.send(TogglePlay)?;
+        Ok(max)
+    };
+    assert!(max_reduction(&mut app)? > 3.0);
+
+    let kick_entry = |app: &app::App| {
+        let kick = app.tracks[0].node_index;
+        let order = &app.state.node_order;
+        order.iter().find(|e| e.node_index == kick).unwrap().send
+    };
+    let buffer = kick_entry(&app).unwrap();
+    app.send(SetSidechain(1, 0, None))?;
+    assert!(max_reduction(&mut app)? < 0.01);
+    // Nothing listens to the kick anymore, so its buffer is free for the next node
+    assert_eq!(None, kick_entry(&app));
+
+    app.send(Undo)?;
+    assert_eq!(routed, app.project().serialize());
+    assert!(kick_entry(&app).is_some());
+    app.send(Redo)?;
+    for _ in 0..16 {
+        app.send(CreateTrack(2, MASTER_TRACK, TrackType::Instrument, None))?;
+    }
+    assert!(app.tracks.iter().any(|t| t.node_index == buffer));
+    Ok(())
+}
+
 fn compare_wav_files<P: AsRef<path::Path>>(left: P, right: P) -> Result<()> {
     let mut reader1 = WavReader::open(left)?;
     let mut reader2 = WavReader::open(right)?;